@@ -0,0 +1,88 @@
+//! Throughput of `ByteRing` against `heapless::spsc::Queue`, the queue it
+//! replaced between the two cores
+//!
+//! 64-byte packets go through a 16 KiB queue the way the firmware moves USB
+//! packets: the producer writes packets while there is room for one, then
+//! the consumer takes everything out again in packets. The heapless queue
+//! moves one byte per call, `ByteRing` one copy per grant.
+//!
+//! ```text
+//! CARGO_PROFILE_RELEASE_OPT_LEVEL=s cargo run --release --example ring_throughput
+//! ```
+//!
+//! The firmware builds with opt-level `s`; the numbers are for comparing
+//! the two queues on one machine, not the bridge's speed on the chip.
+
+use heapless::spsc::Queue;
+use picoterm_bridge::byte_ring::ByteRing;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const QUEUE_SIZE: usize = 16 * 1024;
+const PACKET_SIZE: usize = 64;
+/// Bytes moved per measurement
+const TOTAL: usize = 1 << 30;
+
+fn byte_ring() -> Duration {
+    let ring = Box::leak(Box::new(ByteRing::<QUEUE_SIZE>::new()));
+    let (mut producer, mut consumer) = ring.split();
+    let packet = [0x55u8; PACKET_SIZE];
+    let mut out = [0u8; PACKET_SIZE];
+
+    let start = Instant::now();
+    let mut moved = 0;
+    while moved < TOTAL {
+        while producer.free() >= PACKET_SIZE {
+            producer.push_slice(black_box(&packet));
+        }
+        while !consumer.is_empty() {
+            let data = consumer.read_grant();
+            let count = data.len().min(PACKET_SIZE);
+            out[..count].copy_from_slice(&data[..count]);
+            consumer.release(count);
+            black_box(&out);
+            moved += count;
+        }
+    }
+    start.elapsed()
+}
+
+fn heapless_queue() -> Duration {
+    let queue: &'static mut Queue<u8, QUEUE_SIZE> = Box::leak(Box::new(Queue::new()));
+    let (mut producer, mut consumer) = queue.split();
+    let packet = [0x55u8; PACKET_SIZE];
+    let mut out = [0u8; PACKET_SIZE];
+
+    let start = Instant::now();
+    let mut moved = 0;
+    while moved < TOTAL {
+        while producer.capacity() - producer.len() >= PACKET_SIZE {
+            for &byte in black_box(&packet) {
+                producer.enqueue(byte).unwrap();
+            }
+        }
+        while consumer.ready() {
+            let mut count = 0;
+            while count < PACKET_SIZE {
+                match consumer.dequeue() {
+                    Some(byte) => out[count] = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+            black_box(&out);
+            moved += count;
+        }
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let mb_per_s = TOTAL as f64 / elapsed.as_secs_f64() / 1e6;
+    println!("{name:<24} {mb_per_s:>8.0} MB/s");
+}
+
+fn main() {
+    report("heapless::spsc::Queue", heapless_queue());
+    report("ByteRing", byte_ring());
+}
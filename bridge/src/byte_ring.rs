@@ -0,0 +1,195 @@
+//! Single-producer single-consumer byte ring with contiguous grants
+//!
+//! Works like `heapless::spsc::Queue<u8, N>`, but instead of moving one byte
+//! per call the producer is handed the largest contiguous free region
+//! (`grant`) and the consumer the largest contiguous filled region
+//! (`read_grant`), in the style of `bbqueue`. A USB packet or a DMA transfer
+//! is then a single copy into or out of the ring, followed by `commit` or
+//! `release`. A region stops at the end of the buffer, so data that wraps
+//! around takes two grants.
+//!
+//! The indices run freely and wrap at `usize::MAX`; `N` must be a power of
+//! two so they map onto the buffer with a mask. The producer only writes
+//! `tail` and the consumer only writes `head`, each with `Release` after
+//! touching the bytes and read by the other side with `Acquire` (the
+//! firmware's `core_local` explains why that is enough on both chips). Only
+//! atomic loads and stores are used, which the Cortex-M0+ supports.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Ring buffer storage; split it once into a `Producer` and a `Consumer`
+pub struct ByteRing<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    /// Total bytes consumed (written by the consumer only)
+    head: AtomicUsize,
+    /// Total bytes produced (written by the producer only)
+    tail: AtomicUsize,
+}
+
+// SAFETY: the producer only writes the free region and the consumer only
+// reads the filled region, which never overlap (see the module docs).
+unsafe impl<const N: usize> Sync for ByteRing<N> {}
+
+impl<const N: usize> ByteRing<N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two(), "ByteRing size must be a power of two");
+        N - 1
+    };
+
+    /// An empty ring
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Split the ring into its two ends
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        let ring: &Self = self;
+        (Producer { ring }, Consumer { ring })
+    }
+
+    #[inline]
+    fn buffer(&self) -> *mut u8 {
+        self.buffer.get() as *mut u8
+    }
+}
+
+impl<const N: usize> Default for ByteRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writing end of a `ByteRing`
+pub struct Producer<'a, const N: usize> {
+    ring: &'a ByteRing<N>,
+}
+
+// SAFETY: a `Producer` is the only writer of the free region and of `tail`.
+unsafe impl<const N: usize> Send for Producer<'_, N> {}
+
+impl<const N: usize> Producer<'_, N> {
+    /// Bytes that can be written right now (possibly in two grants)
+    #[inline]
+    pub fn free(&self) -> usize {
+        let head = self.ring.head.load(Ordering::Acquire);
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        N - tail.wrapping_sub(head)
+    }
+
+    /// The largest contiguous free region. Fill any prefix of it and pass
+    /// its length to `commit`.
+    #[inline]
+    pub fn grant(&mut self) -> &mut [u8] {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let offset = tail & ByteRing::<N>::MASK;
+        let len = self.free().min(N - offset);
+        // SAFETY: this region is free, so the consumer does not read it
+        unsafe { core::slice::from_raw_parts_mut(self.ring.buffer().add(offset), len) }
+    }
+
+    /// Hand `count` bytes written through `grant` to the consumer
+    #[inline]
+    pub fn commit(&mut self, count: usize) {
+        debug_assert!(count <= self.free());
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        self.ring
+            .tail
+            .store(tail.wrapping_add(count), Ordering::Release);
+    }
+
+    /// Copy as much of `data` as fits into the ring; returns the count
+    pub fn push_slice(&mut self, data: &[u8]) -> usize {
+        let mut written = 0;
+        while written < data.len() {
+            let grant = self.grant();
+            let count = grant.len().min(data.len() - written);
+            if count == 0 {
+                break;
+            }
+            grant[..count].copy_from_slice(&data[written..written + count]);
+            self.commit(count);
+            written += count;
+        }
+        written
+    }
+}
+
+/// Reading end of a `ByteRing`
+pub struct Consumer<'a, const N: usize> {
+    ring: &'a ByteRing<N>,
+}
+
+// SAFETY: a `Consumer` is the only reader of the filled region and the only
+// writer of `head`.
+unsafe impl<const N: usize> Send for Consumer<'_, N> {}
+
+impl<const N: usize> Consumer<'_, N> {
+    /// Bytes waiting to be read (possibly in two grants)
+    #[inline]
+    pub fn len(&self) -> usize {
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        let head = self.ring.head.load(Ordering::Relaxed);
+        tail.wrapping_sub(head)
+    }
+
+    /// True if there is no data to read
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The largest contiguous filled region. Pass the number of bytes used
+    /// from its start to `release`; the rest stays queued.
+    #[inline]
+    pub fn read_grant(&self) -> &[u8] {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let offset = head & ByteRing::<N>::MASK;
+        let len = self.len().min(N - offset);
+        // SAFETY: this region is filled, so the producer does not write it
+        unsafe { core::slice::from_raw_parts(self.ring.buffer().add(offset), len) }
+    }
+
+    /// Everything queued as two contiguous regions: up to the end of the
+    /// buffer, and what wrapped around to its start (empty if nothing did).
+    /// A DMA can send it all without coming back for the second half.
+    pub fn read_grants(&self) -> (&[u8], &[u8]) {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let offset = head & ByteRing::<N>::MASK;
+        let len = self.len();
+        let first = len.min(N - offset);
+        let buffer = self.ring.buffer();
+        // SAFETY: both regions are filled, so the producer does not write them
+        unsafe {
+            (
+                core::slice::from_raw_parts(buffer.add(offset), first),
+                core::slice::from_raw_parts(buffer, len - first),
+            )
+        }
+    }
+
+    /// Offset of the first `byte` in the queued data at or after `from`
+    pub fn find(&self, from: usize, byte: u8) -> Option<usize> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let buffer = self.ring.buffer();
+        (from..self.len()).find(|&offset| {
+            let index = head.wrapping_add(offset) & ByteRing::<N>::MASK;
+            // SAFETY: offsets below `len` are filled, so the producer does not write them
+            unsafe { *buffer.add(index) == byte }
+        })
+    }
+
+    /// Give `count` bytes from the start of `read_grant` back to the producer
+    #[inline]
+    pub fn release(&mut self, count: usize) {
+        debug_assert!(count <= self.len());
+        let head = self.ring.head.load(Ordering::Relaxed);
+        self.ring
+            .head
+            .store(head.wrapping_add(count), Ordering::Release);
+    }
+}
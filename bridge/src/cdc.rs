@@ -0,0 +1,90 @@
+//! CDC-ACM values the data path works with
+//!
+//! The line coding as the host encodes it and the SERIAL_STATE bits that
+//! report line events back. The USB side of the class itself lives in
+//! [`crate::cdc_acm`].
+
+/// SERIAL_STATE bRxCarrier (DCD)
+pub const SERIAL_STATE_RX_CARRIER: u16 = 1 << 0;
+/// SERIAL_STATE bTxCarrier (DSR)
+pub const SERIAL_STATE_TX_CARRIER: u16 = 1 << 1;
+/// SERIAL_STATE bBreak
+pub const SERIAL_STATE_BREAK: u16 = 1 << 2;
+/// SERIAL_STATE bFraming
+pub const SERIAL_STATE_FRAMING: u16 = 1 << 4;
+/// SERIAL_STATE bParity
+pub const SERIAL_STATE_PARITY: u16 = 1 << 5;
+/// SERIAL_STATE bOverRun
+pub const SERIAL_STATE_OVERRUN: u16 = 1 << 6;
+
+/// Line coding as set by the host with SET_LINE_CODING (raw CDC encoding)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineCoding {
+    data_rate: u32,
+    stop_bits: u8,
+    parity: u8,
+    data_bits: u8,
+}
+
+impl LineCoding {
+    /// A line coding from its raw CDC fields
+    pub const fn new(data_rate: u32, stop_bits: u8, parity: u8, data_bits: u8) -> Self {
+        Self {
+            data_rate,
+            stop_bits,
+            parity,
+            data_bits,
+        }
+    }
+
+    /// Decode the 7-byte SET_LINE_CODING payload. A data width of 0 is
+    /// taken as 8, which some hosts send.
+    pub fn from_bytes(data: &[u8; 7]) -> Self {
+        Self {
+            data_rate: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            stop_bits: data[4],
+            parity: data[5],
+            data_bits: if data[6] == 0 { 8 } else { data[6] },
+        }
+    }
+
+    /// Encode as the 7-byte GET_LINE_CODING payload
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let rate = self.data_rate.to_le_bytes();
+        [
+            rate[0],
+            rate[1],
+            rate[2],
+            rate[3],
+            self.stop_bits,
+            self.parity,
+            self.data_bits,
+        ]
+    }
+
+    /// Baud rate in bits per second
+    pub fn data_rate(&self) -> u32 {
+        self.data_rate
+    }
+
+    /// Stop bits: 0 = 1, 1 = 1.5, 2 = 2
+    pub fn stop_bits(&self) -> u8 {
+        self.stop_bits
+    }
+
+    /// Parity: 0 = none, 1 = odd, 2 = even, 3 = mark, 4 = space
+    pub fn parity(&self) -> u8 {
+        self.parity
+    }
+
+    /// Data bits (5, 6, 7, 8 or 16)
+    pub fn data_bits(&self) -> u8 {
+        self.data_bits
+    }
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self::new(9600, 0, 0, 8)
+    }
+}
@@ -0,0 +1,313 @@
+//! CDC-ACM class for the bridge
+//!
+//! Replaces `usbd_serial::SerialPort` so the bridge can handle the ACM
+//! requests a plain serial port ignores: SEND_BREAK on the control pipe and
+//! SERIAL_STATE notifications on the interrupt endpoint. Data is moved one
+//! packet at a time; buffering is done by the queues in `host` instead.
+
+use crate::cdc::{LineCoding, SERIAL_STATE_RX_CARRIER, SERIAL_STATE_TX_CARRIER};
+use crate::descriptor::DescriptorSink;
+use crate::{ByteSink, ByteSource, SinkError};
+use usb_device::class_prelude::*;
+use usb_device::{Result, UsbError};
+
+/// USB class code for CDC devices
+pub const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+/// ACM capabilities: line coding/control line state/serial state (bit 1)
+/// and SEND_BREAK (bit 2)
+const ACM_CAPABILITIES: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQ_SEND_BREAK: u8 = 0x23;
+
+const NOTIFY_SERIAL_STATE: u8 = 0x20;
+/// Notification endpoint size (a SERIAL_STATE notification is 10 bytes)
+const NOTIFY_PACKET_SIZE: u16 = 16;
+
+/// Line state reported between events: carrier and DSR always present
+const SERIAL_STATE_IDLE: u16 = SERIAL_STATE_RX_CARRIER | SERIAL_STATE_TX_CARRIER;
+
+/// SEND_BREAK duration meaning "until a zero-length break is requested"
+pub const BREAK_INDEFINITE: u16 = 0xFFFF;
+
+/// CDC-ACM function: communication interface with a notification endpoint
+/// plus a data interface with bulk IN/OUT endpoints.
+pub struct CdcAcmClass<'a, B: UsbBus> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, B>,
+    data_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    line_coding: LineCoding,
+    /// Line coding reported until the host sets one, restored on bus reset
+    default_line_coding: LineCoding,
+    dtr: bool,
+    rts: bool,
+    break_request: Option<u16>,
+    /// Irregular SERIAL_STATE bits waiting to be sent
+    serial_state_events: u16,
+    /// An idle SERIAL_STATE must follow the last event so the host sees the
+    /// event bits as edges
+    serial_state_clear_pending: bool,
+    /// The last IN packet was full-size, so the host still takes the
+    /// transfer as unfinished
+    zlp_pending: bool,
+}
+
+impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
+    /// Allocate interfaces and endpoints for one CDC-ACM function
+    pub fn new(alloc: &'a UsbBusAllocator<B>, max_packet_size: u16) -> Self {
+        Self::with_line_coding(alloc, max_packet_size, LineCoding::default())
+    }
+
+    /// Like `new`, with `line_coding` instead of 9600 8N1 as the line coding
+    /// the function starts with and returns to on bus reset
+    pub fn with_line_coding(
+        alloc: &'a UsbBusAllocator<B>,
+        max_packet_size: u16,
+        line_coding: LineCoding,
+    ) -> Self {
+        Self {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(NOTIFY_PACKET_SIZE, 255),
+            data_if: alloc.interface(),
+            read_ep: alloc.bulk(max_packet_size),
+            write_ep: alloc.bulk(max_packet_size),
+            line_coding,
+            default_line_coding: line_coding,
+            dtr: false,
+            rts: false,
+            break_request: None,
+            serial_state_events: 0,
+            serial_state_clear_pending: false,
+            zlp_pending: false,
+        }
+    }
+
+    /// Maximum packet size of the bulk endpoints
+    pub fn max_packet_size(&self) -> u16 {
+        self.write_ep.max_packet_size()
+    }
+
+    /// Line coding last set by the host
+    pub fn line_coding(&self) -> &LineCoding {
+        &self.line_coding
+    }
+
+    /// Host DTR state
+    pub fn dtr(&self) -> bool {
+        self.dtr
+    }
+
+    /// Host RTS state
+    pub fn rts(&self) -> bool {
+        self.rts
+    }
+
+    /// Take the last SEND_BREAK request (duration in ms, 0 = stop,
+    /// `BREAK_INDEFINITE` = until stopped)
+    pub fn take_break_request(&mut self) -> Option<u16> {
+        self.break_request.take()
+    }
+
+    /// Read one packet from the bulk OUT endpoint
+    pub fn read_packet(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_ep.read(data)
+    }
+
+    /// Write one packet to the bulk IN endpoint
+    pub fn write_packet(&mut self, data: &[u8]) -> Result<usize> {
+        let written = self.write_ep.write(data)?;
+        self.zlp_pending = written == usize::from(self.max_packet_size());
+        Ok(written)
+    }
+
+    /// End the IN transfer with a zero-length packet if the last packet was
+    /// full-size. While the endpoint is still busy the packet stays
+    /// pending; call again on a later poll.
+    pub fn finish_transfer(&mut self) {
+        if self.zlp_pending {
+            match self.write_ep.write(&[]) {
+                Err(UsbError::WouldBlock) => {}
+                _ => self.zlp_pending = false,
+            }
+        }
+    }
+
+    /// Queue irregular SERIAL_STATE bits (break, framing, ...) for the host
+    pub fn report_serial_state(&mut self, events: u16) {
+        self.serial_state_events |= events;
+    }
+
+    /// Send queued SERIAL_STATE notifications when the interrupt endpoint is
+    /// free. Call on every poll.
+    pub fn service_notifications(&mut self) {
+        if self.serial_state_events != 0 {
+            if self
+                .send_serial_state(SERIAL_STATE_IDLE | self.serial_state_events)
+                .is_ok()
+            {
+                self.serial_state_events = 0;
+                self.serial_state_clear_pending = true;
+            }
+        } else if self.serial_state_clear_pending
+            && self.send_serial_state(SERIAL_STATE_IDLE).is_ok()
+        {
+            self.serial_state_clear_pending = false;
+        }
+    }
+
+    fn send_serial_state(&mut self, state: u16) -> Result<usize> {
+        let index = u16::from(u8::from(self.comm_if)).to_le_bytes();
+        let state = state.to_le_bytes();
+        self.comm_ep.write(&[
+            0xA1, // bmRequestType: class, interface, device-to-host
+            NOTIFY_SERIAL_STATE,
+            0x00, // wValue
+            0x00,
+            index[0], // wIndex
+            index[1],
+            0x02, // wLength
+            0x00,
+            state[0],
+            state[1],
+        ])
+    }
+
+    /// Write the function's descriptors, 61 bytes. The optional Call
+    /// Management descriptor is left out (the device does no call
+    /// management) so that four functions still fit the 256-byte control
+    /// buffer.
+    pub fn write_descriptors(&self, writer: &mut impl DescriptorSink) -> Result<()> {
+        writer.iad(
+            self.comm_if,
+            2,
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+        )?;
+
+        writer.interface(
+            self.comm_if,
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+        )?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_ACM, ACM_CAPABILITIES])?;
+        writer.write(
+            CS_INTERFACE,
+            &[CDC_TYPE_UNION, self.comm_if.into(), self.data_if.into()],
+        )?;
+        writer.endpoint(&self.comm_ep)?;
+
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+
+        Ok(())
+    }
+
+    fn is_for_comm_if(&self, req: &control::Request) -> bool {
+        req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u16::from(u8::from(self.comm_if))
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        self.write_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.line_coding = self.default_line_coding;
+        self.dtr = false;
+        self.rts = false;
+        self.break_request = None;
+        self.serial_state_events = 0;
+        self.serial_state_clear_pending = false;
+        self.zlp_pending = false;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_for_comm_if(&req) {
+            return;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING if req.length == 7 => {
+                xfer.accept_with(&self.line_coding.to_bytes()).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_for_comm_if(&req) {
+            return;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                xfer.accept().ok();
+            }
+            REQ_SET_LINE_CODING if xfer.data().len() >= 7 => {
+                let data = xfer.data()[..7].try_into().unwrap();
+                self.line_coding = LineCoding::from_bytes(data);
+                xfer.accept().ok();
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.dtr = (req.value & 0x0001) != 0;
+                self.rts = (req.value & 0x0002) != 0;
+                xfer.accept().ok();
+            }
+            REQ_SEND_BREAK => {
+                self.break_request = Some(req.value);
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
+
+/// The bulk OUT endpoint, one packet per call
+impl<B: UsbBus> ByteSource for CdcAcmClass<'_, B> {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.read_packet(buf).unwrap_or(0)
+    }
+}
+
+/// The bulk IN endpoint, one packet per call
+impl<B: UsbBus> ByteSink for CdcAcmClass<'_, B> {
+    fn write(&mut self, data: &[u8]) -> core::result::Result<usize, SinkError> {
+        let count = data.len().min(usize::from(self.max_packet_size()));
+        match self.write_packet(&data[..count]) {
+            Ok(written) => Ok(written),
+            Err(UsbError::WouldBlock) => Ok(0),
+            Err(_) => Err(SinkError),
+        }
+    }
+
+    fn end_transfer(&mut self) {
+        self.finish_transfer();
+    }
+}
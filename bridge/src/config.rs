@@ -0,0 +1,264 @@
+//! Settings that persist across resets
+//!
+//! `Config` holds what the firmware takes from flash at boot instead of from
+//! constants: the UART pins and flow control, each channel's power-on baud
+//! rate, the USB strings and the LED behaviour. `encode`/`decode` give its byte layout,
+//! which is stored inside a versioned record (see `config_store`).
+
+use crate::pin_map::{UART_COUNT, UartPins};
+use crate::uart_config::FlowControl;
+
+/// Bridge channels: UART0, UART1 and the two PIO UARTs
+pub const CHANNELS: usize = 4;
+/// Layout version of `encode`; records of other versions are not decoded
+pub const VERSION: u8 = 2;
+/// Longest USB string in bytes of UTF-8
+pub const USB_STRING_MAX: usize = 31;
+
+/// Bytes per UART entry: kind, TX, RX, CTS, RTS
+const UART_ENTRY_LEN: usize = 5;
+/// Bytes per USB string: length, text
+const USB_STRING_LEN: usize = 1 + USB_STRING_MAX;
+/// Marks an unused pin byte and an absent USB string
+const NONE: u8 = 0xFF;
+
+/// UART entry kinds
+const KIND_BOARD_DEFAULT: u8 = 0;
+const KIND_UNCONNECTED: u8 = 1;
+const KIND_PINS: u8 = 2;
+const KIND_PINS_WITH_FLOW: u8 = 3;
+
+/// Flow control bytes (`NONE`: the firmware default)
+const FLOW_NONE: u8 = 0;
+const FLOW_RTS_CTS: u8 = 1;
+
+/// Pins a hardware UART is routed to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PinSelection {
+    /// The pins in the board file
+    #[default]
+    BoardDefault,
+    /// No pins; the channel's serial port is not wired to anything
+    Unconnected,
+    /// These pins, if they pass validation against the mux and the board
+    Pins(UartPins),
+}
+
+/// What the activity LED shows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LedMode {
+    /// Lit while data moves
+    #[default]
+    Activity,
+    /// Always lit (power indicator)
+    On,
+    /// Always dark
+    Off,
+}
+
+/// A UTF-8 string of at most `USB_STRING_MAX` bytes, stored inline
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ShortString {
+    len: u8,
+    bytes: [u8; USB_STRING_MAX],
+}
+
+impl ShortString {
+    /// `text`, or `None` if it is longer than `USB_STRING_MAX` bytes
+    pub fn new(text: &str) -> Option<Self> {
+        let len = text.len();
+        if len > USB_STRING_MAX {
+            return None;
+        }
+        let mut bytes = [0; USB_STRING_MAX];
+        bytes[..len].copy_from_slice(text.as_bytes());
+        Some(Self {
+            len: len as u8,
+            bytes,
+        })
+    }
+
+    /// The text
+    pub fn as_str(&self) -> &str {
+        // Only built from a `&str` or from decoded bytes checked to be UTF-8
+        core::str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl core::fmt::Debug for ShortString {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// USB string descriptors; `None` uses the board file's string
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsbStrings {
+    pub manufacturer: Option<ShortString>,
+    pub product: Option<ShortString>,
+    pub serial_number: Option<ShortString>,
+}
+
+/// Why bytes could not be read as a `Config`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Shorter than the layout
+    Truncated,
+    /// A field has a value no firmware writes
+    Invalid,
+}
+
+/// The persisted settings
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Pins of UART0 and UART1
+    pub uart_pins: [PinSelection; UART_COUNT],
+    /// Flow control of UART0 and UART1; `None` uses the firmware default.
+    /// RTS/CTS needs pins with CTS and RTS.
+    pub flow_control: [Option<FlowControl>; UART_COUNT],
+    /// Power-on baud rate of each channel; `None` uses the firmware default
+    pub baud: [Option<u32>; CHANNELS],
+    pub usb: UsbStrings,
+    pub led: LedMode,
+}
+
+impl Config {
+    /// Nothing saved: board pins, default flow control, baud rates and
+    /// strings
+    pub const DEFAULT: Self = Self {
+        uart_pins: [PinSelection::BoardDefault; UART_COUNT],
+        flow_control: [None; UART_COUNT],
+        baud: [None; CHANNELS],
+        usb: UsbStrings {
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+        },
+        led: LedMode::Activity,
+    };
+
+    /// Length of the encoded form
+    pub const ENCODED_LEN: usize =
+        UART_COUNT * UART_ENTRY_LEN + CHANNELS * 4 + 3 * USB_STRING_LEN + 1 + UART_COUNT;
+
+    /// The byte layout stored in a record
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [NONE; Self::ENCODED_LEN];
+        let (uarts, rest) = bytes.split_at_mut(UART_COUNT * UART_ENTRY_LEN);
+        let (bauds, rest) = rest.split_at_mut(CHANNELS * 4);
+        let (strings, rest) = rest.split_at_mut(3 * USB_STRING_LEN);
+        let (led, flows) = rest.split_at_mut(1);
+
+        for (entry, selection) in uarts.chunks_exact_mut(UART_ENTRY_LEN).zip(&self.uart_pins) {
+            entry[0] = match selection {
+                PinSelection::BoardDefault => KIND_BOARD_DEFAULT,
+                PinSelection::Unconnected => KIND_UNCONNECTED,
+                PinSelection::Pins(pins) => {
+                    entry[1] = pins.tx;
+                    entry[2] = pins.rx;
+                    match pins.flow {
+                        Some((cts, rts)) => {
+                            entry[3] = cts;
+                            entry[4] = rts;
+                            KIND_PINS_WITH_FLOW
+                        }
+                        None => KIND_PINS,
+                    }
+                }
+            };
+        }
+        for (field, baud) in bauds.chunks_exact_mut(4).zip(&self.baud) {
+            field.copy_from_slice(&baud.unwrap_or(0).to_le_bytes());
+        }
+        let usb = [
+            self.usb.manufacturer,
+            self.usb.product,
+            self.usb.serial_number,
+        ];
+        for (field, string) in strings.chunks_exact_mut(USB_STRING_LEN).zip(usb) {
+            if let Some(string) = string {
+                field[0] = string.len;
+                field[1..].copy_from_slice(&string.bytes);
+            }
+        }
+        led[0] = match self.led {
+            LedMode::Activity => 0,
+            LedMode::On => 1,
+            LedMode::Off => 2,
+        };
+        for (field, flow) in flows.iter_mut().zip(&self.flow_control) {
+            *field = match flow {
+                Some(FlowControl::None) => FLOW_NONE,
+                Some(FlowControl::RtsCts) => FLOW_RTS_CTS,
+                None => NONE,
+            };
+        }
+        bytes
+    }
+
+    /// Read a `Config` from the start of `bytes`
+    pub fn decode(bytes: &[u8]) -> Result<Self, ConfigError> {
+        let bytes = bytes
+            .get(..Self::ENCODED_LEN)
+            .ok_or(ConfigError::Truncated)?;
+        let (uarts, rest) = bytes.split_at(UART_COUNT * UART_ENTRY_LEN);
+        let (bauds, rest) = rest.split_at(CHANNELS * 4);
+        let (strings, rest) = rest.split_at(3 * USB_STRING_LEN);
+        let (led, flows) = rest.split_at(1);
+
+        let mut config = Self::DEFAULT;
+        for (selection, entry) in config
+            .uart_pins
+            .iter_mut()
+            .zip(uarts.chunks_exact(UART_ENTRY_LEN))
+        {
+            let pins = UartPins::pair(entry[1], entry[2]);
+            *selection = match entry[0] {
+                KIND_BOARD_DEFAULT => PinSelection::BoardDefault,
+                KIND_UNCONNECTED => PinSelection::Unconnected,
+                KIND_PINS => PinSelection::Pins(pins),
+                KIND_PINS_WITH_FLOW => PinSelection::Pins(UartPins {
+                    flow: Some((entry[3], entry[4])),
+                    ..pins
+                }),
+                _ => return Err(ConfigError::Invalid),
+            };
+        }
+        for (baud, field) in config.baud.iter_mut().zip(bauds.chunks_exact(4)) {
+            let value = u32::from_le_bytes([field[0], field[1], field[2], field[3]]);
+            *baud = (value != 0).then_some(value);
+        }
+        let mut usb = [None; 3];
+        for (string, field) in usb.iter_mut().zip(strings.chunks_exact(USB_STRING_LEN)) {
+            if field[0] == NONE {
+                continue;
+            }
+            let text = field[1..]
+                .get(..usize::from(field[0]))
+                .and_then(|text| core::str::from_utf8(text).ok())
+                .ok_or(ConfigError::Invalid)?;
+            *string = ShortString::new(text);
+        }
+        let [manufacturer, product, serial_number] = usb;
+        config.usb = UsbStrings {
+            manufacturer,
+            product,
+            serial_number,
+        };
+        config.led = match led[0] {
+            0 => LedMode::Activity,
+            1 => LedMode::On,
+            2 => LedMode::Off,
+            _ => return Err(ConfigError::Invalid),
+        };
+        for (flow, &field) in config.flow_control.iter_mut().zip(flows) {
+            *flow = match field {
+                NONE => None,
+                FLOW_NONE => Some(FlowControl::None),
+                FLOW_RTS_CTS => Some(FlowControl::RtsCts),
+                _ => return Err(ConfigError::Invalid),
+            };
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,262 @@
+//! Wear-levelled, power-fail safe storage of the `Config` in flash
+//!
+//! The last `SECTORS` erase sectors of the flash hold a log of fixed-size
+//! records. A save programs the erased slot after the newest record; when
+//! that sector is full, the other sector is erased and the log continues
+//! there. The sector holding the newest record is never erased, so a reset
+//! in the middle of a save leaves the previous record readable, and a
+//! record that was cut short fails its CRC and is skipped.
+//!
+//! Record layout (`RECORD_SIZE` bytes, one flash page):
+//!
+//! | Offset | Size | Field                                          |
+//! |--------|------|------------------------------------------------|
+//! | 0      | 4    | `MAGIC`                                        |
+//! | 4      | 1    | `Config` layout version (`config::VERSION`)    |
+//! | 5      | 1    | 0                                              |
+//! | 6      | 2    | Payload length (LE)                            |
+//! | 8      | 4    | Sequence number (LE), one more than the last   |
+//! | 12     | n    | Payload (`Config::encode`)                     |
+//! | 12 + n | 4    | CRC-32 of bytes 0..12 + n (LE)                 |
+
+use crate::config::{Config, ConfigError, VERSION};
+
+/// Flash erase sector
+pub const SECTOR_SIZE: u32 = 4096;
+/// Sectors the records rotate through
+pub const SECTORS: u32 = 2;
+/// Flash at the end of the chip reserved for the store
+pub const STORE_SIZE: u32 = SECTOR_SIZE * SECTORS;
+/// One record, programmed as one flash page
+pub const RECORD_SIZE: usize = 256;
+/// Record slots per sector
+const RECORDS_PER_SECTOR: u32 = SECTOR_SIZE / RECORD_SIZE as u32;
+
+/// Start of every record
+pub const MAGIC: [u8; 4] = *b"PTCF";
+/// Bytes before the payload
+const HEADER_LEN: usize = 12;
+/// Bytes after the payload
+const CRC_LEN: usize = 4;
+
+const _: () = assert!(HEADER_LEN + Config::ENCODED_LEN + CRC_LEN <= RECORD_SIZE);
+
+/// CRC-32 (IEEE 802.3, as used by zlib and PNG)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The store's flash, addressed from the start of its first sector
+pub trait ConfigFlash {
+    /// Copy the bytes at `offset` into `buf`
+    fn read(&self, offset: u32, buf: &mut [u8]);
+
+    /// Erase the sector at `offset` to 0xFF
+    fn erase(&mut self, offset: u32);
+
+    /// Program the record slot at `offset`. Programming only clears bits,
+    /// so the slot must have been erased.
+    fn program(&mut self, offset: u32, data: &[u8; RECORD_SIZE]);
+}
+
+impl<T: ConfigFlash + ?Sized> ConfigFlash for &mut T {
+    fn read(&self, offset: u32, buf: &mut [u8]) {
+        (**self).read(offset, buf);
+    }
+
+    fn erase(&mut self, offset: u32) {
+        (**self).erase(offset);
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8; RECORD_SIZE]) {
+        (**self).program(offset, data);
+    }
+}
+
+/// Why `load` has no configuration to return
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// No record has been saved (or none survived)
+    Empty,
+    /// The newest record was written by a firmware with another layout
+    UnsupportedVersion(u8),
+    /// The newest record's payload does not decode
+    Invalid(ConfigError),
+}
+
+/// Why `save` failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveError {
+    /// The record read back differs from what was programmed
+    Verify,
+}
+
+/// Position and header of a valid record
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    sector: u32,
+    index: u32,
+    sequence: u32,
+    version: u8,
+}
+
+impl Slot {
+    fn offset(&self) -> u32 {
+        slot_offset(self.sector, self.index)
+    }
+}
+
+fn slot_offset(sector: u32, index: u32) -> u32 {
+    sector * SECTOR_SIZE + index * RECORD_SIZE as u32
+}
+
+/// True if sequence number `a` was written after `b` (wrapping)
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The configuration records in `F`
+pub struct ConfigStore<F> {
+    flash: F,
+    /// The newest valid record
+    newest: Option<Slot>,
+}
+
+impl<F: ConfigFlash> ConfigStore<F> {
+    /// Scan `flash` for the newest valid record
+    pub fn new(flash: F) -> Self {
+        let mut store = Self {
+            flash,
+            newest: None,
+        };
+        for sector in 0..SECTORS {
+            for index in 0..RECORDS_PER_SECTOR {
+                let Some(slot) = store.read_slot(sector, index) else {
+                    continue;
+                };
+                if store
+                    .newest
+                    .is_none_or(|newest| is_newer(slot.sequence, newest.sequence))
+                {
+                    store.newest = Some(slot);
+                }
+            }
+        }
+        store
+    }
+
+    /// The flash the records live in
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Sequence number of the newest record (how many saves there have
+    /// been), or `None` if there is none
+    pub fn sequence(&self) -> Option<u32> {
+        self.newest.map(|slot| slot.sequence)
+    }
+
+    /// The configuration in the newest record
+    pub fn load(&self) -> Result<Config, LoadError> {
+        let newest = self.newest.ok_or(LoadError::Empty)?;
+        if newest.version != VERSION {
+            return Err(LoadError::UnsupportedVersion(newest.version));
+        }
+        let mut record = [0; RECORD_SIZE];
+        self.flash.read(newest.offset(), &mut record);
+        Config::decode(&record[HEADER_LEN..]).map_err(LoadError::Invalid)
+    }
+
+    /// Append `config` as the newest record
+    pub fn save(&mut self, config: &Config) -> Result<(), SaveError> {
+        let (sector, index) = match self.newest {
+            Some(newest)
+                if newest.index + 1 < RECORDS_PER_SECTOR
+                    && self.is_erased(newest.sector, newest.index + 1) =>
+            {
+                (newest.sector, newest.index + 1)
+            }
+            // Full (or a torn write after the newest record): continue in
+            // the other sector
+            Some(newest) => ((newest.sector + 1) % SECTORS, 0),
+            None => (0, 0),
+        };
+        if index == 0 {
+            self.flash.erase(sector * SECTOR_SIZE);
+        }
+
+        let sequence = self
+            .newest
+            .map_or(1, |newest| newest.sequence.wrapping_add(1));
+        let record = encode_record(config, sequence);
+        let offset = slot_offset(sector, index);
+        self.flash.program(offset, &record);
+
+        let mut written = [0; RECORD_SIZE];
+        self.flash.read(offset, &mut written);
+        if written != record {
+            return Err(SaveError::Verify);
+        }
+        self.newest = Some(Slot {
+            sector,
+            index,
+            sequence,
+            version: VERSION,
+        });
+        Ok(())
+    }
+
+    /// The slot's header if it holds a record with a valid CRC
+    fn read_slot(&self, sector: u32, index: u32) -> Option<Slot> {
+        let mut record = [0; RECORD_SIZE];
+        self.flash.read(slot_offset(sector, index), &mut record);
+        if !record.starts_with(&MAGIC) {
+            return None;
+        }
+        let len = usize::from(u16::from_le_bytes([record[6], record[7]]));
+        let end = HEADER_LEN + len;
+        let crc = record.get(end..end + CRC_LEN)?;
+        if crc32(&record[..end]).to_le_bytes() != crc {
+            return None;
+        }
+        Some(Slot {
+            sector,
+            index,
+            sequence: u32::from_le_bytes([record[8], record[9], record[10], record[11]]),
+            version: record[4],
+        })
+    }
+
+    fn is_erased(&self, sector: u32, index: u32) -> bool {
+        let mut record = [0; RECORD_SIZE];
+        self.flash.read(slot_offset(sector, index), &mut record);
+        record.iter().all(|&byte| byte == 0xFF)
+    }
+}
+
+/// A record holding `config`, padded with 0xFF to the slot size
+fn encode_record(config: &Config, sequence: u32) -> [u8; RECORD_SIZE] {
+    let payload = config.encode();
+    let end = HEADER_LEN + payload.len();
+    let mut record = [0xFF; RECORD_SIZE];
+    record[..4].copy_from_slice(&MAGIC);
+    record[4] = VERSION;
+    record[5] = 0;
+    record[6..8].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    record[8..12].copy_from_slice(&sequence.to_le_bytes());
+    record[HEADER_LEN..end].copy_from_slice(&payload);
+    let crc = crc32(&record[..end]);
+    record[end..end + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+    record
+}
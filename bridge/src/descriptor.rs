@@ -0,0 +1,255 @@
+//! Configuration descriptors longer than usb-device's control buffer
+//!
+//! usb-device builds the configuration descriptor in its control buffer,
+//! which is at most 256 bytes: the header and four CDC-ACM functions (253
+//! bytes). For more functions, `ConfigDescriptorWriter` writes the whole
+//! descriptor into a buffer of its own once, before the device is built, and
+//! `ConfigDescriptor` answers GET_DESCRIPTOR(CONFIGURATION) from it. Classes
+//! get the control request before the device's standard handling, and a
+//! static buffer is sent without passing through the control buffer.
+//!
+//! Functions write their descriptors through `DescriptorSink`, so the same
+//! code serves usb-device's `DescriptorWriter` and `ConfigDescriptorWriter`.
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::endpoint::{Endpoint, EndpointDirection};
+use usb_device::{Result, UsbError};
+
+const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+const DESCRIPTOR_IAD: u8 = 0x0B;
+
+/// bConfigurationValue of usb-device's only configuration
+const CONFIGURATION_VALUE: u8 = 1;
+/// bmAttributes and bMaxPower as usb-device writes them by default: bus
+/// powered, no remote wakeup, 100 mA
+const CONFIGURATION_ATTRIBUTES: u8 = 0x80;
+const CONFIGURATION_MAX_POWER: u8 = 50;
+
+/// Where a function writes its interface, endpoint and class descriptors
+pub trait DescriptorSink {
+    /// An interface association descriptor without a string
+    fn iad(
+        &mut self,
+        first_interface: InterfaceNumber,
+        interface_count: u8,
+        function_class: u8,
+        function_sub_class: u8,
+        function_protocol: u8,
+    ) -> Result<()>;
+
+    /// An interface descriptor (default alternate setting, no string)
+    fn interface(
+        &mut self,
+        number: InterfaceNumber,
+        interface_class: u8,
+        interface_sub_class: u8,
+        interface_protocol: u8,
+    ) -> Result<()>;
+
+    /// An endpoint descriptor of the last interface
+    fn endpoint<B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'_, B, D>,
+    ) -> Result<()>;
+
+    /// Any other descriptor
+    fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> Result<()>;
+}
+
+impl DescriptorSink for DescriptorWriter<'_> {
+    fn iad(
+        &mut self,
+        first_interface: InterfaceNumber,
+        interface_count: u8,
+        function_class: u8,
+        function_sub_class: u8,
+        function_protocol: u8,
+    ) -> Result<()> {
+        DescriptorWriter::iad(
+            self,
+            first_interface,
+            interface_count,
+            function_class,
+            function_sub_class,
+            function_protocol,
+            None,
+        )
+    }
+
+    fn interface(
+        &mut self,
+        number: InterfaceNumber,
+        interface_class: u8,
+        interface_sub_class: u8,
+        interface_protocol: u8,
+    ) -> Result<()> {
+        DescriptorWriter::interface(
+            self,
+            number,
+            interface_class,
+            interface_sub_class,
+            interface_protocol,
+        )
+    }
+
+    fn endpoint<B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'_, B, D>,
+    ) -> Result<()> {
+        DescriptorWriter::endpoint(self, endpoint)
+    }
+
+    fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> Result<()> {
+        DescriptorWriter::write(self, descriptor_type, descriptor)
+    }
+}
+
+/// Writes a complete configuration descriptor, with IADs, into a buffer
+pub struct ConfigDescriptorWriter<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+    /// bNumEndpoints of the last interface
+    num_endpoints_mark: Option<usize>,
+}
+
+impl<'a> ConfigDescriptorWriter<'a> {
+    /// Start a configuration descriptor in `buf` with usb-device's default
+    /// attributes
+    pub fn new(buf: &'a mut [u8]) -> Result<Self> {
+        let mut writer = Self {
+            buf,
+            position: 0,
+            num_endpoints_mark: None,
+        };
+        writer.write(
+            DESCRIPTOR_CONFIGURATION,
+            &[
+                0, // wTotalLength
+                0,
+                0, // bNumInterfaces
+                CONFIGURATION_VALUE,
+                0, // iConfiguration
+                CONFIGURATION_ATTRIBUTES,
+                CONFIGURATION_MAX_POWER,
+            ],
+        )?;
+        Ok(writer)
+    }
+
+    /// Fill in the total length and return the descriptor
+    pub fn finish(self) -> &'a [u8] {
+        let length = self.position as u16;
+        self.buf[2..4].copy_from_slice(&length.to_le_bytes());
+        &self.buf[..self.position]
+    }
+}
+
+impl DescriptorSink for ConfigDescriptorWriter<'_> {
+    fn iad(
+        &mut self,
+        first_interface: InterfaceNumber,
+        interface_count: u8,
+        function_class: u8,
+        function_sub_class: u8,
+        function_protocol: u8,
+    ) -> Result<()> {
+        self.write(
+            DESCRIPTOR_IAD,
+            &[
+                first_interface.into(),
+                interface_count,
+                function_class,
+                function_sub_class,
+                function_protocol,
+                0, // iFunction
+            ],
+        )
+    }
+
+    fn interface(
+        &mut self,
+        number: InterfaceNumber,
+        interface_class: u8,
+        interface_sub_class: u8,
+        interface_protocol: u8,
+    ) -> Result<()> {
+        let mark = self.position + 4;
+        self.write(
+            DESCRIPTOR_INTERFACE,
+            &[
+                number.into(),
+                0, // bAlternateSetting
+                0, // bNumEndpoints
+                interface_class,
+                interface_sub_class,
+                interface_protocol,
+                0, // iInterface
+            ],
+        )?;
+        self.buf[4] += 1;
+        self.num_endpoints_mark = Some(mark);
+        Ok(())
+    }
+
+    fn endpoint<B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'_, B, D>,
+    ) -> Result<()> {
+        let mark = self.num_endpoints_mark.ok_or(UsbError::InvalidState)?;
+        let [mps_lo, mps_hi] = endpoint.max_packet_size().to_le_bytes();
+        self.write(
+            DESCRIPTOR_ENDPOINT,
+            &[
+                endpoint.address().into(),
+                endpoint.ep_type().to_bm_attributes(),
+                mps_lo,
+                mps_hi,
+                endpoint.interval(),
+            ],
+        )?;
+        self.buf[mark] += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> Result<()> {
+        let length = descriptor.len() + 2;
+        let end = self.position + length;
+        let buf = self
+            .buf
+            .get_mut(self.position..end)
+            .ok_or(UsbError::BufferOverflow)?;
+        buf[0] = length as u8;
+        buf[1] = descriptor_type;
+        buf[2..].copy_from_slice(descriptor);
+        self.position = end;
+        Ok(())
+    }
+}
+
+/// Answers GET_DESCRIPTOR(CONFIGURATION) with a descriptor built by
+/// `ConfigDescriptorWriter`
+pub struct ConfigDescriptor {
+    descriptor: &'static [u8],
+}
+
+impl ConfigDescriptor {
+    pub fn new(descriptor: &'static [u8]) -> Self {
+        Self { descriptor }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for ConfigDescriptor {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::GET_DESCRIPTOR
+            && req.descriptor_type_index() == (DESCRIPTOR_CONFIGURATION, 0)
+        {
+            xfer.accept_with_static(self.descriptor).ok();
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! In-band escape menu on a data port
+//!
+//! For setups with only one terminal, an escape sequence typed into a
+//! channel's data port opens a small menu on the device instead of going
+//! to the UART, like ssh's `~` escapes: `~` at the start of a line followed
+//! by `.` (`EscapeKey::Tilde`), or a control character anywhere followed by
+//! any other byte (`EscapeKey::Control`); a menu key right after the
+//! control character is carried out at once. Typing the escape twice sends
+//! it once. While the menu is open, what the host types goes to the menu,
+//! and the menu's text is sent to the host in place of the UART data, which
+//! waits in its queue. An LF right after the CR that ends a menu entry is
+//! dropped, so a terminal sending CRLF does not pass it on to the UART.
+
+use crate::byte_ring::Producer;
+use crate::host::MAX_PACKET_SIZE;
+use crate::shell::{LineFormat, Output};
+use crate::stats::LineErrors;
+use crate::uart_config::LineConfig;
+use crate::{ByteSink, ByteSource, SinkError};
+use core::fmt::Write;
+
+/// Menu text waiting for the IN endpoint; more is dropped
+const OUTPUT_SIZE: usize = 256;
+/// Digits of the longest baud rate that can be typed
+const BAUD_DIGITS_MAX: u8 = 8;
+
+const CTRL_C: u8 = 0x03;
+const ESC: u8 = 0x1B;
+
+/// What opens the escape menu
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EscapeKey {
+    /// `~` at the start of a line (after CR or LF), then `.`
+    Tilde,
+    /// This control character, e.g. 0x1D for Ctrl-]
+    Control(u8),
+}
+
+impl EscapeKey {
+    /// The byte that starts the sequence
+    fn byte(self) -> u8 {
+        match self {
+            Self::Tilde => b'~',
+            Self::Control(byte) => byte,
+        }
+    }
+}
+
+/// The channel as seen by the escape menu
+pub trait MenuTarget {
+    /// Line configuration the UART runs with
+    fn line_config(&self) -> LineConfig;
+
+    /// Switch the UART to `baud`, keeping the character format
+    fn set_baud(&mut self, baud: u32);
+
+    /// Send a break on TX
+    fn send_break(&mut self);
+
+    /// Pulse the target's reset line. Returns false if the channel has none.
+    fn reset_target(&mut self) -> bool;
+
+    /// RX line error counters
+    fn line_errors(&self) -> LineErrors;
+
+    /// UART→host bytes lost because the IN endpoint failed
+    fn dropped(&self) -> u32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Passing data to the UART; `line_start` after CR or LF
+    Data { line_start: bool },
+    /// `~` at the start of a line, held back until the next byte
+    Tilde,
+    /// The control key, held back until the next byte
+    Control,
+    /// Waiting for a menu key
+    Menu,
+    /// Typing a baud rate
+    Baud { rate: u32, digits: u8 },
+}
+
+/// Escape detection and menu of one channel
+pub struct Escape {
+    key: Option<EscapeKey>,
+    state: State,
+    /// The last byte was a CR that ended a menu entry
+    after_cr: bool,
+    output: Output<OUTPUT_SIZE>,
+}
+
+impl Escape {
+    /// Detect `key` in the host's data (`None`: pass everything through)
+    pub const fn new(key: Option<EscapeKey>) -> Self {
+        Self {
+            key,
+            state: State::Data { line_start: true },
+            after_cr: false,
+            output: Output::new(),
+        }
+    }
+
+    /// True while the menu is open or its text is still being sent; the
+    /// channel's UART data waits meanwhile
+    pub fn is_active(&self) -> bool {
+        !matches!(
+            self.state,
+            State::Data { .. } | State::Tilde | State::Control
+        ) || !self.output.is_empty()
+    }
+
+    /// Move OUT packets from the host into the queue to the UART like
+    /// `host::receive_packets`, taking escape sequences out on the way. A
+    /// packet is only read when the queue has room for one byte more than
+    /// a packet, for an escape key held back from the packet before.
+    /// Returns true if data was received.
+    pub fn receive_packets<const N: usize>(
+        &mut self,
+        host: &mut impl ByteSource,
+        packet_size: usize,
+        producer: &mut Producer<'_, N>,
+        target: &mut impl MenuTarget,
+    ) -> bool {
+        assert!(packet_size <= MAX_PACKET_SIZE);
+        let mut received = false;
+        while producer.free() > packet_size {
+            let mut packet = [0u8; MAX_PACKET_SIZE];
+            let count = host.read(&mut packet[..packet_size]);
+            if count == 0 {
+                break;
+            }
+            let mut data = [0u8; MAX_PACKET_SIZE + 1];
+            let kept = self.filter(&packet[..count], &mut data, target);
+            producer.push_slice(&data[..kept]);
+            received = true;
+        }
+        received
+    }
+
+    /// Run `data` from the host through the escape detection and the menu
+    /// and write what goes on to the UART into `out`, which must have room
+    /// for one byte more than `data`. Returns the number of bytes written.
+    pub fn filter(&mut self, data: &[u8], out: &mut [u8], target: &mut impl MenuTarget) -> usize {
+        assert!(out.len() > data.len());
+        let mut kept = 0;
+        let mut keep = |byte: u8| {
+            out[kept] = byte;
+            kept += 1;
+        };
+        for &byte in data {
+            if core::mem::replace(&mut self.after_cr, false) && byte == b'\n' {
+                continue;
+            }
+            match self.state {
+                State::Data { line_start } => match self.key {
+                    Some(EscapeKey::Tilde) if line_start && byte == b'~' => {
+                        self.state = State::Tilde;
+                    }
+                    Some(EscapeKey::Control(key)) if byte == key => {
+                        self.state = State::Control;
+                    }
+                    _ => {
+                        keep(byte);
+                        self.state = State::Data {
+                            line_start: is_line_end(byte),
+                        };
+                    }
+                },
+                State::Tilde => match byte {
+                    b'.' => self.open_menu(target),
+                    b'~' => {
+                        keep(b'~');
+                        self.state = State::Data { line_start: false };
+                    }
+                    _ => {
+                        keep(b'~');
+                        keep(byte);
+                        self.state = State::Data {
+                            line_start: is_line_end(byte),
+                        };
+                    }
+                },
+                State::Control => {
+                    if self.key.is_some_and(|key| key.byte() == byte) {
+                        keep(byte);
+                        self.state = State::Data { line_start: false };
+                    } else {
+                        self.open_menu(target);
+                        if matches!(byte, b'b' | b'k' | b'r' | b's') {
+                            self.menu_key(byte, target);
+                        }
+                        self.after_cr = byte == b'\r';
+                    }
+                }
+                State::Menu => {
+                    if self.key.is_some_and(|key| key.byte() == byte) {
+                        keep(byte);
+                        self.close_menu();
+                    } else {
+                        self.menu_key(byte, target);
+                        self.after_cr = byte == b'\r';
+                    }
+                }
+                State::Baud { rate, digits } => {
+                    self.baud_key(byte, rate, digits, target);
+                    self.after_cr = byte == b'\r';
+                }
+            }
+        }
+        kept
+    }
+
+    /// Hand waiting menu text to `sink` as long as it takes it. Returns
+    /// true if anything was sent.
+    pub fn send(&mut self, sink: &mut impl ByteSink) -> Result<bool, SinkError> {
+        self.output.send(sink)
+    }
+
+    fn open_menu(&mut self, target: &impl MenuTarget) {
+        self.state = State::Menu;
+        self.output.line(format_args!(
+            "\r\n[picoterm] {}",
+            LineFormat(target.line_config())
+        ));
+        self.show_keys();
+    }
+
+    fn show_keys(&mut self) {
+        self.output.line(format_args!(
+            "[picoterm] b: baud rate, k: send break, r: reset target, s: stats, .: back"
+        ));
+    }
+
+    fn close_menu(&mut self) {
+        self.state = State::Data { line_start: true };
+    }
+
+    fn menu_key(&mut self, byte: u8, target: &mut impl MenuTarget) {
+        match byte {
+            b'b' => {
+                self.state = State::Baud { rate: 0, digits: 0 };
+                let _ = self.output.write_str("[picoterm] baud rate: ");
+            }
+            b'k' => {
+                target.send_break();
+                self.output.line(format_args!("[picoterm] break sent"));
+                self.close_menu();
+            }
+            b'r' => {
+                if target.reset_target() {
+                    self.output.line(format_args!("[picoterm] target reset"));
+                } else {
+                    self.output
+                        .line(format_args!("[picoterm] no reset line on this channel"));
+                }
+                self.close_menu();
+            }
+            b's' => {
+                let errors = target.line_errors();
+                self.output.line(format_args!(
+                    "[picoterm] framing {}, parity {}, breaks {}, overrun {}, dropped {}",
+                    errors.framing,
+                    errors.parity,
+                    errors.breaks,
+                    errors.overrun,
+                    target.dropped()
+                ));
+                self.close_menu();
+            }
+            b'.' | b'\r' | b'\n' | CTRL_C | ESC => self.close_menu(),
+            _ => self.show_keys(),
+        }
+    }
+
+    fn baud_key(&mut self, byte: u8, rate: u32, digits: u8, target: &mut impl MenuTarget) {
+        match byte {
+            b'0'..=b'9' if digits < BAUD_DIGITS_MAX => {
+                self.state = State::Baud {
+                    rate: rate * 10 + u32::from(byte - b'0'),
+                    digits: digits + 1,
+                };
+                let _ = self.output.write_char(char::from(byte));
+            }
+            // Backspace / DEL
+            0x08 | 0x7F if digits > 0 => {
+                self.state = State::Baud {
+                    rate: rate / 10,
+                    digits: digits - 1,
+                };
+                let _ = self.output.write_str("\x08 \x08");
+            }
+            b'\r' | b'\n' if rate > 0 => {
+                target.set_baud(rate);
+                self.output.line(format_args!(
+                    "\r\n[picoterm] {}",
+                    LineFormat(target.line_config())
+                ));
+                self.close_menu();
+            }
+            b'\r' | b'\n' | CTRL_C | ESC => {
+                self.output
+                    .line(format_args!("\r\n[picoterm] baud rate unchanged"));
+                self.close_menu();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_line_end(byte: u8) -> bool {
+    byte == b'\r' || byte == b'\n'
+}
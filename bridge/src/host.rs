@@ -0,0 +1,243 @@
+//! Host side of the data path (Core0 in the firmware)
+//!
+//! Moves packets between a CDC function's bulk endpoints and a channel's
+//! pair of queues, and keeps the channel's line state in step: the line
+//! coding goes to the UART when the host changes it, and line errors come
+//! back as SERIAL_STATE events.
+
+use crate::byte_ring::{Consumer, Producer};
+use crate::cdc::LineCoding;
+use crate::cdc_acm::CdcAcmClass;
+use crate::stats::LineErrors;
+use crate::uart_config::LineConfig;
+use crate::{ByteSink, ByteSource, UartControl};
+use usb_device::bus::UsbBus;
+
+/// Largest bulk packet a full-speed device can use
+pub const MAX_PACKET_SIZE: usize = 64;
+
+/// Move OUT packets from the host into the queue to the UART.
+///
+/// A packet is only read when the queue has room for a full packet. Until
+/// then it stays in the endpoint buffer and the host's further OUT
+/// transfers are NAKed, so a large transfer is throttled to the UART's
+/// speed instead of losing data. As the packet may have arrived during an
+/// earlier poll, this is called on every pass. Returns true if data was
+/// received.
+pub fn receive_packets<const N: usize>(
+    host: &mut impl ByteSource,
+    packet_size: usize,
+    producer: &mut Producer<'_, N>,
+) -> bool {
+    assert!(packet_size <= MAX_PACKET_SIZE);
+    let mut received = false;
+    while producer.free() >= packet_size {
+        let grant = producer.grant();
+        let count = if grant.len() >= packet_size {
+            // Read straight into the queue
+            let count = host.read(&mut grant[..packet_size]);
+            producer.commit(count);
+            count
+        } else {
+            // The free space wraps around: copy the packet in two parts.
+            // Cannot fall short: this is the only producer and checked the room
+            let mut buf = [0u8; MAX_PACKET_SIZE];
+            let count = host.read(&mut buf[..packet_size]);
+            producer.push_slice(&buf[..count]);
+            count
+        };
+        if count == 0 {
+            break;
+        }
+        received = true;
+    }
+    received
+}
+
+/// Host-side state of one bridge channel
+pub struct HostChannel {
+    /// Line configuration last forwarded to the UART
+    line_config: LineConfig,
+    /// Line configuration the host last asked for
+    host_line_config: LineConfig,
+    /// Line error counters last reported to the host
+    reported_errors: LineErrors,
+    /// UART→host bytes the IN endpoint rejected
+    dropped: u32,
+    /// UART→host data is held back until a packet fills or RX has been
+    /// idle this long (µs)
+    latency_us: u32,
+    /// Received character that sends the UART→host data right away
+    event_char: Option<u8>,
+    /// Bytes queued at the last check; more means new RX data
+    seen: usize,
+    /// Time (µs) when new RX data was last seen
+    last_rx_us: u32,
+    /// Bytes up to and including the last event character, sent right away
+    urgent: usize,
+}
+
+impl HostChannel {
+    /// A channel whose UART starts out with `line_config`
+    pub const fn new(line_config: LineConfig, latency_us: u32, event_char: Option<u8>) -> Self {
+        Self {
+            line_config,
+            host_line_config: line_config,
+            reported_errors: LineErrors::NONE,
+            dropped: 0,
+            latency_us,
+            event_char,
+            seen: 0,
+            last_rx_us: 0,
+            urgent: 0,
+        }
+    }
+
+    /// Line configuration last forwarded to the UART
+    pub fn line_config(&self) -> LineConfig {
+        self.line_config
+    }
+
+    /// UART→host bytes lost because the IN endpoint failed
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Forward the host's line coding to the UART when it changes.
+    ///
+    /// Settings the UART cannot represent are ignored and the previous
+    /// configuration stays in effect.
+    pub fn sync_line_coding(&mut self, coding: &LineCoding, uart: &mut impl UartControl) {
+        let Some(config) = LineConfig::from_line_coding(coding) else {
+            return;
+        };
+        if self.host_line_config != config {
+            self.host_line_config = config;
+            self.set_line_config(config, uart);
+        }
+    }
+
+    /// Switch the UART to `config` on the device's own account (the escape
+    /// menu). It stays in effect until the host sets a new line coding.
+    pub fn set_line_config(&mut self, config: LineConfig, uart: &mut impl UartControl) {
+        if self.line_config != config {
+            self.line_config = config;
+            uart.set_line_config(config);
+        }
+    }
+
+    /// SERIAL_STATE event bits for the UART's line errors since the last
+    /// call, or 0 if nothing changed. Data lost on the way to the host
+    /// counts as an overrun too.
+    pub fn line_error_events(&mut self, uart: &impl UartControl) -> u16 {
+        let mut errors = uart.line_errors();
+        errors.overrun = errors.overrun.wrapping_add(self.dropped);
+        let events = errors.serial_state_events(&self.reported_errors);
+        self.reported_errors = errors;
+        events
+    }
+
+    /// Exchange line state with the channel's CDC function: the host's line
+    /// coding goes to the UART, the UART's line errors go back as
+    /// SERIAL_STATE notifications.
+    ///
+    /// `configured` gates the line coding: until enumeration has completed
+    /// the CDC function only reports its built-in default.
+    pub fn sync_cdc<B: UsbBus>(
+        &mut self,
+        serial: &mut CdcAcmClass<'_, B>,
+        configured: bool,
+        uart: &mut impl UartControl,
+    ) {
+        if configured {
+            self.sync_line_coding(serial.line_coding(), uart);
+        }
+        let events = self.line_error_events(uart);
+        if events != 0 {
+            serial.report_serial_state(events);
+        }
+        serial.service_notifications();
+    }
+
+    /// Send the queued UART→host data, as many packets as the IN endpoint
+    /// takes. `now_us` is a free-running microsecond clock.
+    ///
+    /// Like an FTDI latency timer, data is held back until a full packet is
+    /// queued, the event character has arrived, or no new data has come in
+    /// for the latency time; `flush_deadline` tells when the held data is
+    /// due.
+    ///
+    /// Packets are written from the queue in place and their bytes are only
+    /// released once the endpoint accepts them: while it is busy they stay
+    /// queued and are offered again on the next call. Only an endpoint
+    /// error discards them; those bytes are counted in `dropped` and
+    /// reported as an overrun. Once the queue is empty the transfer is
+    /// ended (`ByteSink::end_transfer`), so data that filled the last
+    /// packet exactly does not wait at the host. Returns true if a packet
+    /// was sent.
+    pub fn send_packets<const N: usize>(
+        &mut self,
+        consumer: &mut Consumer<'_, N>,
+        host: &mut impl ByteSink,
+        packet_size: usize,
+        now_us: u32,
+    ) -> bool {
+        let queued = consumer.len();
+        if queued > self.seen {
+            self.last_rx_us = now_us;
+            if let Some(event_char) = self.event_char
+                && let Some(offset) = consumer.find(self.seen, event_char)
+            {
+                self.urgent = offset + 1;
+            }
+        }
+        self.seen = queued;
+
+        let mut sent = false;
+        loop {
+            // Bytes that arrived after the check above wait for the next call
+            let data = consumer.read_grant();
+            let count = data.len().min(packet_size).min(self.seen);
+            if count == 0 {
+                break;
+            }
+            let idle_us = now_us.wrapping_sub(self.last_rx_us);
+            if self.urgent == 0 && self.seen < packet_size && idle_us < self.latency_us {
+                break;
+            }
+
+            let count = match host.write(&data[..count]) {
+                Ok(0) => break,
+                Ok(written) => written,
+                Err(_) => {
+                    self.dropped = self.dropped.wrapping_add(count as u32);
+                    self.release(consumer, count);
+                    break;
+                }
+            };
+            self.release(consumer, count);
+            sent = true;
+        }
+        if consumer.is_empty() {
+            host.end_transfer();
+        }
+        sent
+    }
+
+    /// When the held-back UART→host data is due under the latency timer,
+    /// if that is still ahead of `now_us`. Data already due is only
+    /// waiting for the IN endpoint.
+    pub fn flush_deadline(&self, now_us: u32) -> Option<u32> {
+        if self.seen == 0 {
+            return None;
+        }
+        let deadline = self.last_rx_us.wrapping_add(self.latency_us);
+        (deadline.wrapping_sub(now_us) as i32 > 0).then_some(deadline)
+    }
+
+    fn release<const N: usize>(&mut self, consumer: &mut Consumer<'_, N>, count: usize) {
+        consumer.release(count);
+        self.seen -= count;
+        self.urgent = self.urgent.saturating_sub(count);
+    }
+}
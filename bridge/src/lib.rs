@@ -0,0 +1,67 @@
+//! Hardware-independent data path of the picoterm bridge
+//!
+//! Everything that moves bytes and line state between a CDC-ACM function
+//! and a UART without touching a register lives here: the queues between
+//! the two sides, the line coding translation, flow control in both
+//! directions, the latency timer and the line error statistics. The UART
+//! pin mux table, the persisted settings and their wear-levelled flash
+//! store live here too, so they can be checked on the host.
+//!
+//! The firmware plugs its USB endpoints and UART ports in through
+//! `ByteSource`, `ByteSink` and `UartControl`. The simulation in
+//! `tests/pty_bridge.rs` plugs in Linux pseudo-terminals instead, so the
+//! same code runs under `cargo test` on the host.
+
+#![no_std]
+
+pub mod byte_ring;
+pub mod cdc;
+pub mod cdc_acm;
+pub mod config;
+pub mod config_store;
+pub mod descriptor;
+pub mod escape;
+pub mod host;
+pub mod pin_map;
+pub mod pump;
+pub mod shell;
+pub mod stats;
+pub mod uart_config;
+
+use stats::LineErrors;
+use uart_config::LineConfig;
+
+/// Where data comes from: a UART's receiver or a CDC OUT endpoint
+pub trait ByteSource {
+    /// Copy waiting data into `buf` and return the number of bytes (0 if
+    /// there is none). A packet-based source returns one packet per call.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+}
+
+/// Data offered to a `ByteSink` could not be delivered and is lost
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SinkError;
+
+/// Where data goes: a UART's transmitter or a CDC IN endpoint
+pub trait ByteSink {
+    /// Take a prefix of `data` and return its length. 0 means the sink is
+    /// busy; the data is offered again later.
+    fn write(&mut self, data: &[u8]) -> Result<usize, SinkError>;
+
+    /// Called when everything queued has been written. A USB IN endpoint
+    /// ends a transfer whose last packet was full-size with a zero-length
+    /// packet here, or else the host keeps waiting for more.
+    fn end_transfer(&mut self) {}
+}
+
+/// Line control of a UART, driven by the host side
+pub trait UartControl {
+    /// Switch to a new line configuration
+    fn set_line_config(&mut self, config: LineConfig);
+
+    /// Start or stop driving a break on TX
+    fn set_break(&mut self, asserted: bool);
+
+    /// Current RX line error counters
+    fn line_errors(&self) -> LineErrors;
+}
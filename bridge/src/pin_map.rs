@@ -0,0 +1,111 @@
+//! Hardware UART pin mapping and the GPIO function mux
+//!
+//! This is the table of the UART function F2, which both the RP2040 and the
+//! RP2350 have. TX, RX, CTS and RTS repeat every 4 pins and the UART
+//! alternates in blocks of 8 (UART0 on GPIO0-3, UART1 on GPIO4-11, UART0 on
+//! GPIO12-19, ...), so UART0 can use GPIO0/1, 12/13, 16/17 or 28/29 for TX/RX.
+//! A mapping chosen at run time is checked against this table before a pin
+//! is switched over.
+//!
+//! The RP2350 also has UART_AUX (F11), which puts the same UART's TX and RX
+//! on the CTS and RTS pins (TX on GPIO2, RX on GPIO3, ...). The firmware only
+//! routes pins to F2, so `validate` rejects those mappings on both chips.
+
+/// User GPIOs (GPIO0-GPIO29)
+pub const GPIO_COUNT: usize = 30;
+/// Hardware UARTs (UART0 and UART1)
+pub const UART_COUNT: usize = 2;
+
+/// Signal of a GPIO in its UART function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartSignal {
+    Tx,
+    Rx,
+    Cts,
+    Rts,
+}
+
+/// (UART, signal) of `gpio` in its UART function
+pub const fn uart_function(gpio: u8) -> (usize, UartSignal) {
+    let uart = ((gpio as usize + 4) / 8) % 2;
+    let signal = match gpio % 4 {
+        0 => UartSignal::Tx,
+        1 => UartSignal::Rx,
+        2 => UartSignal::Cts,
+        _ => UartSignal::Rts,
+    };
+    (uart, signal)
+}
+
+/// The TX/RX pairs `uart` can be routed to, in GPIO order
+pub fn tx_rx_pairs(uart: usize) -> impl Iterator<Item = (u8, u8)> {
+    (0..GPIO_COUNT as u8)
+        .step_by(4)
+        .filter(move |&gpio| uart_function(gpio).0 == uart)
+        .map(|gpio| (gpio, gpio + 1))
+}
+
+/// Pins of one hardware UART
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UartPins {
+    pub tx: u8,
+    pub rx: u8,
+    /// (CTS, RTS) for hardware flow control; without them RTS/CTS is unavailable
+    pub flow: Option<(u8, u8)>,
+}
+
+/// Why a pin mapping was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinMapError {
+    /// The GPIO does not exist
+    NoSuchGpio(u8),
+    /// The GPIO's UART function is not `signal` of `uart`
+    WrongFunction {
+        gpio: u8,
+        uart: usize,
+        signal: UartSignal,
+    },
+    /// The board already uses the GPIO for something else
+    InUse(u8),
+}
+
+impl UartPins {
+    /// TX and RX only, without flow control
+    pub const fn pair(tx: u8, rx: u8) -> Self {
+        Self { tx, rx, flow: None }
+    }
+
+    /// The GPIOs with the signal each one has to carry
+    fn signals(&self) -> impl Iterator<Item = (u8, UartSignal)> {
+        let flow = self
+            .flow
+            .map(|(cts, rts)| [(cts, UartSignal::Cts), (rts, UartSignal::Rts)]);
+        [(self.tx, UartSignal::Tx), (self.rx, UartSignal::Rx)]
+            .into_iter()
+            .chain(flow.into_iter().flatten())
+    }
+
+    /// Bit mask of the GPIOs used (bit n for GPIOn)
+    pub fn mask(&self) -> u32 {
+        self.signals()
+            .filter(|&(gpio, _)| usize::from(gpio) < GPIO_COUNT)
+            .fold(0, |mask, (gpio, _)| mask | 1 << gpio)
+    }
+
+    /// Check that every pin carries its signal of `uart` and that none of
+    /// them is in `reserved` (a GPIO bit mask of pins the board uses)
+    pub fn validate(&self, uart: usize, reserved: u32) -> Result<(), PinMapError> {
+        for (gpio, signal) in self.signals() {
+            if usize::from(gpio) >= GPIO_COUNT {
+                return Err(PinMapError::NoSuchGpio(gpio));
+            }
+            if uart_function(gpio) != (uart, signal) {
+                return Err(PinMapError::WrongFunction { gpio, uart, signal });
+            }
+            if reserved & (1 << gpio) != 0 {
+                return Err(PinMapError::InUse(gpio));
+            }
+        }
+        Ok(())
+    }
+}
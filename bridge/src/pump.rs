@@ -0,0 +1,73 @@
+//! UART side of the data path (Core1 in the firmware)
+//!
+//! Moves data between a UART and its pair of queues through the queues'
+//! contiguous regions, so a UART that hands out chunks (DMA, a PIO FIFO, a
+//! pseudo-terminal) costs one copy per chunk.
+
+use crate::byte_ring::{Consumer, Producer};
+use crate::{ByteSink, ByteSource};
+
+/// Outcome of one pass over a direction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pumped {
+    /// Some data was moved
+    pub moved: bool,
+    /// The direction could not finish: the queue to the host is full (RX)
+    /// or the UART has no room for queued data (TX)
+    pub stalled: bool,
+}
+
+/// Read from the UART straight into the queue to the host, as much as it
+/// has room for.
+///
+/// Once the queue is full, `stalled` is set and the caller should stop
+/// taking data off the line, so RTS/CTS flow control pauses the target
+/// (without it, the UART overruns).
+pub fn uart_to_host<const N: usize>(
+    uart: &mut impl ByteSource,
+    producer: &mut Producer<'_, N>,
+) -> Pumped {
+    let mut pumped = Pumped::default();
+    loop {
+        let grant = producer.grant();
+        if grant.is_empty() {
+            pumped.stalled = true;
+            break;
+        }
+        let count = uart.read(grant);
+        if count == 0 {
+            break;
+        }
+        producer.commit(count);
+        pumped.moved = true;
+    }
+    pumped
+}
+
+/// Write the host's queued data to the UART, as much as it takes.
+///
+/// Never waits for TX room: with CTS flow control the target may hold the
+/// UART for as long as it likes. `stalled` tells the caller to come back
+/// once the UART has room. A chunk the UART fails to take is discarded.
+pub fn host_to_uart<const N: usize>(
+    consumer: &mut Consumer<'_, N>,
+    uart: &mut impl ByteSink,
+) -> Pumped {
+    let mut pumped = Pumped::default();
+    loop {
+        let data = consumer.read_grant();
+        if data.is_empty() {
+            break;
+        }
+        match uart.write(data) {
+            Ok(0) => {
+                pumped.stalled = true;
+                break;
+            }
+            Ok(count) => consumer.release(count),
+            Err(_) => consumer.release(data.len()),
+        }
+        pumped.moved = true;
+    }
+    pumped
+}
@@ -0,0 +1,624 @@
+//! Line-based configuration shell of the control CDC function
+//!
+//! The host types commands into a terminal on the control port; `Shell`
+//! echoes and edits the line, runs it against a `ShellTarget` and queues the
+//! reply for the IN endpoint. `set` only changes the settings in RAM; `save`
+//! writes them to flash and they take effect at the next `reboot`. `set
+//! baud` also switches the running channel right away, like the escape
+//! menu.
+//!
+//! The flash write is left to the target to finish later: until `poll`
+//! sees its outcome the shell shows no prompt and ignores what is typed.
+
+use crate::config::{CHANNELS, Config, LedMode, PinSelection, ShortString, USB_STRING_MAX};
+use crate::config_store::SaveError;
+use crate::pin_map::{PinMapError, UART_COUNT, UartPins, UartSignal};
+use crate::stats::LineErrors;
+use crate::uart_config::{DataBits, FlowControl, LineConfig, Parity, StopBits};
+use crate::{ByteSink, SinkError};
+use core::fmt::{self, Write};
+use core::ops::RangeInclusive;
+
+/// Longest command line
+pub const LINE_MAX: usize = 96;
+/// Reply bytes waiting for the IN endpoint; more is dropped
+pub const OUTPUT_SIZE: usize = 1024;
+
+const PROMPT: &str = "> ";
+
+const HELP: &str = "\
+status                                  settings in use and to be saved\r
+stats                                   line errors per channel\r
+set baud <channel> <rate>|default       baud rate now (format kept) and at power-on (8N1)\r
+set pins <uart> <tx> <rx> [<cts> <rts>] UART pins\r
+set pins <uart> default|none\r
+set flow <uart> none|rtscts|default     hardware flow control (needs CTS/RTS pins)\r
+set led activity|on|off\r
+set usb manufacturer|product|serial <text>|default\r
+save                                    write the settings to flash\r
+reboot                                  restart with the saved settings\r
+";
+
+/// The bridge as seen by the shell
+pub trait ShellTarget {
+    /// The settings `set` changes and `save` writes
+    fn config(&self) -> &Config;
+
+    /// Mutable access to `config`
+    fn config_mut(&mut self) -> &mut Config;
+
+    /// Line configuration `channel` runs with
+    fn line_config(&self, channel: usize) -> LineConfig;
+
+    /// Switch `channel`'s UART to its power-on baud rate in `config` now,
+    /// keeping the character format. It stays in effect until the host
+    /// sets a new line coding.
+    fn apply_baud(&mut self, channel: usize);
+
+    /// Baud rates `channel` can run at
+    fn baud_range(&self, channel: usize) -> RangeInclusive<u32>;
+
+    /// Pins hardware UART `uart` uses (`None`: not connected)
+    fn uart_pins(&self, uart: usize) -> Option<UartPins>;
+
+    /// GPIOs the board uses for other things (bit n for GPIOn)
+    fn reserved_gpios(&self) -> u32;
+
+    /// RX line error counters of `channel`
+    fn line_errors(&self, channel: usize) -> LineErrors;
+
+    /// UART→host bytes of `channel` lost because the IN endpoint failed
+    fn dropped(&self, channel: usize) -> u32;
+
+    /// Start writing `config` to flash
+    fn save(&mut self);
+
+    /// The outcome of the last `save` once the write has finished
+    fn save_result(&mut self) -> Option<Result<(), SaveError>>;
+
+    /// Restart the device once the reply has been sent
+    fn reboot(&mut self);
+}
+
+/// Text waiting to be sent to the host; what does not fit is dropped
+pub(crate) struct Output<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    end: usize,
+}
+
+impl<const N: usize> Output<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Write `args` followed by a line break
+    pub(crate) fn line(&mut self, args: fmt::Arguments) {
+        let _ = self.write_fmt(args);
+        let _ = self.write_str("\r\n");
+    }
+
+    /// Hand the waiting bytes to `sink` as long as it takes them, ending
+    /// the transfer once they are all out. Returns true if anything was
+    /// sent.
+    pub(crate) fn send(&mut self, sink: &mut impl ByteSink) -> Result<bool, SinkError> {
+        let mut sent = false;
+        while !self.is_empty() {
+            let count = sink.write(&self.buf[self.start..self.end])?;
+            if count == 0 {
+                break;
+            }
+            self.start += count;
+            sent = true;
+        }
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+            sink.end_transfer();
+        }
+        Ok(sent)
+    }
+}
+
+impl<const N: usize> Write for Output<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.end + s.len() > N {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        let count = s.len().min(N - self.end);
+        self.buf[self.end..self.end + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.end += count;
+        Ok(())
+    }
+}
+
+/// Why a command was not carried out
+enum CommandError {
+    /// Wrong or missing arguments; shows the usage of the command
+    Usage(&'static str),
+    Pins(PinMapError),
+    UsbStringTooLong,
+    /// The channel cannot run at that baud rate
+    Baud(RangeInclusive<u32>),
+    Unknown,
+}
+
+/// The shell's line editor and reply queue
+pub struct Shell {
+    line: [u8; LINE_MAX],
+    len: usize,
+    /// The last byte was a CR, so an LF right after it ends no line
+    after_cr: bool,
+    /// `set` changed something since the last `save`
+    unsaved: bool,
+    /// `save` is waiting for the target to write the flash
+    saving: bool,
+    output: Output<OUTPUT_SIZE>,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; LINE_MAX],
+            len: 0,
+            after_cr: false,
+            unsaved: false,
+            saving: false,
+            output: Output::new(),
+        }
+    }
+
+    /// True if reply bytes are waiting for `send`
+    pub fn has_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// Take bytes typed by the host: echo them, handle backspace and Ctrl-C,
+    /// and run each line as it is completed with CR or LF. Bytes arriving
+    /// while a `save` is in progress are dropped.
+    pub fn receive(&mut self, data: &[u8], target: &mut impl ShellTarget) {
+        for &byte in data {
+            if self.saving {
+                return;
+            }
+            let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    let _ = self.output.write_str("\r\n");
+                    self.run_line(target);
+                    self.len = 0;
+                    if !self.saving {
+                        let _ = self.output.write_str(PROMPT);
+                    }
+                }
+                // Backspace / DEL
+                0x08 | 0x7F if self.len > 0 => {
+                    self.len -= 1;
+                    let _ = self.output.write_str("\x08 \x08");
+                }
+                // Ctrl-C
+                0x03 => {
+                    self.len = 0;
+                    let _ = self.output.write_str("^C\r\n");
+                    let _ = self.output.write_str(PROMPT);
+                }
+                b' '..=b'~' if self.len < LINE_MAX => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    let _ = self.output.write_char(char::from(byte));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Report the outcome of a `save` once the target has finished it
+    pub fn poll(&mut self, target: &mut impl ShellTarget) {
+        if !self.saving {
+            return;
+        }
+        let Some(result) = target.save_result() else {
+            return;
+        };
+        self.saving = false;
+        match result {
+            Ok(()) => {
+                self.unsaved = false;
+                self.output.line(format_args!("saved"));
+            }
+            Err(SaveError::Verify) => self
+                .output
+                .line(format_args!("error: the flash did not take the settings")),
+        }
+        let _ = self.output.write_str(PROMPT);
+    }
+
+    /// Hand waiting reply bytes to `sink` as long as it takes them. Returns
+    /// true if anything was sent.
+    pub fn send(&mut self, sink: &mut impl ByteSink) -> Result<bool, SinkError> {
+        self.output.send(sink)
+    }
+
+    fn run_line(&mut self, target: &mut impl ShellTarget) {
+        let line = self.line;
+        // Only printable ASCII is stored
+        let line = core::str::from_utf8(&line[..self.len]).unwrap_or_default();
+        let (command, args) = next_word(line);
+        let result = match command {
+            "" => Ok(()),
+            "help" => {
+                let _ = self.output.write_str(HELP);
+                Ok(())
+            }
+            "status" => {
+                self.status(target);
+                Ok(())
+            }
+            "stats" => {
+                self.stats(target);
+                Ok(())
+            }
+            "set" => self.set(args, target),
+            "save" => {
+                target.save();
+                self.saving = true;
+                Ok(())
+            }
+            "reboot" => {
+                if self.unsaved {
+                    self.output
+                        .line(format_args!("rebooting; unsaved settings are discarded"));
+                } else {
+                    self.output.line(format_args!("rebooting"));
+                }
+                target.reboot();
+                Ok(())
+            }
+            _ => Err(CommandError::Unknown),
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(CommandError::Usage(usage)) => self.output.line(format_args!("usage: {usage}")),
+            Err(CommandError::Pins(error)) => {
+                self.output.line(format_args!("error: {}", PinError(error)))
+            }
+            Err(CommandError::UsbStringTooLong) => self.output.line(format_args!(
+                "error: USB strings are at most {USB_STRING_MAX} bytes"
+            )),
+            Err(CommandError::Baud(range)) => self.output.line(format_args!(
+                "error: the baud rate must be from {} to {}",
+                range.start(),
+                range.end()
+            )),
+            Err(CommandError::Unknown) => self
+                .output
+                .line(format_args!("unknown command {command:?}; type help")),
+        }
+    }
+
+    fn status(&mut self, target: &impl ShellTarget) {
+        let out = &mut self.output;
+        for channel in 0..CHANNELS {
+            let line_config = LineFormat(target.line_config(channel));
+            if channel < UART_COUNT {
+                let pins = match target.uart_pins(channel) {
+                    Some(pins) => Pins(pins),
+                    None => {
+                        out.line(format_args!(
+                            "channel {channel}: UART{channel} {line_config}, not connected"
+                        ));
+                        continue;
+                    }
+                };
+                out.line(format_args!(
+                    "channel {channel}: UART{channel} {line_config}, pins {pins}"
+                ));
+            } else {
+                out.line(format_args!("channel {channel}: PIO UART {line_config}"));
+            }
+        }
+
+        let config = target.config();
+        if self.unsaved {
+            out.line(format_args!("settings (not saved):"));
+        } else {
+            out.line(format_args!("settings:"));
+        }
+        let _ = out.write_str("  baud:");
+        for (channel, baud) in config.baud.iter().enumerate() {
+            let separator = if channel == 0 { " " } else { ", " };
+            match baud {
+                Some(baud) => {
+                    let _ = write!(out, "{separator}{baud}");
+                }
+                None => {
+                    let _ = write!(out, "{separator}default");
+                }
+            }
+        }
+        let _ = out.write_str("\r\n");
+        for (uart, selection) in config.uart_pins.iter().enumerate() {
+            match selection {
+                PinSelection::BoardDefault => {
+                    out.line(format_args!("  uart{uart} pins: default"));
+                }
+                PinSelection::Unconnected => out.line(format_args!("  uart{uart} pins: none")),
+                PinSelection::Pins(pins) => {
+                    out.line(format_args!("  uart{uart} pins: {}", Pins(*pins)));
+                }
+            }
+        }
+        for (uart, flow) in config.flow_control.iter().enumerate() {
+            let flow = match flow {
+                Some(FlowControl::None) => "none",
+                Some(FlowControl::RtsCts) => "rtscts",
+                None => "default",
+            };
+            out.line(format_args!("  uart{uart} flow: {flow}"));
+        }
+        let usb = &config.usb;
+        for (name, string) in [
+            ("manufacturer", &usb.manufacturer),
+            ("product", &usb.product),
+            ("serial", &usb.serial_number),
+        ] {
+            match string {
+                Some(string) => out.line(format_args!("  usb {name}: \"{}\"", string.as_str())),
+                None => out.line(format_args!("  usb {name}: default")),
+            }
+        }
+        let led = match config.led {
+            LedMode::Activity => "activity",
+            LedMode::On => "on",
+            LedMode::Off => "off",
+        };
+        out.line(format_args!("  led: {led}"));
+    }
+
+    fn stats(&mut self, target: &impl ShellTarget) {
+        for channel in 0..CHANNELS {
+            let errors = target.line_errors(channel);
+            self.output.line(format_args!(
+                "channel {channel}: framing {}, parity {}, breaks {}, overrun {}, dropped {}",
+                errors.framing,
+                errors.parity,
+                errors.breaks,
+                errors.overrun,
+                target.dropped(channel)
+            ));
+        }
+    }
+
+    fn set(&mut self, args: &str, target: &mut impl ShellTarget) -> Result<(), CommandError> {
+        const USAGE: &str = "set baud|pins|flow|led|usb ...";
+        let (setting, args) = next_word(args);
+        match setting {
+            "baud" => {
+                let channel = set_baud(args, target)?;
+                target.apply_baud(channel);
+                self.unsaved = true;
+                self.output.line(format_args!(
+                    "ok; channel {channel} now runs at {}; save to keep it",
+                    LineFormat(target.line_config(channel))
+                ));
+                return Ok(());
+            }
+            "pins" => set_pins(args, target)?,
+            "flow" => set_flow(args, target.config_mut())?,
+            "led" => set_led(args, target.config_mut())?,
+            "usb" => set_usb(args, target.config_mut())?,
+            _ => return Err(CommandError::Usage(USAGE)),
+        }
+        self.unsaved = true;
+        self.output
+            .line(format_args!("ok; save and reboot to apply"));
+        Ok(())
+    }
+}
+
+/// The first word of `text` and what follows it, without leading spaces
+fn next_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(' ').unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    (word, rest.trim_start())
+}
+
+/// Every word of `text`, or `None` if there are not exactly `N`
+fn words<const N: usize>(text: &str) -> Option<[&str; N]> {
+    let mut words = text.split_ascii_whitespace();
+    let mut result = [""; N];
+    for slot in &mut result {
+        *slot = words.next()?;
+    }
+    words.next().is_none().then_some(result)
+}
+
+/// Returns the channel whose baud rate changed
+fn set_baud(args: &str, target: &mut impl ShellTarget) -> Result<usize, CommandError> {
+    const USAGE: &str = "set baud <channel> <rate>|default";
+    let [channel, rate] = words(args).ok_or(CommandError::Usage(USAGE))?;
+    let channel = channel
+        .parse::<usize>()
+        .ok()
+        .filter(|&channel| channel < CHANNELS)
+        .ok_or(CommandError::Usage(USAGE))?;
+    let baud = match rate {
+        "default" => None,
+        rate => {
+            let rate = rate
+                .parse::<u32>()
+                .ok()
+                .filter(|&rate| rate > 0)
+                .ok_or(CommandError::Usage(USAGE))?;
+            let range = target.baud_range(channel);
+            if !range.contains(&rate) {
+                return Err(CommandError::Baud(range));
+            }
+            Some(rate)
+        }
+    };
+    target.config_mut().baud[channel] = baud;
+    Ok(channel)
+}
+
+fn set_pins(args: &str, target: &mut impl ShellTarget) -> Result<(), CommandError> {
+    const USAGE: &str = "set pins <uart> <tx> <rx> [<cts> <rts>]|default|none";
+    let (uart, args) = next_word(args);
+    let uart = uart
+        .parse::<usize>()
+        .ok()
+        .filter(|&uart| uart < UART_COUNT)
+        .ok_or(CommandError::Usage(USAGE))?;
+    let gpio = |word: &str| word.parse::<u8>().map_err(|_| CommandError::Usage(USAGE));
+
+    let selection = if let Some([word]) = words(args) {
+        match word {
+            "default" => PinSelection::BoardDefault,
+            "none" => PinSelection::Unconnected,
+            _ => return Err(CommandError::Usage(USAGE)),
+        }
+    } else if let Some([tx, rx]) = words(args) {
+        PinSelection::Pins(UartPins::pair(gpio(tx)?, gpio(rx)?))
+    } else if let Some([tx, rx, cts, rts]) = words(args) {
+        PinSelection::Pins(UartPins {
+            tx: gpio(tx)?,
+            rx: gpio(rx)?,
+            flow: Some((gpio(cts)?, gpio(rts)?)),
+        })
+    } else {
+        return Err(CommandError::Usage(USAGE));
+    };
+
+    if let PinSelection::Pins(pins) = selection {
+        pins.validate(uart, target.reserved_gpios())
+            .map_err(CommandError::Pins)?;
+    }
+    target.config_mut().uart_pins[uart] = selection;
+    Ok(())
+}
+
+fn set_flow(args: &str, config: &mut Config) -> Result<(), CommandError> {
+    const USAGE: &str = "set flow <uart> none|rtscts|default";
+    let [uart, mode] = words(args).ok_or(CommandError::Usage(USAGE))?;
+    let uart = uart
+        .parse::<usize>()
+        .ok()
+        .filter(|&uart| uart < UART_COUNT)
+        .ok_or(CommandError::Usage(USAGE))?;
+    config.flow_control[uart] = match mode {
+        "none" => Some(FlowControl::None),
+        "rtscts" => Some(FlowControl::RtsCts),
+        "default" => None,
+        _ => return Err(CommandError::Usage(USAGE)),
+    };
+    Ok(())
+}
+
+fn set_led(args: &str, config: &mut Config) -> Result<(), CommandError> {
+    const USAGE: &str = "set led activity|on|off";
+    config.led = match words(args).ok_or(CommandError::Usage(USAGE))? {
+        ["activity"] => LedMode::Activity,
+        ["on"] => LedMode::On,
+        ["off"] => LedMode::Off,
+        _ => return Err(CommandError::Usage(USAGE)),
+    };
+    Ok(())
+}
+
+fn set_usb(args: &str, config: &mut Config) -> Result<(), CommandError> {
+    const USAGE: &str = "set usb manufacturer|product|serial <text>|default";
+    let (name, text) = next_word(args);
+    let text = text.trim_end();
+    let string = match name {
+        "manufacturer" => &mut config.usb.manufacturer,
+        "product" => &mut config.usb.product,
+        "serial" => &mut config.usb.serial_number,
+        _ => return Err(CommandError::Usage(USAGE)),
+    };
+    *string = match text {
+        "" => return Err(CommandError::Usage(USAGE)),
+        "default" => None,
+        text => Some(ShortString::new(text).ok_or(CommandError::UsbStringTooLong)?),
+    };
+    Ok(())
+}
+
+/// `115200 8N1`
+pub(crate) struct LineFormat(pub(crate) LineConfig);
+
+impl fmt::Display for LineFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = &self.0;
+        let data_bits = match config.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match config.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        let stop_bits = match config.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {data_bits}{parity}{stop_bits}", config.baud)
+    }
+}
+
+/// `0/1` or `0/1, cts/rts 2/3`
+struct Pins(UartPins);
+
+impl fmt::Display for Pins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.0.tx, self.0.rx)?;
+        if let Some((cts, rts)) = self.0.flow {
+            write!(f, ", cts/rts {cts}/{rts}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Why `set pins` rejected the pins, for the user
+struct PinError(PinMapError);
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            PinMapError::NoSuchGpio(gpio) => write!(f, "there is no GPIO{gpio}"),
+            PinMapError::WrongFunction { gpio, uart, signal } => {
+                let signal = match signal {
+                    UartSignal::Tx => "TX",
+                    UartSignal::Rx => "RX",
+                    UartSignal::Cts => "CTS",
+                    UartSignal::Rts => "RTS",
+                };
+                write!(f, "GPIO{gpio} cannot be UART{uart} {signal}")
+            }
+            PinMapError::InUse(gpio) => write!(f, "GPIO{gpio} is used by the board"),
+        }
+    }
+}
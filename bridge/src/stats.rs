@@ -0,0 +1,47 @@
+//! UART line error counters and their SERIAL_STATE events
+
+use crate::cdc::{
+    SERIAL_STATE_BREAK, SERIAL_STATE_FRAMING, SERIAL_STATE_OVERRUN, SERIAL_STATE_PARITY,
+};
+
+/// Snapshot of the RX line error counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LineErrors {
+    /// Characters with a missing stop bit (usually a baud rate mismatch)
+    pub framing: u32,
+    /// Characters with a parity mismatch
+    pub parity: u32,
+    /// Break conditions (RX held low for longer than a character)
+    pub breaks: u32,
+    /// Characters lost because the hardware RX FIFO, or the DMA ring
+    /// behind it, was full
+    pub overrun: u32,
+}
+
+impl LineErrors {
+    /// No errors counted
+    pub const NONE: Self = Self {
+        framing: 0,
+        parity: 0,
+        breaks: 0,
+        overrun: 0,
+    };
+
+    /// SERIAL_STATE event bits for every counter that differs from `previous`
+    pub fn serial_state_events(&self, previous: &Self) -> u16 {
+        let mut events = 0;
+        if self.breaks != previous.breaks {
+            events |= SERIAL_STATE_BREAK;
+        }
+        if self.framing != previous.framing {
+            events |= SERIAL_STATE_FRAMING;
+        }
+        if self.parity != previous.parity {
+            events |= SERIAL_STATE_PARITY;
+        }
+        if self.overrun != previous.overrun {
+            events |= SERIAL_STATE_OVERRUN;
+        }
+        events
+    }
+}
@@ -0,0 +1,177 @@
+//! UART line configuration shared by Core0 (USB) and Core1 (UART)
+//!
+//! Core0 translates the CDC line coding requested by the host into a
+//! `LineConfig`, and Core1 programs it into the UART registers.
+
+use crate::cdc::LineCoding;
+
+/// Number of data bits per character
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+/// Parity mode (Mark/Space use PL011 stick parity)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+/// Number of stop bits (1.5 is not supported by the PL011 and maps to Two)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// UART hardware flow control mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    /// No flow control; CTS/RTS pins are left unused
+    None,
+    /// PL011 hardware RTS/CTS on the UART's CTS/RTS pins
+    RtsCts,
+}
+
+/// Complete UART line configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineConfig {
+    /// 8N1 at `baud` (the power-on configuration)
+    pub const fn eight_n_one(baud: u32) -> Self {
+        Self {
+            baud,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+
+    /// Convert a CDC line coding into a UART configuration.
+    ///
+    /// Returns `None` for settings the UART cannot represent (a zero baud
+    /// rate, a data width outside 5..=8 or an unknown parity/stop code).
+    pub fn from_line_coding(coding: &LineCoding) -> Option<Self> {
+        let baud = coding.data_rate();
+        if baud == 0 {
+            return None;
+        }
+
+        let data_bits = match coding.data_bits() {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            _ => return None,
+        };
+
+        let parity = match coding.parity() {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => return None,
+        };
+
+        let stop_bits = match coding.stop_bits() {
+            0 => StopBits::One,
+            1 | 2 => StopBits::Two,
+            _ => return None,
+        };
+
+        Some(Self {
+            baud,
+            data_bits,
+            parity,
+            stop_bits,
+        })
+    }
+
+    /// The CDC line coding a host reads back for this configuration
+    pub fn to_line_coding(&self) -> LineCoding {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 2,
+        };
+        LineCoding::new(self.baud, stop_bits, parity, data_bits)
+    }
+
+    /// Pack the character format (everything but the baud rate) into one byte
+    /// so it can be handed to Core1 through an atomic.
+    pub fn format_bits(&self) -> u8 {
+        let data = match self.data_bits {
+            DataBits::Five => 0,
+            DataBits::Six => 1,
+            DataBits::Seven => 2,
+            DataBits::Eight => 3,
+        };
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1,
+        };
+        data | (parity << 2) | (stop << 5)
+    }
+
+    /// Inverse of `format_bits`
+    pub fn from_parts(baud: u32, format: u8) -> Self {
+        let data_bits = match format & 0x3 {
+            0 => DataBits::Five,
+            1 => DataBits::Six,
+            2 => DataBits::Seven,
+            _ => DataBits::Eight,
+        };
+        let parity = match (format >> 2) & 0x7 {
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => Parity::None,
+        };
+        let stop_bits = if (format >> 5) & 0x1 != 0 {
+            StopBits::Two
+        } else {
+            StopBits::One
+        };
+
+        Self {
+            baud,
+            data_bits,
+            parity,
+            stop_bits,
+        }
+    }
+}
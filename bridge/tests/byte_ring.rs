@@ -0,0 +1,144 @@
+//! Contiguous grants of the byte ring at the wrap point and at the full and
+//! empty boundaries
+
+use picoterm_bridge::byte_ring::{ByteRing, Consumer, Producer};
+
+const SIZE: usize = 16;
+
+fn ring() -> (Producer<'static, SIZE>, Consumer<'static, SIZE>) {
+    Box::leak(Box::new(ByteRing::new())).split()
+}
+
+/// Read and release everything queued, grant by grant
+fn drain(consumer: &mut Consumer<'_, SIZE>) -> Vec<u8> {
+    let mut data = Vec::new();
+    while !consumer.is_empty() {
+        let grant = consumer.read_grant();
+        data.extend_from_slice(grant);
+        let count = grant.len();
+        consumer.release(count);
+    }
+    data
+}
+
+#[test]
+fn empty_ring() {
+    let (mut producer, consumer) = ring();
+    assert_eq!(producer.free(), SIZE);
+    assert_eq!(producer.grant().len(), SIZE);
+    assert!(consumer.is_empty());
+    assert_eq!(consumer.len(), 0);
+    assert!(consumer.read_grant().is_empty());
+}
+
+#[test]
+fn full_ring() {
+    let (mut producer, mut consumer) = ring();
+    let data: Vec<u8> = (0..SIZE as u8 + 4).collect();
+    assert_eq!(producer.push_slice(&data), SIZE);
+    assert_eq!(producer.free(), 0);
+    assert!(producer.grant().is_empty());
+    assert_eq!(producer.push_slice(&data), 0);
+    assert_eq!(consumer.len(), SIZE);
+    assert_eq!(consumer.read_grant(), &data[..SIZE]);
+
+    // Releasing one byte frees exactly one byte
+    consumer.release(1);
+    assert_eq!(producer.free(), 1);
+    assert_eq!(producer.grant().len(), 1);
+    assert_eq!(producer.push_slice(&[0xAA, 0xBB]), 1);
+    let mut expected = data[1..SIZE].to_vec();
+    expected.push(0xAA);
+    assert_eq!(drain(&mut consumer), expected);
+    assert_eq!(producer.free(), SIZE);
+}
+
+#[test]
+fn grants_stop_at_the_end_of_the_buffer() {
+    let (mut producer, mut consumer) = ring();
+    producer.push_slice(&[0; 12]);
+    consumer.release(12);
+
+    // 16 bytes free, but only 4 before the end of the buffer
+    assert_eq!(producer.free(), SIZE);
+    assert_eq!(producer.grant().len(), 4);
+    producer.grant()[..4].copy_from_slice(b"abcd");
+    producer.commit(4);
+    assert_eq!(producer.grant().len(), SIZE - 4);
+
+    producer.grant()[..3].copy_from_slice(b"efg");
+    producer.commit(3);
+    assert_eq!(consumer.len(), 7);
+    assert_eq!(consumer.read_grant(), b"abcd");
+    consumer.release(4);
+    assert_eq!(consumer.read_grant(), b"efg");
+}
+
+#[test]
+fn push_slice_wraps_around() {
+    let (mut producer, mut consumer) = ring();
+    producer.push_slice(&[0; 10]);
+    consumer.release(10);
+
+    let data: Vec<u8> = (1..=12).collect();
+    assert_eq!(producer.push_slice(&data), 12);
+    assert_eq!(producer.free(), SIZE - 12);
+    // The first grant ends at the buffer's end, the second starts over
+    assert_eq!(consumer.read_grant(), &data[..6]);
+    assert_eq!(drain(&mut consumer), data);
+}
+
+#[test]
+fn read_grants_cover_the_wrapped_part() {
+    let (mut producer, mut consumer) = ring();
+    assert_eq!(consumer.read_grants(), (&[][..], &[][..]));
+    producer.push_slice(&[0; 10]);
+    consumer.release(10);
+
+    producer.push_slice(b"abcd");
+    assert_eq!(consumer.read_grants(), (&b"abcd"[..], &[][..]));
+    producer.push_slice(b"efghij");
+    assert_eq!(consumer.read_grants(), (&b"abcdef"[..], &b"ghij"[..]));
+    consumer.release(6);
+    assert_eq!(consumer.read_grants(), (&b"ghij"[..], &[][..]));
+}
+
+#[test]
+fn partial_release_keeps_the_rest() {
+    let (mut producer, mut consumer) = ring();
+    producer.push_slice(b"hello");
+    consumer.release(2);
+    assert_eq!(consumer.read_grant(), b"llo");
+    assert_eq!(producer.free(), SIZE - 3);
+    assert_eq!(consumer.find(0, b'o'), Some(2));
+    assert_eq!(consumer.find(3, b'o'), None);
+}
+
+#[test]
+fn free_accounting_over_many_laps() {
+    let (mut producer, mut consumer) = ring();
+    let mut sent = Vec::new();
+    let mut received = Vec::new();
+    let mut next = 0u8;
+    for round in 0..200 {
+        // Uneven chunk sizes so every offset becomes a wrap point
+        let chunk: Vec<u8> = (0..round % 7 + 1)
+            .map(|_| {
+                next = next.wrapping_add(1);
+                next
+            })
+            .collect();
+        let written = producer.push_slice(&chunk);
+        sent.extend_from_slice(&chunk[..written]);
+        assert_eq!(producer.free() + consumer.len(), SIZE);
+
+        let grant = consumer.read_grant();
+        let count = grant.len().min(round % 5);
+        received.extend_from_slice(&grant[..count]);
+        consumer.release(count);
+        assert_eq!(producer.free() + consumer.len(), SIZE);
+    }
+    received.extend(drain(&mut consumer));
+    assert_eq!(received, sent);
+    assert_eq!(producer.free(), SIZE);
+}
@@ -0,0 +1,296 @@
+//! UART pin mapping against the GPIO function mux, the byte layout of the
+//! persisted settings and their flash store
+
+use picoterm_bridge::config::{
+    Config, ConfigError, LedMode, PinSelection, ShortString, USB_STRING_MAX, UsbStrings, VERSION,
+};
+use picoterm_bridge::config_store::{
+    ConfigFlash, ConfigStore, LoadError, RECORD_SIZE, SECTOR_SIZE, STORE_SIZE, crc32,
+};
+use picoterm_bridge::pin_map::{PinMapError, UartPins, UartSignal, tx_rx_pairs, uart_function};
+use picoterm_bridge::uart_config::FlowControl;
+
+#[test]
+fn mux_table_matches_the_datasheet() {
+    assert_eq!(uart_function(0), (0, UartSignal::Tx));
+    assert_eq!(uart_function(5), (1, UartSignal::Rx));
+    assert_eq!(uart_function(11), (1, UartSignal::Rts));
+    assert_eq!(uart_function(14), (0, UartSignal::Cts));
+    assert_eq!(uart_function(29), (0, UartSignal::Rx));
+}
+
+#[test]
+fn tx_rx_pairs_per_uart() {
+    let uart0: Vec<_> = tx_rx_pairs(0).collect();
+    let uart1: Vec<_> = tx_rx_pairs(1).collect();
+    assert_eq!(uart0, [(0, 1), (12, 13), (16, 17), (28, 29)]);
+    assert_eq!(uart1, [(4, 5), (8, 9), (20, 21), (24, 25)]);
+}
+
+#[test]
+fn every_listed_pair_validates() {
+    for uart in 0..2 {
+        for (tx, rx) in tx_rx_pairs(uart) {
+            assert_eq!(UartPins::pair(tx, rx).validate(uart, 0), Ok(()));
+        }
+    }
+}
+
+#[test]
+fn pins_of_the_other_uart_are_rejected() {
+    assert_eq!(
+        UartPins::pair(4, 5).validate(0, 0),
+        Err(PinMapError::WrongFunction {
+            gpio: 4,
+            uart: 0,
+            signal: UartSignal::Tx
+        })
+    );
+}
+
+#[test]
+fn swapped_tx_rx_is_rejected() {
+    assert_eq!(
+        UartPins::pair(13, 12).validate(0, 0),
+        Err(PinMapError::WrongFunction {
+            gpio: 13,
+            uart: 0,
+            signal: UartSignal::Tx
+        })
+    );
+}
+
+/// The RP2350's UART_AUX pins (TX/RX on CTS/RTS) are not routed
+#[test]
+fn uart_aux_pins_are_rejected() {
+    assert_eq!(
+        UartPins::pair(2, 3).validate(0, 0),
+        Err(PinMapError::WrongFunction {
+            gpio: 2,
+            uart: 0,
+            signal: UartSignal::Tx
+        })
+    );
+}
+
+#[test]
+fn flow_pins_are_checked() {
+    let pins = UartPins {
+        tx: 16,
+        rx: 17,
+        flow: Some((18, 19)),
+    };
+    assert_eq!(pins.validate(0, 0), Ok(()));
+    assert_eq!(pins.mask(), 0b1111 << 16);
+
+    let swapped = UartPins {
+        flow: Some((19, 18)),
+        ..pins
+    };
+    assert!(matches!(
+        swapped.validate(0, 0),
+        Err(PinMapError::WrongFunction { gpio: 19, .. })
+    ));
+}
+
+#[test]
+fn reserved_and_missing_gpios_are_rejected() {
+    assert_eq!(
+        UartPins::pair(28, 29).validate(0, 1 << 29),
+        Err(PinMapError::InUse(29))
+    );
+    let pins = UartPins {
+        tx: 28,
+        rx: 29,
+        flow: Some((30, 31)),
+    };
+    assert_eq!(pins.validate(0, 0), Err(PinMapError::NoSuchGpio(30)));
+}
+
+#[test]
+fn config_round_trips() {
+    let config = Config {
+        uart_pins: [
+            PinSelection::Pins(UartPins {
+                tx: 12,
+                rx: 13,
+                flow: Some((14, 15)),
+            }),
+            PinSelection::Unconnected,
+        ],
+        flow_control: [Some(FlowControl::RtsCts), Some(FlowControl::None)],
+        baud: [Some(115_200), None, Some(9600), Some(3_000_000)],
+        usb: UsbStrings {
+            manufacturer: ShortString::new("Example"),
+            product: None,
+            serial_number: ShortString::new("bench-2"),
+        },
+        led: LedMode::Off,
+    };
+    assert_eq!(Config::decode(&config.encode()), Ok(config));
+
+    let config = Config {
+        uart_pins: [
+            PinSelection::BoardDefault,
+            PinSelection::Pins(UartPins::pair(8, 9)),
+        ],
+        flow_control: [None, Some(FlowControl::None)],
+        led: LedMode::On,
+        ..Config::DEFAULT
+    };
+    assert_eq!(Config::decode(&config.encode()), Ok(config));
+    assert_eq!(Config::default(), Config::DEFAULT);
+}
+
+#[test]
+fn usb_strings_are_limited() {
+    let longest = "x".repeat(USB_STRING_MAX);
+    assert_eq!(ShortString::new(&longest).unwrap().as_str(), longest);
+    assert_eq!(ShortString::new(&format!("{longest}x")), None);
+}
+
+#[test]
+fn short_and_unknown_data_are_rejected() {
+    let mut bytes = Config::DEFAULT.encode();
+    assert_eq!(
+        Config::decode(&bytes[..bytes.len() - 1]),
+        Err(ConfigError::Truncated)
+    );
+
+    bytes[0] = 0x7F;
+    assert_eq!(Config::decode(&bytes), Err(ConfigError::Invalid));
+
+    let mut bytes = Config::DEFAULT.encode();
+    let last = bytes.len() - 1;
+    bytes[last] = 2;
+    assert_eq!(Config::decode(&bytes), Err(ConfigError::Invalid));
+}
+
+/// Flash that erases to 0xFF and programs by clearing bits, counting erases
+/// per sector
+struct MemFlash {
+    bytes: Vec<u8>,
+    erases: [u32; 2],
+}
+
+impl MemFlash {
+    fn erased() -> Self {
+        Self {
+            bytes: vec![0xFF; STORE_SIZE as usize],
+            erases: [0; 2],
+        }
+    }
+}
+
+impl ConfigFlash for MemFlash {
+    fn read(&self, offset: u32, buf: &mut [u8]) {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.bytes[offset..offset + buf.len()]);
+    }
+
+    fn erase(&mut self, offset: u32) {
+        assert_eq!(offset % SECTOR_SIZE, 0);
+        let offset = offset as usize;
+        self.bytes[offset..offset + SECTOR_SIZE as usize].fill(0xFF);
+        self.erases[offset / SECTOR_SIZE as usize] += 1;
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8; RECORD_SIZE]) {
+        let offset = offset as usize;
+        for (byte, &new) in self.bytes[offset..].iter_mut().zip(data) {
+            *byte &= new;
+        }
+    }
+}
+
+fn config_with_baud(baud: u32) -> Config {
+    let mut config = Config::DEFAULT;
+    config.baud[0] = Some(baud);
+    config
+}
+
+#[test]
+fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn erased_flash_has_no_config() {
+    let store = ConfigStore::new(MemFlash::erased());
+    assert_eq!(store.load(), Err(LoadError::Empty));
+    assert_eq!(store.sequence(), None);
+}
+
+#[test]
+fn saved_config_survives_a_rescan() {
+    let mut flash = MemFlash::erased();
+    let mut store = ConfigStore::new(&mut flash);
+    store.save(&config_with_baud(115_200)).unwrap();
+    store.save(&config_with_baud(57_600)).unwrap();
+    assert_eq!(store.load(), Ok(config_with_baud(57_600)));
+
+    let store = ConfigStore::new(&mut flash);
+    assert_eq!(store.load(), Ok(config_with_baud(57_600)));
+    assert_eq!(store.sequence(), Some(2));
+}
+
+#[test]
+fn writes_alternate_between_the_sectors() {
+    let records_per_sector = SECTOR_SIZE / RECORD_SIZE as u32;
+    let mut flash = MemFlash::erased();
+    let mut store = ConfigStore::new(&mut flash);
+    for baud in 1..=4 * records_per_sector {
+        store.save(&config_with_baud(baud)).unwrap();
+    }
+    assert_eq!(store.load(), Ok(config_with_baud(4 * records_per_sector)));
+    // One erase per sector's worth of saves, shared evenly
+    assert_eq!(store.flash().erases, [2, 2]);
+
+    let store = ConfigStore::new(&mut flash);
+    assert_eq!(store.load(), Ok(config_with_baud(4 * records_per_sector)));
+}
+
+#[test]
+fn torn_write_falls_back_to_the_previous_record() {
+    let mut flash = MemFlash::erased();
+    let mut store = ConfigStore::new(&mut flash);
+    store.save(&config_with_baud(115_200)).unwrap();
+
+    // Power lost a quarter of the way through programming the next record
+    let mut complete = flash.bytes.clone();
+    let mut store = ConfigStore::new(&mut flash);
+    store.save(&config_with_baud(9600)).unwrap();
+    let second = RECORD_SIZE..RECORD_SIZE + RECORD_SIZE / 4;
+    complete[second.clone()].copy_from_slice(&flash.bytes[second]);
+    flash.bytes = complete;
+
+    let mut store = ConfigStore::new(&mut flash);
+    assert_eq!(store.load(), Ok(config_with_baud(115_200)));
+
+    // The next save skips the damaged slot
+    store.save(&config_with_baud(230_400)).unwrap();
+    let store = ConfigStore::new(&mut flash);
+    assert_eq!(store.load(), Ok(config_with_baud(230_400)));
+}
+
+#[test]
+fn record_of_another_version_is_reported() {
+    let mut flash = MemFlash::erased();
+    ConfigStore::new(&mut flash).save(&Config::DEFAULT).unwrap();
+
+    // Rewrite the record as a later firmware would, with a valid CRC
+    let mut record = [0; RECORD_SIZE];
+    flash.read(0, &mut record);
+    record[4] = VERSION + 1;
+    let len = 12 + Config::ENCODED_LEN;
+    let crc = crc32(&record[..len]);
+    record[len..len + 4].copy_from_slice(&crc.to_le_bytes());
+    flash.erase(0);
+    flash.program(0, &record);
+
+    let store = ConfigStore::new(&mut flash);
+    assert_eq!(
+        store.load(),
+        Err(LoadError::UnsupportedVersion(VERSION + 1))
+    );
+}
@@ -0,0 +1,301 @@
+//! Escape sequences in a data port's host→UART stream and the menu they
+//! open, driven against a channel that records what the menu asks of it
+
+use picoterm_bridge::byte_ring::ByteRing;
+use picoterm_bridge::escape::{Escape, EscapeKey, MenuTarget};
+use picoterm_bridge::stats::LineErrors;
+use picoterm_bridge::uart_config::LineConfig;
+use picoterm_bridge::{ByteSink, ByteSource, SinkError};
+
+const CTRL_BRACKET: u8 = 0x1D;
+
+#[derive(Default)]
+struct Channel {
+    baud: Option<u32>,
+    breaks: u32,
+    resets: u32,
+    has_reset_line: bool,
+}
+
+impl MenuTarget for Channel {
+    fn line_config(&self) -> LineConfig {
+        LineConfig::eight_n_one(self.baud.unwrap_or(115_200))
+    }
+
+    fn set_baud(&mut self, baud: u32) {
+        self.baud = Some(baud);
+    }
+
+    fn send_break(&mut self) {
+        self.breaks += 1;
+    }
+
+    fn reset_target(&mut self) -> bool {
+        self.resets += 1;
+        self.has_reset_line
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        LineErrors {
+            overrun: 2,
+            ..LineErrors::NONE
+        }
+    }
+
+    fn dropped(&self) -> u32 {
+        5
+    }
+}
+
+/// An IN endpoint that takes everything
+struct Endpoint(Vec<u8>);
+
+impl ByteSink for Endpoint {
+    fn write(&mut self, data: &[u8]) -> Result<usize, SinkError> {
+        self.0.extend_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+/// Run `input` through `escape`; returns what goes to the UART and the
+/// menu's text
+fn type_in(escape: &mut Escape, channel: &mut Channel, input: &[u8]) -> (Vec<u8>, String) {
+    let mut out = vec![0; input.len() + 1];
+    let count = escape.filter(input, &mut out, channel);
+    out.truncate(count);
+    let mut endpoint = Endpoint(Vec::new());
+    escape.send(&mut endpoint).unwrap();
+    (out, String::from_utf8(endpoint.0).unwrap())
+}
+
+#[test]
+fn data_without_escapes_passes_through() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"ls ~/src\r~x ~");
+    assert_eq!(uart, b"ls ~/src\r~x ~");
+    assert_eq!(menu, "");
+    assert!(!escape.is_active());
+}
+
+#[test]
+fn disabled_escape_passes_everything() {
+    let mut escape = Escape::new(None);
+    let mut channel = Channel::default();
+    let (uart, _) = type_in(&mut escape, &mut channel, b"~.~~");
+    assert_eq!(uart, b"~.~~");
+}
+
+#[test]
+fn tilde_dot_opens_the_menu() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"ok\r~.");
+    assert_eq!(uart, b"ok\r");
+    assert!(menu.starts_with("\r\n[picoterm] 115200 8N1\r\n[picoterm] b: baud rate"));
+    assert!(escape.is_active());
+
+    // Keys go to the menu, not the UART
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"s");
+    assert_eq!(uart, b"");
+    assert_eq!(
+        menu,
+        "[picoterm] framing 0, parity 0, breaks 0, overrun 2, dropped 5\r\n"
+    );
+    assert!(!escape.is_active());
+
+    // Back at the start of a line
+    let (uart, _) = type_in(&mut escape, &mut channel, b"~.k");
+    assert_eq!(uart, b"");
+    assert_eq!(channel.breaks, 1);
+}
+
+#[test]
+fn doubled_tilde_sends_one() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"~~.");
+    assert_eq!(uart, b"~.");
+    assert_eq!(menu, "");
+}
+
+#[test]
+fn held_tilde_spans_packets() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let (uart, _) = type_in(&mut escape, &mut channel, b"\n~");
+    assert_eq!(uart, b"\n");
+    let (uart, _) = type_in(&mut escape, &mut channel, b"x");
+    assert_eq!(uart, b"~x");
+
+    let (uart, _) = type_in(&mut escape, &mut channel, b"\r~");
+    assert_eq!(uart, b"\r");
+    let (uart, _) = type_in(&mut escape, &mut channel, b".");
+    assert_eq!(uart, b"");
+    assert!(escape.is_active());
+}
+
+#[test]
+fn control_key_opens_the_menu_anywhere() {
+    let mut escape = Escape::new(Some(EscapeKey::Control(CTRL_BRACKET)));
+    let mut channel = Channel::default();
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"abc\x1d");
+    assert_eq!(uart, b"abc");
+    assert_eq!(menu, "");
+    assert!(!escape.is_active());
+
+    // The next byte opens the menu
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"?");
+    assert_eq!(uart, b"");
+    assert!(menu.contains("[picoterm] 115200 8N1"));
+    assert!(escape.is_active());
+
+    // Pressed again in the menu, the key itself goes to the UART
+    let (uart, _) = type_in(&mut escape, &mut channel, &[CTRL_BRACKET, b'~']);
+    assert_eq!(uart, [CTRL_BRACKET, b'~']);
+    assert!(!escape.is_active());
+}
+
+#[test]
+fn doubled_control_key_sends_one() {
+    let mut escape = Escape::new(Some(EscapeKey::Control(CTRL_BRACKET)));
+    let mut channel = Channel::default();
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"a\x1d\x1db");
+    assert_eq!(uart, [b'a', CTRL_BRACKET, b'b']);
+    assert_eq!(menu, "");
+
+    // Held across packets too
+    let (uart, _) = type_in(&mut escape, &mut channel, &[CTRL_BRACKET]);
+    assert_eq!(uart, b"");
+    let (uart, menu) = type_in(&mut escape, &mut channel, &[CTRL_BRACKET]);
+    assert_eq!(uart, [CTRL_BRACKET]);
+    assert_eq!(menu, "");
+    assert!(!escape.is_active());
+}
+
+#[test]
+fn menu_key_after_the_control_key_runs_at_once() {
+    let mut escape = Escape::new(Some(EscapeKey::Control(CTRL_BRACKET)));
+    let mut channel = Channel::default();
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"\x1dk");
+    assert_eq!(uart, b"");
+    assert!(menu.ends_with("[picoterm] break sent\r\n"));
+    assert_eq!(channel.breaks, 1);
+    assert!(!escape.is_active());
+}
+
+#[test]
+fn lf_after_the_cr_ending_a_menu_entry_is_dropped() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let (uart, _) = type_in(&mut escape, &mut channel, b"~.\r\nls\r\n");
+    assert_eq!(uart, b"ls\r\n");
+    assert!(!escape.is_active());
+
+    // Also when the LF comes in the next packet, after a baud rate
+    let (uart, _) = type_in(&mut escape, &mut channel, b"~.b9600\r");
+    assert_eq!(uart, b"");
+    assert_eq!(channel.baud, Some(9600));
+    let (uart, _) = type_in(&mut escape, &mut channel, b"\n\n");
+    assert_eq!(uart, b"\n");
+}
+
+#[test]
+fn baud_rate_is_typed_in_the_menu() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    type_in(&mut escape, &mut channel, b"~.");
+    let (uart, menu) = type_in(&mut escape, &mut channel, b"b96x01\x7f0\r");
+    assert_eq!(uart, b"");
+    assert_eq!(
+        menu,
+        "[picoterm] baud rate: 9601\x08 \x080\r\n[picoterm] 9600 8N1\r\n"
+    );
+    assert_eq!(channel.baud, Some(9600));
+
+    type_in(&mut escape, &mut channel, b"~.b");
+    let (_, menu) = type_in(&mut escape, &mut channel, b"\x1b");
+    assert!(menu.ends_with("baud rate unchanged\r\n"));
+    assert_eq!(channel.baud, Some(9600));
+    assert!(!escape.is_active());
+}
+
+#[test]
+fn reset_needs_a_reset_line() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let (_, menu) = type_in(&mut escape, &mut channel, b"~.r");
+    assert!(menu.ends_with("[picoterm] no reset line on this channel\r\n"));
+
+    channel.has_reset_line = true;
+    let (_, menu) = type_in(&mut escape, &mut channel, b"~.r");
+    assert!(menu.ends_with("[picoterm] target reset\r\n"));
+    assert_eq!(channel.resets, 2);
+}
+
+#[test]
+fn unknown_menu_key_shows_the_keys_again() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    type_in(&mut escape, &mut channel, b"~.");
+    let (_, menu) = type_in(&mut escape, &mut channel, b"?");
+    assert!(menu.starts_with("[picoterm] b: baud rate"));
+    let (uart, menu) = type_in(&mut escape, &mut channel, b".x");
+    assert_eq!(menu, "");
+    assert_eq!(uart, b"x");
+}
+
+/// OUT packets waiting in the endpoint
+struct Packets(Vec<Vec<u8>>);
+
+impl ByteSource for Packets {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        if self.0.is_empty() {
+            return 0;
+        }
+        let packet = self.0.remove(0);
+        buf[..packet.len()].copy_from_slice(&packet);
+        packet.len()
+    }
+}
+
+#[test]
+fn packets_reach_the_queue_without_escapes() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let ring = Box::leak(Box::new(ByteRing::<256>::new()));
+    let (mut producer, mut consumer) = ring.split();
+    let mut host = Packets(vec![b"run\r~".to_vec(), b"~x\r~.".to_vec(), b"s".to_vec()]);
+
+    assert!(escape.receive_packets(&mut host, 64, &mut producer, &mut channel));
+    let mut queued = Vec::new();
+    while !consumer.is_empty() {
+        let data = consumer.read_grant();
+        queued.extend_from_slice(data);
+        let count = data.len();
+        consumer.release(count);
+    }
+    assert_eq!(queued, b"run\r~x\r");
+    assert!(host.0.is_empty());
+
+    // The menu's reply to `s` is waiting for the IN endpoint
+    assert!(escape.is_active());
+    let mut endpoint = Endpoint(Vec::new());
+    assert_eq!(escape.send(&mut endpoint), Ok(true));
+    assert!(endpoint.0.ends_with(b"dropped 5\r\n"));
+    assert!(!escape.is_active());
+}
+
+#[test]
+fn packets_wait_for_room_for_a_held_tilde() {
+    let mut escape = Escape::new(Some(EscapeKey::Tilde));
+    let mut channel = Channel::default();
+    let ring = Box::leak(Box::new(ByteRing::<128>::new()));
+    let (mut producer, _consumer) = ring.split();
+    producer.push_slice(&[0; 64]);
+    let mut host = Packets(vec![vec![b'a'; 64]]);
+
+    // 64 bytes free: one packet plus a held `~` would not fit
+    assert!(!escape.receive_packets(&mut host, 64, &mut producer, &mut channel));
+    assert_eq!(host.0.len(), 1);
+}
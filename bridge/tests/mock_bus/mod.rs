@@ -0,0 +1,273 @@
+//! In-memory `UsbBus` for driving USB classes from host tests
+//!
+//! `MockBus` goes into the `UsbBusAllocator` like a real peripheral; the
+//! test keeps a `MockHost` for the other end of the cable. Every endpoint
+//! holds at most one packet per direction, like the RP2040's buffers: an OUT
+//! packet the device has not read yet makes the next one NAK, and an IN
+//! packet stays pending until the host collects it.
+//!
+//! The host side scripts single transactions (`setup`, `out`, `take_in`)
+//! and leaves polling the device to the test, which calls `UsbDevice::poll`
+//! in between.
+
+use std::sync::{Arc, Mutex};
+use usb_device::bus::{PollResult, UsbBus};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+const ENDPOINTS: usize = 16;
+
+#[derive(Default)]
+struct OutEndpoint {
+    allocated: bool,
+    max_packet_size: u16,
+    stalled: bool,
+    setup: Option<[u8; 8]>,
+    packet: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct InEndpoint {
+    allocated: bool,
+    max_packet_size: u16,
+    stalled: bool,
+    packet: Option<Vec<u8>>,
+    complete: bool,
+}
+
+#[derive(Default)]
+struct BusState {
+    out_eps: [OutEndpoint; ENDPOINTS],
+    in_eps: [InEndpoint; ENDPOINTS],
+    reset_pending: bool,
+    address: u8,
+}
+
+/// The device side, handed to `UsbBusAllocator::new`
+pub struct MockBus {
+    state: Arc<Mutex<BusState>>,
+}
+
+/// The host side of the same bus
+#[derive(Clone)]
+pub struct MockHost {
+    state: Arc<Mutex<BusState>>,
+}
+
+/// A bus and the host attached to it
+pub fn mock_bus() -> (MockBus, MockHost) {
+    let state = Arc::new(Mutex::new(BusState::default()));
+    (
+        MockBus {
+            state: state.clone(),
+        },
+        MockHost { state },
+    )
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let mut state = self.state.lock().unwrap();
+        let taken = |state: &BusState, index: usize| match ep_dir {
+            UsbDirection::Out => state.out_eps[index].allocated,
+            UsbDirection::In => state.in_eps[index].allocated,
+        };
+        let index = match ep_addr {
+            Some(addr) if taken(&state, addr.index()) => return Err(UsbError::InvalidEndpoint),
+            Some(addr) => addr.index(),
+            None => (1..ENDPOINTS)
+                .find(|&index| !taken(&state, index))
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+        match ep_dir {
+            UsbDirection::Out => {
+                state.out_eps[index].allocated = true;
+                state.out_eps[index].max_packet_size = max_packet_size;
+            }
+            UsbDirection::In => {
+                state.in_eps[index].allocated = true;
+                state.in_eps[index].max_packet_size = max_packet_size;
+            }
+        }
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        for ep in &mut state.out_eps {
+            ep.stalled = false;
+            ep.setup = None;
+            ep.packet = None;
+        }
+        for ep in &mut state.in_eps {
+            ep.stalled = false;
+            ep.packet = None;
+            ep.complete = false;
+        }
+        state.address = 0;
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state.lock().unwrap().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let ep = &mut state.in_eps[ep_addr.index()];
+        if !ep.allocated {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if ep.packet.is_some() {
+            return Err(UsbError::WouldBlock);
+        }
+        if buf.len() > usize::from(ep.max_packet_size) {
+            return Err(UsbError::BufferOverflow);
+        }
+        ep.packet = Some(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let ep = &mut state.out_eps[ep_addr.index()];
+        if !ep.allocated {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let packet = match ep.setup.take() {
+            Some(setup) => setup.to_vec(),
+            None => ep.packet.take().ok_or(UsbError::WouldBlock)?,
+        };
+        if packet.len() > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+        match ep_addr.direction() {
+            UsbDirection::Out => state.out_eps[ep_addr.index()].stalled = stalled,
+            UsbDirection::In => state.in_eps[ep_addr.index()].stalled = stalled,
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let state = self.state.lock().unwrap();
+        match ep_addr.direction() {
+            UsbDirection::Out => state.out_eps[ep_addr.index()].stalled,
+            UsbDirection::In => state.in_eps[ep_addr.index()].stalled,
+        }
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state.lock().unwrap();
+        if state.reset_pending {
+            state.reset_pending = false;
+            return PollResult::Reset;
+        }
+
+        let (mut ep_out, mut ep_in_complete, mut ep_setup) = (0u16, 0u16, 0u16);
+        for index in 0..ENDPOINTS {
+            if state.out_eps[index].setup.is_some() {
+                ep_setup |= 1 << index;
+            }
+            if state.out_eps[index].packet.is_some() {
+                ep_out |= 1 << index;
+            }
+            // Reported once per finished transfer
+            if std::mem::take(&mut state.in_eps[index].complete) {
+                ep_in_complete |= 1 << index;
+            }
+        }
+        if ep_out | ep_in_complete | ep_setup == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
+
+/// What the device answered to a host transaction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Handshake {
+    /// The packet was transferred (for IN, with this data)
+    Ack(Vec<u8>),
+    /// The endpoint is not ready; the host retries later
+    Nak,
+    /// The endpoint refused the request
+    Stall,
+}
+
+impl MockHost {
+    /// Drive a bus reset
+    pub fn reset(&self) {
+        self.state.lock().unwrap().reset_pending = true;
+    }
+
+    /// Address the device was given by SET_ADDRESS
+    pub fn address(&self) -> u8 {
+        self.state.lock().unwrap().address
+    }
+
+    /// Send a SETUP packet to endpoint 0. A new SETUP clears a stall and
+    /// aborts whatever the previous control transfer left behind.
+    pub fn setup(&self, packet: [u8; 8]) {
+        let mut state = self.state.lock().unwrap();
+        state.out_eps[0].stalled = false;
+        state.out_eps[0].packet = None;
+        state.out_eps[0].setup = Some(packet);
+        state.in_eps[0].stalled = false;
+        state.in_eps[0].packet = None;
+    }
+
+    /// Send an OUT packet; NAKed while the previous one is still unread
+    pub fn out(&self, ep: usize, data: &[u8]) -> Handshake {
+        let mut state = self.state.lock().unwrap();
+        let endpoint = &mut state.out_eps[ep];
+        assert!(endpoint.allocated, "OUT endpoint {ep} not allocated");
+        assert!(data.len() <= usize::from(endpoint.max_packet_size));
+        if endpoint.stalled {
+            Handshake::Stall
+        } else if endpoint.packet.is_some() || endpoint.setup.is_some() {
+            Handshake::Nak
+        } else {
+            endpoint.packet = Some(data.to_vec());
+            Handshake::Ack(Vec::new())
+        }
+    }
+
+    /// Collect the pending IN packet of an endpoint, if any
+    pub fn take_in(&self, ep: usize) -> Handshake {
+        let mut state = self.state.lock().unwrap();
+        let endpoint = &mut state.in_eps[ep];
+        assert!(endpoint.allocated, "IN endpoint {ep} not allocated");
+        if endpoint.stalled {
+            return Handshake::Stall;
+        }
+        match endpoint.packet.take() {
+            Some(packet) => {
+                endpoint.complete = true;
+                Handshake::Ack(packet)
+            }
+            None => Handshake::Nak,
+        }
+    }
+}
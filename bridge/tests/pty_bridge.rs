@@ -0,0 +1,163 @@
+//! The whole bridge data path between two pseudo-terminals (see `sim`)
+
+#![cfg(target_os = "linux")]
+
+mod sim;
+
+use picoterm_bridge::cdc::{LineCoding, SERIAL_STATE_FRAMING, SERIAL_STATE_OVERRUN};
+use picoterm_bridge::uart_config::{DataBits, LineConfig, Parity, StopBits};
+use sim::{Bridge, PACKET_SIZE, QUEUE_SIZE, read_for, read_len};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Long enough for the simulated cores to make many passes
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// A recognisable byte pattern that does not repeat every queue size
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+#[test]
+fn data_passes_both_ways() {
+    let mut bridge = Bridge::start(1000, None);
+
+    bridge.uart.write_all(b"hello from the target").unwrap();
+    assert_eq!(
+        read_len(&mut bridge.terminal, 21, TIMEOUT),
+        b"hello from the target"
+    );
+
+    bridge.terminal.write_all(b"hello from the host").unwrap();
+    assert_eq!(
+        read_len(&mut bridge.uart, 19, TIMEOUT),
+        b"hello from the host"
+    );
+}
+
+#[test]
+fn large_transfer_to_uart_is_lossless() {
+    let mut bridge = Bridge::start(1000, None);
+    let data = pattern(64 * QUEUE_SIZE + 13);
+
+    // The pty and the queues fill up, so the writer has to block on the
+    // bridge's backpressure while the target reads
+    let mut terminal = bridge.terminal.try_clone().unwrap();
+    let sent = data.clone();
+    let writer = thread::spawn(move || terminal.write_all(&sent).unwrap());
+    let received = read_len(&mut bridge.uart, data.len(), TIMEOUT * 4);
+    writer.join().unwrap();
+
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "data corrupted on the way to the UART");
+}
+
+#[test]
+fn large_transfer_to_host_is_lossless() {
+    let mut bridge = Bridge::start(1000, None);
+    let data = pattern(64 * QUEUE_SIZE + 13);
+
+    let mut uart = bridge.uart.try_clone().unwrap();
+    let sent = data.clone();
+    let writer = thread::spawn(move || uart.write_all(&sent).unwrap());
+    let received = read_len(&mut bridge.terminal, data.len(), TIMEOUT * 4);
+    writer.join().unwrap();
+
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "data corrupted on the way to the host");
+}
+
+#[test]
+fn latency_timer_holds_short_data() {
+    let mut bridge = Bridge::start(500_000, None);
+
+    bridge.uart.write_all(b"abc").unwrap();
+    assert!(read_for(&mut bridge.terminal, SETTLE).is_empty());
+    assert_eq!(read_len(&mut bridge.terminal, 3, TIMEOUT), b"abc");
+
+    // A full packet goes out right away
+    let packet = pattern(PACKET_SIZE);
+    bridge.uart.write_all(&packet).unwrap();
+    assert_eq!(read_for(&mut bridge.terminal, SETTLE), packet);
+}
+
+#[test]
+fn event_char_flushes_right_away() {
+    let mut bridge = Bridge::start(10_000_000, Some(b'\n'));
+
+    bridge.uart.write_all(b"line\n").unwrap();
+    assert_eq!(read_for(&mut bridge.terminal, SETTLE), b"line\n");
+
+    bridge.uart.write_all(b"partial").unwrap();
+    assert!(read_for(&mut bridge.terminal, SETTLE).is_empty());
+}
+
+#[test]
+fn line_coding_reaches_uart() {
+    let bridge = Bridge::start(1000, None);
+
+    // 9600 7E2
+    bridge.set_line_coding(LineCoding::new(9600, 2, 2, 7));
+    thread::sleep(SETTLE);
+
+    let expected = LineConfig {
+        baud: 9600,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+    };
+    let log = bridge.log.lock().unwrap();
+    assert_eq!(log.line_configs.last(), Some(&expected));
+    // Forwarded once, not on every pass
+    assert_eq!(
+        log.line_configs.iter().filter(|c| **c == expected).count(),
+        1
+    );
+
+    let uart = sim::get_termios(&bridge.uart);
+    assert_eq!(sim::termios_baud(&uart), 9600);
+    assert_ne!(uart.c_cflag & libc::CSTOPB, 0);
+}
+
+#[test]
+fn unsupported_line_coding_is_ignored() {
+    let bridge = Bridge::start(1000, None);
+    bridge.set_line_coding(LineCoding::new(57600, 0, 0, 8));
+    thread::sleep(SETTLE);
+
+    // 16 data bits and a zero baud rate have no UART equivalent
+    bridge.set_line_coding(LineCoding::new(9600, 0, 0, 16));
+    thread::sleep(SETTLE);
+    bridge.set_line_coding(LineCoding::new(0, 0, 0, 8));
+    thread::sleep(SETTLE);
+
+    let log = bridge.log.lock().unwrap();
+    assert_eq!(
+        log.line_configs.last(),
+        Some(&LineConfig::eight_n_one(57600))
+    );
+    assert_eq!(sim::termios_baud(&sim::get_termios(&bridge.uart)), 57600);
+}
+
+#[test]
+fn line_errors_become_serial_state_events() {
+    let bridge = Bridge::start(1000, None);
+    thread::sleep(SETTLE);
+    assert_eq!(bridge.log.lock().unwrap().serial_state, 0);
+
+    bridge.log.lock().unwrap().errors.framing += 1;
+    thread::sleep(SETTLE);
+    assert_eq!(
+        bridge.log.lock().unwrap().serial_state,
+        SERIAL_STATE_FRAMING
+    );
+
+    bridge.log.lock().unwrap().errors.overrun += 3;
+    thread::sleep(SETTLE);
+    assert_eq!(
+        bridge.log.lock().unwrap().serial_state,
+        SERIAL_STATE_FRAMING | SERIAL_STATE_OVERRUN
+    );
+}
@@ -0,0 +1,412 @@
+//! The configuration shell of the control port, driven against a bridge
+//! that records what the shell asks of it
+
+use picoterm_bridge::config::{Config, LedMode, PinSelection, ShortString};
+use picoterm_bridge::config_store::SaveError;
+use picoterm_bridge::pin_map::UartPins;
+use picoterm_bridge::shell::{OUTPUT_SIZE, Shell, ShellTarget};
+use picoterm_bridge::stats::LineErrors;
+use picoterm_bridge::uart_config::{FlowControl, LineConfig, Parity};
+use picoterm_bridge::{ByteSink, SinkError};
+use std::ops::RangeInclusive;
+
+/// A bridge with two hardware UARTs on the board's default pins
+struct Bridge {
+    config: Config,
+    /// Baud rate each channel runs at
+    bauds: [u32; 4],
+    /// What `save` wrote last
+    saved: Option<Config>,
+    save_fails: bool,
+    /// A `save` has been started and not reported yet
+    saving: bool,
+    /// Keep the flash busy: `save_result` has nothing yet
+    flash_busy: bool,
+    reboots: u32,
+}
+
+impl Bridge {
+    fn new() -> Self {
+        Self {
+            config: Config::DEFAULT,
+            bauds: [115_200, 9600, 115_200, 115_200],
+            saved: None,
+            save_fails: false,
+            saving: false,
+            flash_busy: false,
+            reboots: 0,
+        }
+    }
+}
+
+impl ShellTarget for Bridge {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    fn line_config(&self, channel: usize) -> LineConfig {
+        let config = LineConfig::eight_n_one(self.bauds[channel]);
+        match channel {
+            1 => LineConfig {
+                parity: Parity::Even,
+                ..config
+            },
+            _ => config,
+        }
+    }
+
+    fn apply_baud(&mut self, channel: usize) {
+        self.bauds[channel] = self.config.baud[channel].unwrap_or(115_200);
+    }
+
+    /// clk_peri / 16 at 125 MHz
+    fn baud_range(&self, _channel: usize) -> RangeInclusive<u32> {
+        120..=7_812_500
+    }
+
+    fn uart_pins(&self, uart: usize) -> Option<UartPins> {
+        match uart {
+            0 => Some(UartPins::pair(0, 1)),
+            _ => None,
+        }
+    }
+
+    /// GPIO25 drives the LED
+    fn reserved_gpios(&self) -> u32 {
+        1 << 25
+    }
+
+    fn line_errors(&self, channel: usize) -> LineErrors {
+        LineErrors {
+            framing: channel as u32,
+            ..LineErrors::NONE
+        }
+    }
+
+    fn dropped(&self, channel: usize) -> u32 {
+        10 * channel as u32
+    }
+
+    fn save(&mut self) {
+        self.saving = true;
+    }
+
+    fn save_result(&mut self) -> Option<Result<(), SaveError>> {
+        if !self.saving || self.flash_busy {
+            return None;
+        }
+        self.saving = false;
+        if self.save_fails {
+            return Some(Err(SaveError::Verify));
+        }
+        self.saved = Some(self.config);
+        Some(Ok(()))
+    }
+
+    fn reboot(&mut self) {
+        self.reboots += 1;
+    }
+}
+
+/// An IN endpoint taking at most `limit` bytes per write
+struct Endpoint {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+impl ByteSink for Endpoint {
+    fn write(&mut self, data: &[u8]) -> Result<usize, SinkError> {
+        let count = data.len().min(self.limit);
+        self.data.extend_from_slice(&data[..count]);
+        Ok(count)
+    }
+}
+
+/// Type `input` and return everything the shell sends back
+fn type_in(shell: &mut Shell, bridge: &mut Bridge, input: &str) -> String {
+    shell.receive(input.as_bytes(), bridge);
+    shell.poll(bridge);
+    let mut endpoint = Endpoint {
+        data: Vec::new(),
+        limit: 64,
+    };
+    shell.send(&mut endpoint).unwrap();
+    assert!(!shell.has_output());
+    String::from_utf8(endpoint.data).unwrap()
+}
+
+#[test]
+fn lines_are_echoed_and_prompted() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "help\r");
+    assert!(reply.starts_with("help\r\n"));
+    assert!(reply.contains("set baud <channel>"));
+    assert!(reply.ends_with("\r\n> "));
+}
+
+#[test]
+fn crlf_ends_one_line() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "\r\n\n");
+    assert_eq!(reply, "\r\n> \r\n> ");
+}
+
+#[test]
+fn backspace_and_ctrl_c_edit_the_line() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "statx\x7fs\r");
+    assert!(reply.starts_with("statx\x08 \x08s\r\n"));
+    assert!(reply.contains("channel 3: framing 3, parity 0, breaks 0, overrun 0, dropped 30"));
+
+    let reply = type_in(&mut shell, &mut bridge, "reboot\x03\r");
+    assert_eq!(reply, "reboot^C\r\n> \r\n> ");
+    assert_eq!(bridge.reboots, 0);
+}
+
+#[test]
+fn status_shows_running_and_edited_settings() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("channel 0: UART0 115200 8N1, pins 0/1\r\n"));
+    assert!(reply.contains("channel 1: UART1 9600 8E1, not connected\r\n"));
+    assert!(reply.contains("channel 2: PIO UART 115200 8N1\r\n"));
+    assert!(reply.contains("settings:\r\n"));
+    assert!(reply.contains("  baud: default, default, default, default\r\n"));
+    assert!(reply.contains("  uart0 pins: default\r\n"));
+    assert!(reply.contains("  usb product: default\r\n"));
+    assert!(reply.contains("  led: activity\r\n"));
+}
+
+#[test]
+fn set_baud_then_save() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "set baud 2 9600\r");
+    assert!(reply.contains("ok; channel 2 now runs at 9600 8N1; save to keep it\r\n"));
+    assert_eq!(bridge.config.baud, [None, None, Some(9600), None]);
+    assert_eq!(bridge.bauds, [115_200, 9600, 9600, 115_200]);
+    assert_eq!(bridge.saved, None);
+
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("settings (not saved):\r\n"));
+    assert!(reply.contains("  baud: default, default, 9600, default\r\n"));
+
+    let reply = type_in(&mut shell, &mut bridge, "save\r");
+    assert!(reply.contains("saved\r\n"));
+    assert_eq!(bridge.saved, Some(bridge.config));
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("settings:\r\n"));
+
+    type_in(&mut shell, &mut bridge, "set baud 2 default\r");
+    assert_eq!(bridge.config.baud, [None; 4]);
+    assert_eq!(bridge.bauds, [115_200, 9600, 115_200, 115_200]);
+
+    // The character format stays as the host set it
+    let reply = type_in(&mut shell, &mut bridge, "set baud 1 19200\r");
+    assert!(reply.contains("ok; channel 1 now runs at 19200 8E1; save to keep it\r\n"));
+}
+
+#[test]
+fn baud_rates_outside_the_divider_range_are_rejected() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    for line in ["set baud 0 119\r", "set baud 3 8000000\r"] {
+        let reply = type_in(&mut shell, &mut bridge, line);
+        assert!(reply.contains("error: the baud rate must be from 120 to 7812500\r\n"));
+    }
+    assert_eq!(bridge.config, Config::DEFAULT);
+    assert_eq!(bridge.bauds, [115_200, 9600, 115_200, 115_200]);
+
+    type_in(&mut shell, &mut bridge, "set baud 3 7812500\r");
+    assert_eq!(bridge.config.baud[3], Some(7_812_500));
+}
+
+#[test]
+fn bad_arguments_show_the_usage() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    for line in [
+        "set baud 4 9600\r",
+        "set baud 0 0\r",
+        "set baud 0\r",
+        "set baud 0 9600 1\r",
+    ] {
+        let reply = type_in(&mut shell, &mut bridge, line);
+        assert!(reply.contains("usage: set baud <channel> <rate>|default\r\n"));
+    }
+    let reply = type_in(&mut shell, &mut bridge, "set colour blue\r");
+    assert!(reply.contains("usage: set baud|pins|flow|led|usb ...\r\n"));
+    assert_eq!(bridge.config, Config::DEFAULT);
+}
+
+#[test]
+fn set_pins_checks_the_mux_and_the_board() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "set pins 0 4 5\r");
+    assert!(reply.contains("error: GPIO4 cannot be UART0 TX\r\n"));
+    let reply = type_in(&mut shell, &mut bridge, "set pins 1 24 25\r");
+    assert!(reply.contains("error: GPIO25 is used by the board\r\n"));
+    let reply = type_in(&mut shell, &mut bridge, "set pins 1 32 33\r");
+    assert!(reply.contains("error: there is no GPIO32\r\n"));
+    assert_eq!(bridge.config, Config::DEFAULT);
+
+    type_in(&mut shell, &mut bridge, "set pins 1 8 9 10 11\r");
+    assert_eq!(
+        bridge.config.uart_pins[1],
+        PinSelection::Pins(UartPins {
+            tx: 8,
+            rx: 9,
+            flow: Some((10, 11)),
+        })
+    );
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("  uart1 pins: 8/9, cts/rts 10/11\r\n"));
+
+    type_in(&mut shell, &mut bridge, "set pins 0 none\r");
+    assert_eq!(bridge.config.uart_pins[0], PinSelection::Unconnected);
+    type_in(&mut shell, &mut bridge, "set pins 1 default\r");
+    assert_eq!(bridge.config.uart_pins[1], PinSelection::BoardDefault);
+}
+
+#[test]
+fn set_flow_control() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("  uart0 flow: default\r\n"));
+
+    let reply = type_in(&mut shell, &mut bridge, "set flow 0 rtscts\r");
+    assert!(reply.contains("ok; save and reboot to apply\r\n"));
+    type_in(&mut shell, &mut bridge, "set flow 1 none\r");
+    assert_eq!(
+        bridge.config.flow_control,
+        [Some(FlowControl::RtsCts), Some(FlowControl::None)]
+    );
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("  uart0 flow: rtscts\r\n  uart1 flow: none\r\n"));
+
+    let reply = type_in(&mut shell, &mut bridge, "set flow 2 none\r");
+    assert!(reply.contains("usage: set flow <uart> none|rtscts|default\r\n"));
+    type_in(&mut shell, &mut bridge, "set flow 0 default\r");
+    assert_eq!(bridge.config.flow_control[0], None);
+}
+
+#[test]
+fn set_led_and_usb_strings() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    type_in(&mut shell, &mut bridge, "set led off\r");
+    assert_eq!(bridge.config.led, LedMode::Off);
+
+    type_in(
+        &mut shell,
+        &mut bridge,
+        "set usb product  Lab bench UART \r",
+    );
+    assert_eq!(
+        bridge.config.usb.product,
+        ShortString::new("Lab bench UART")
+    );
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert!(reply.contains("  usb product: \"Lab bench UART\"\r\n"));
+
+    let reply = type_in(
+        &mut shell,
+        &mut bridge,
+        "set usb serial 0123456789abcdef0123456789abcdef\r",
+    );
+    assert!(reply.contains("error: USB strings are at most 31 bytes\r\n"));
+    assert_eq!(bridge.config.usb.serial_number, None);
+
+    type_in(&mut shell, &mut bridge, "set usb product default\r");
+    assert_eq!(bridge.config.usb.product, None);
+}
+
+#[test]
+fn failed_save_keeps_the_changes_unsaved() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    bridge.save_fails = true;
+    type_in(&mut shell, &mut bridge, "set led on\r");
+    let reply = type_in(&mut shell, &mut bridge, "save\r");
+    assert!(reply.contains("error: the flash did not take the settings\r\n"));
+    let reply = type_in(&mut shell, &mut bridge, "reboot\r");
+    assert!(reply.contains("rebooting; unsaved settings are discarded\r\n"));
+    assert_eq!(bridge.reboots, 1);
+}
+
+#[test]
+fn save_replies_once_the_flash_is_written() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    bridge.flash_busy = true;
+    type_in(&mut shell, &mut bridge, "set led on\r");
+    let reply = type_in(&mut shell, &mut bridge, "save\r");
+    assert_eq!(reply, "save\r\n");
+    // Typed while the flash is written
+    let reply = type_in(&mut shell, &mut bridge, "status\r");
+    assert_eq!(reply, "");
+    assert_eq!(bridge.saved, None);
+
+    bridge.flash_busy = false;
+    let reply = type_in(&mut shell, &mut bridge, "");
+    assert_eq!(reply, "saved\r\n> ");
+    assert_eq!(bridge.saved, Some(bridge.config));
+    let reply = type_in(&mut shell, &mut bridge, "reboot\r");
+    assert!(reply.contains("rebooting\r\n"));
+}
+
+#[test]
+fn unknown_command() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    let reply = type_in(&mut shell, &mut bridge, "format c:\r");
+    assert!(reply.contains("unknown command \"format\"; type help\r\n"));
+}
+
+#[test]
+fn busy_endpoint_keeps_the_reply() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    shell.receive(b"help\r", &mut bridge);
+
+    let mut endpoint = Endpoint {
+        data: Vec::new(),
+        limit: 0,
+    };
+    assert_eq!(shell.send(&mut endpoint), Ok(false));
+    assert!(shell.has_output());
+
+    endpoint.limit = 64;
+    assert_eq!(shell.send(&mut endpoint), Ok(true));
+    assert!(!shell.has_output());
+    assert!(endpoint.data.starts_with(b"help\r\n"));
+}
+
+#[test]
+fn reply_beyond_the_queue_is_dropped() {
+    let mut shell = Shell::new();
+    let mut bridge = Bridge::new();
+    for _ in 0..10 {
+        shell.receive(b"status\r", &mut bridge);
+    }
+    let mut endpoint = Endpoint {
+        data: Vec::new(),
+        limit: 64,
+    };
+    shell.send(&mut endpoint).unwrap();
+    assert_eq!(endpoint.data.len(), OUTPUT_SIZE);
+
+    // The shell keeps working once the queue has room again
+    let reply = type_in(&mut shell, &mut bridge, "\r");
+    assert_eq!(reply, "\r\n> ");
+}
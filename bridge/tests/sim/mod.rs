@@ -0,0 +1,338 @@
+//! Linux simulation of the bridge
+//!
+//! One pseudo-terminal stands in for the UART and another for the CDC
+//! function. Two threads play Core0 and Core1 and run the same data path
+//! as the firmware between the master ends; a test drives the slave ends
+//! like a target device on the UART and a terminal program on the host.
+//!
+//! The terminal's line coding is set through `Bridge::set_line_coding`,
+//! standing in for SET_LINE_CODING: Linux ptys force 8 data bits without
+//! parity, so the character format cannot travel through their settings.
+//! The UART end applies the baud rate and stop bits to its pty and logs
+//! every configuration.
+
+use picoterm_bridge::byte_ring::ByteRing;
+use picoterm_bridge::cdc::LineCoding;
+use picoterm_bridge::host::{self, HostChannel};
+use picoterm_bridge::pump;
+use picoterm_bridge::stats::LineErrors;
+use picoterm_bridge::uart_config::{LineConfig, StopBits};
+use picoterm_bridge::{ByteSink, ByteSource, SinkError, UartControl};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Queue size between the two simulated cores; small, so the queues wrap
+/// and fill up quickly
+pub const QUEUE_SIZE: usize = 1024;
+/// Bulk packet size of the simulated CDC function
+pub const PACKET_SIZE: usize = 64;
+/// Pause between two passes of a simulated core
+const POLL_INTERVAL: Duration = Duration::from_micros(200);
+
+/// Termios speed constants and their baud rates
+const SPEEDS: [(libc::speed_t, u32); 8] = [
+    (libc::B1200, 1200),
+    (libc::B9600, 9600),
+    (libc::B19200, 19200),
+    (libc::B38400, 38400),
+    (libc::B57600, 57600),
+    (libc::B115200, 115_200),
+    (libc::B230400, 230_400),
+    (libc::B921600, 921_600),
+];
+
+/// A pseudo-terminal pair: the master is non-blocking, the slave is in raw
+/// mode so bytes pass unchanged
+pub struct Pty {
+    pub master: File,
+    pub slave: File,
+}
+
+impl Pty {
+    pub fn open() -> Self {
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open("/dev/ptmx")
+            .expect("open /dev/ptmx");
+        let fd = master.as_raw_fd();
+        let mut name = [0 as libc::c_char; 128];
+        unsafe {
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(name.to_str().unwrap())
+            .expect("open pty slave");
+
+        let mut termios = get_termios(&slave);
+        unsafe { libc::cfmakeraw(&mut termios) };
+        set_termios(&slave, &termios);
+        Self { master, slave }
+    }
+}
+
+pub fn get_termios(file: &File) -> libc::termios {
+    let mut termios = unsafe { std::mem::zeroed() };
+    assert_eq!(
+        unsafe { libc::tcgetattr(file.as_raw_fd(), &mut termios) },
+        0
+    );
+    termios
+}
+
+pub fn set_termios(file: &File, termios: &libc::termios) {
+    assert_eq!(
+        unsafe { libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, termios) },
+        0
+    );
+}
+
+/// Baud rate of a termios setting
+pub fn termios_baud(termios: &libc::termios) -> u32 {
+    let speed = unsafe { libc::cfgetospeed(termios) };
+    SPEEDS
+        .iter()
+        .find(|(s, _)| *s == speed)
+        .map_or(0, |(_, baud)| *baud)
+}
+
+/// Set the baud rate of a termios setting (standard rates only)
+pub fn set_termios_baud(termios: &mut libc::termios, baud: u32) {
+    let (speed, _) = SPEEDS
+        .iter()
+        .find(|(_, b)| *b == baud)
+        .expect("non-standard baud rate");
+    unsafe { libc::cfsetspeed(termios, *speed) };
+}
+
+/// Read whatever arrives on `file` within `timeout`
+pub fn read_for(file: &mut File, timeout: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let mut data = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return data;
+        }
+        let mut poll = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll, 1, left.as_millis().max(1) as i32) } > 0 {
+            let mut buf = [0u8; 4096];
+            let count = file.read(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..count]);
+        }
+    }
+}
+
+/// Read exactly `len` bytes from `file`, giving up after `timeout`
+pub fn read_len(file: &mut File, len: usize, timeout: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len && Instant::now() < deadline {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut chunk = read_for(file, left.min(Duration::from_millis(10)));
+        chunk.truncate(len - data.len());
+        data.extend_from_slice(&chunk);
+    }
+    data
+}
+
+/// Non-blocking read from a pty master; nothing waiting reads as 0
+fn read_master(file: &File, buf: &mut [u8]) -> usize {
+    match (&*file).read(buf) {
+        Ok(count) => count,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+        Err(err) => panic!("pty read: {err}"),
+    }
+}
+
+/// Non-blocking write to a pty master; a full pty takes 0 bytes
+fn write_master(file: &File, data: &[u8]) -> Result<usize, SinkError> {
+    match (&*file).write(data) {
+        Ok(count) => Ok(count),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+        Err(_) => Err(SinkError),
+    }
+}
+
+/// What the bridge did to the simulated UART, for the tests to check
+#[derive(Default)]
+pub struct UartLog {
+    pub line_configs: Vec<LineConfig>,
+    pub breaks: Vec<bool>,
+    /// Line error counters the UART reports (set by the tests)
+    pub errors: LineErrors,
+    /// SERIAL_STATE event bits reported to the host so far
+    pub serial_state: u16,
+}
+
+/// The UART end: the master of the UART pty
+#[derive(Clone)]
+struct PtyUart {
+    file: Arc<File>,
+    log: Arc<Mutex<UartLog>>,
+}
+
+impl ByteSource for PtyUart {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        read_master(&self.file, buf)
+    }
+}
+
+impl ByteSink for PtyUart {
+    fn write(&mut self, data: &[u8]) -> Result<usize, SinkError> {
+        write_master(&self.file, data)
+    }
+}
+
+impl UartControl for PtyUart {
+    fn set_line_config(&mut self, config: LineConfig) {
+        // The master shares the slave's settings, so the target sees them
+        let mut termios = get_termios(&self.file);
+        set_termios_baud(&mut termios, config.baud);
+        if config.stop_bits == StopBits::Two {
+            termios.c_cflag |= libc::CSTOPB;
+        } else {
+            termios.c_cflag &= !libc::CSTOPB;
+        }
+        set_termios(&self.file, &termios);
+        self.log.lock().unwrap().line_configs.push(config);
+    }
+
+    fn set_break(&mut self, asserted: bool) {
+        self.log.lock().unwrap().breaks.push(asserted);
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        self.log.lock().unwrap().errors
+    }
+}
+
+/// The CDC end: the master of the host pty, one packet per call
+struct PtyCdc {
+    file: Arc<File>,
+}
+
+impl ByteSource for PtyCdc {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        read_master(&self.file, buf)
+    }
+}
+
+impl ByteSink for PtyCdc {
+    fn write(&mut self, data: &[u8]) -> Result<usize, SinkError> {
+        write_master(&self.file, &data[..data.len().min(PACKET_SIZE)])
+    }
+}
+
+/// A running simulated bridge
+pub struct Bridge {
+    /// The target device's end of the UART
+    pub uart: File,
+    /// The terminal program's end of the CDC function
+    pub terminal: File,
+    pub log: Arc<Mutex<UartLog>>,
+    line_coding: Arc<Mutex<LineCoding>>,
+    stop: Arc<AtomicBool>,
+    cores: Vec<JoinHandle<()>>,
+}
+
+impl Bridge {
+    /// Start the two simulated cores with the given latency timer settings
+    pub fn start(latency_us: u32, event_char: Option<u8>) -> Self {
+        let uart_pty = Pty::open();
+        let cdc_pty = Pty::open();
+        let log = Arc::new(Mutex::new(UartLog::default()));
+        let line_coding = Arc::new(Mutex::new(LineCoding::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let uart = PtyUart {
+            file: Arc::new(uart_pty.master),
+            log: log.clone(),
+        };
+        let cdc = PtyCdc {
+            file: Arc::new(cdc_pty.master),
+        };
+
+        let (cdc_to_uart_in, cdc_to_uart_out) =
+            Box::leak(Box::new(ByteRing::<QUEUE_SIZE>::new())).split();
+        let (uart_to_cdc_in, uart_to_cdc_out) =
+            Box::leak(Box::new(ByteRing::<QUEUE_SIZE>::new())).split();
+
+        let core1 = {
+            let stop = stop.clone();
+            let mut uart = uart.clone();
+            let (mut producer, mut consumer) = (uart_to_cdc_in, cdc_to_uart_out);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    pump::uart_to_host(&mut uart, &mut producer);
+                    pump::host_to_uart(&mut consumer, &mut uart);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            })
+        };
+
+        let core0 = {
+            let stop = stop.clone();
+            let log = log.clone();
+            let line_coding = line_coding.clone();
+            let (mut uart, mut cdc) = (uart, cdc);
+            let (mut producer, mut consumer) = (cdc_to_uart_in, uart_to_cdc_out);
+            let mut channel =
+                HostChannel::new(LineConfig::eight_n_one(115_200), latency_us, event_char);
+            let start = Instant::now();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let coding = *line_coding.lock().unwrap();
+                    channel.sync_line_coding(&coding, &mut uart);
+                    let events = channel.line_error_events(&uart);
+                    log.lock().unwrap().serial_state |= events;
+
+                    host::receive_packets(&mut cdc, PACKET_SIZE, &mut producer);
+                    let now_us = start.elapsed().as_micros() as u32;
+                    channel.send_packets(&mut consumer, &mut cdc, PACKET_SIZE, now_us);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            })
+        };
+
+        Self {
+            uart: uart_pty.slave,
+            terminal: cdc_pty.slave,
+            log,
+            line_coding,
+            stop,
+            cores: vec![core0, core1],
+        }
+    }
+
+    /// Set the line coding as the terminal program would
+    pub fn set_line_coding(&self, coding: LineCoding) {
+        *self.line_coding.lock().unwrap() = coding;
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for core in self.cores.drain(..) {
+            core.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,594 @@
+//! The CDC-ACM function and the host side of the data path, driven through
+//! an in-memory USB bus (see `mock_bus`)
+//!
+//! `Device::service` does for one channel what the firmware's
+//! `handle_usb_serial` does on every USB interrupt.
+
+mod mock_bus;
+
+use mock_bus::{Handshake, MockBus, MockHost, mock_bus};
+use picoterm_bridge::UartControl;
+use picoterm_bridge::byte_ring::{ByteRing, Consumer, Producer};
+use picoterm_bridge::cdc::LineCoding;
+use picoterm_bridge::cdc_acm::CdcAcmClass;
+use picoterm_bridge::descriptor::{ConfigDescriptor, ConfigDescriptorWriter};
+use picoterm_bridge::host::{self, HostChannel};
+use picoterm_bridge::stats::LineErrors;
+use picoterm_bridge::uart_config::{DataBits, LineConfig, Parity, StopBits};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+
+const PACKET_SIZE: usize = 64;
+/// Size of both queues; a few packets, so they fill up quickly
+const QUEUE_SIZE: usize = 256;
+const LATENCY_US: u32 = 1000;
+
+/// Endpoints of the first CDC function, in allocation order
+const NOTIFY_EP: usize = 1;
+const DATA_OUT_EP: usize = 1;
+const DATA_IN_EP: usize = 2;
+
+/// Class request to the first function's communication interface
+const CLASS_OUT: u8 = 0x21;
+const CLASS_IN: u8 = 0xA1;
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const SEND_BREAK: u8 = 0x23;
+
+/// A UART that records what the host side asks of it
+#[derive(Default)]
+struct RecordingUart {
+    line_configs: Vec<LineConfig>,
+    errors: LineErrors,
+}
+
+impl UartControl for RecordingUart {
+    fn set_line_config(&mut self, config: LineConfig) {
+        self.line_configs.push(config);
+    }
+
+    fn set_break(&mut self, _asserted: bool) {}
+
+    fn line_errors(&self) -> LineErrors {
+        self.errors
+    }
+}
+
+/// A composite device of CDC functions; the first one is bridged to a
+/// `RecordingUart` through a pair of queues
+struct Device {
+    host: MockHost,
+    usb: UsbDevice<'static, MockBus>,
+    serials: Vec<CdcAcmClass<'static, MockBus>>,
+    /// Answers GET_DESCRIPTOR(CONFIGURATION) in place of usb-device
+    descriptor: Option<ConfigDescriptor>,
+    channel: HostChannel,
+    uart: RecordingUart,
+    /// The UART's end of the host → UART queue
+    to_uart: Consumer<'static, QUEUE_SIZE>,
+    cdc_to_uart: Producer<'static, QUEUE_SIZE>,
+    /// The UART's end of the UART → host queue
+    from_uart: Producer<'static, QUEUE_SIZE>,
+    uart_to_cdc: Consumer<'static, QUEUE_SIZE>,
+    now_us: u32,
+}
+
+impl Device {
+    fn new(functions: usize) -> Self {
+        Self::with_line_coding(functions, LineCoding::default())
+    }
+
+    /// A device whose CDC functions start out with `line_coding`
+    fn with_line_coding(functions: usize, line_coding: LineCoding) -> Self {
+        Self::build(functions, line_coding, false)
+    }
+
+    /// A device whose configuration descriptor is written by
+    /// `ConfigDescriptorWriter` instead of usb-device
+    fn with_config_descriptor(functions: usize) -> Self {
+        Self::build(functions, LineCoding::default(), true)
+    }
+
+    fn build(functions: usize, line_coding: LineCoding, config_descriptor: bool) -> Self {
+        let (bus, host) = mock_bus();
+        let alloc: &'static UsbBusAllocator<MockBus> =
+            Box::leak(Box::new(UsbBusAllocator::new(bus)));
+        let serials: Vec<_> = (0..functions)
+            .map(|_| CdcAcmClass::with_line_coding(alloc, PACKET_SIZE as u16, line_coding))
+            .collect();
+        let descriptor = config_descriptor
+            .then(|| ConfigDescriptor::new(write_config_descriptor(&serials).leak()));
+        let usb = UsbDeviceBuilder::new(alloc, UsbVidPid(0x2E8A, 0x000A))
+            .max_packet_size_0(64)
+            .unwrap()
+            .composite_with_iads()
+            .build();
+
+        let (cdc_to_uart, to_uart) = Box::leak(Box::new(ByteRing::new())).split();
+        let (from_uart, uart_to_cdc) = Box::leak(Box::new(ByteRing::new())).split();
+
+        Self {
+            host,
+            usb,
+            serials,
+            descriptor,
+            channel: HostChannel::new(LineConfig::eight_n_one(115_200), LATENCY_US, None),
+            uart: RecordingUart::default(),
+            to_uart,
+            cdc_to_uart,
+            from_uart,
+            uart_to_cdc,
+            now_us: 0,
+        }
+    }
+
+    /// Reset, address and configure the device
+    fn configure(&mut self) {
+        self.host.reset();
+        self.service();
+        assert_eq!(
+            self.control_out(0x00, 0x05, 7, 0, &[]),
+            Handshake::Ack(vec![])
+        );
+        assert_eq!(self.host.address(), 7);
+        assert_eq!(
+            self.control_out(0x00, 0x09, 1, 0, &[]),
+            Handshake::Ack(vec![])
+        );
+        assert_eq!(self.usb.state(), UsbDeviceState::Configured);
+    }
+
+    /// A configured device whose UART has taken the CDC default line coding
+    fn enumerated(functions: usize) -> Self {
+        let mut device = Self::new(functions);
+        device.configure();
+        device.uart.line_configs.clear();
+        device
+    }
+
+    /// One pass of the firmware's USB interrupt for the first channel
+    fn service(&mut self) -> bool {
+        let mut classes: Vec<&mut dyn UsbClass<MockBus>> = self
+            .descriptor
+            .iter_mut()
+            .map(|descriptor| descriptor as &mut dyn UsbClass<MockBus>)
+            .chain(
+                self.serials
+                    .iter_mut()
+                    .map(|serial| serial as &mut dyn UsbClass<MockBus>),
+            )
+            .collect();
+        let event = self.usb.poll(&mut classes);
+        let configured = self.usb.state() == UsbDeviceState::Configured;
+
+        let serial = &mut self.serials[0];
+        self.channel.sync_cdc(serial, configured, &mut self.uart);
+        let received = host::receive_packets(serial, PACKET_SIZE, &mut self.cdc_to_uart);
+        let sent =
+            self.channel
+                .send_packets(&mut self.uart_to_cdc, serial, PACKET_SIZE, self.now_us);
+        event || received || sent
+    }
+
+    fn setup(&mut self, request_type: u8, request: u8, value: u16, index: u16, length: u16) {
+        let [value_lo, value_hi] = value.to_le_bytes();
+        let [index_lo, index_hi] = index.to_le_bytes();
+        let [length_lo, length_hi] = length.to_le_bytes();
+        self.host.setup([
+            request_type,
+            request,
+            value_lo,
+            value_hi,
+            index_lo,
+            index_hi,
+            length_lo,
+            length_hi,
+        ]);
+        self.service();
+    }
+
+    /// A control transfer without data or with a host-to-device data stage
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Handshake {
+        self.setup(request_type, request, value, index, data.len() as u16);
+        for chunk in data.chunks(64) {
+            match self.host.out(0, chunk) {
+                Handshake::Ack(_) => {}
+                other => return other,
+            }
+            self.service();
+        }
+        // Status stage
+        let status = self.host.take_in(0);
+        self.service();
+        status
+    }
+
+    /// A control transfer with a device-to-host data stage
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, Handshake> {
+        self.setup(request_type, request, value, index, length);
+        let mut data = Vec::new();
+        loop {
+            match self.host.take_in(0) {
+                Handshake::Ack(packet) => {
+                    let short = packet.len() < 64;
+                    data.extend_from_slice(&packet);
+                    self.service();
+                    if short || data.len() == usize::from(length) {
+                        break;
+                    }
+                }
+                other => return Err(other),
+            }
+        }
+        // Status stage
+        assert_eq!(self.host.out(0, &[]), Handshake::Ack(vec![]));
+        self.service();
+        Ok(data)
+    }
+
+    fn set_line_coding(&mut self, coding: LineCoding) -> Handshake {
+        self.control_out(CLASS_OUT, SET_LINE_CODING, 0, 0, &coding.to_bytes())
+    }
+
+    /// Everything the UART has been sent so far
+    fn uart_output(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        while !self.to_uart.is_empty() {
+            let chunk = self.to_uart.read_grant();
+            data.extend_from_slice(chunk);
+            let count = chunk.len();
+            self.to_uart.release(count);
+        }
+        data
+    }
+}
+
+/// The configuration descriptor of `serials`, written by
+/// `ConfigDescriptorWriter`
+fn write_config_descriptor(serials: &[CdcAcmClass<'static, MockBus>]) -> Vec<u8> {
+    let mut buf = vec![0; 512];
+    let mut writer = ConfigDescriptorWriter::new(&mut buf).unwrap();
+    for serial in serials {
+        serial.write_descriptors(&mut writer).unwrap();
+    }
+    let length = writer.finish().len();
+    buf.truncate(length);
+    buf
+}
+
+#[test]
+fn four_functions_fit_the_control_buffer() {
+    let mut device = Device::enumerated(4);
+    let descriptor = device.control_in(0x80, 0x06, 0x0200, 0, 255).unwrap();
+
+    // 9-byte configuration header plus 61 bytes per function
+    let total = usize::from(u16::from_le_bytes([descriptor[2], descriptor[3]]));
+    assert_eq!(total, 9 + 4 * 61);
+    assert_eq!(descriptor.len(), total);
+    assert_eq!(descriptor[4], 8, "two interfaces per function");
+}
+
+#[test]
+fn five_functions_overflow_the_control_buffer() {
+    let mut device = Device::enumerated(5);
+    assert!(device.control_in(0x80, 0x06, 0x0200, 0, 512).is_err());
+}
+
+#[test]
+fn written_descriptor_matches_usb_device() {
+    let mut device = Device::enumerated(4);
+    let descriptor = device.control_in(0x80, 0x06, 0x0200, 0, 255).unwrap();
+    assert_eq!(write_config_descriptor(&device.serials), descriptor);
+}
+
+#[test]
+fn five_functions_enumerate_with_a_written_descriptor() {
+    let mut device = Device::with_config_descriptor(5);
+    device.configure();
+    let descriptor = device.control_in(0x80, 0x06, 0x0200, 0, 512).unwrap();
+
+    let total = usize::from(u16::from_le_bytes([descriptor[2], descriptor[3]]));
+    assert_eq!(total, 9 + 5 * 61);
+    assert_eq!(descriptor.len(), total);
+    assert_eq!(descriptor[4], 10, "two interfaces per function");
+
+    // A host reading only the header gets just that
+    let header = device.control_in(0x80, 0x06, 0x0200, 0, 9).unwrap();
+    assert_eq!(header, descriptor[..9]);
+}
+
+#[test]
+fn line_coding_round_trip() {
+    let mut device = Device::enumerated(1);
+    let coding = LineCoding::new(9600, 2, 2, 7);
+    assert_eq!(device.set_line_coding(coding), Handshake::Ack(vec![]));
+
+    let reported = device
+        .control_in(CLASS_IN, GET_LINE_CODING, 0, 0, 7)
+        .unwrap();
+    assert_eq!(reported, coding.to_bytes());
+    assert_eq!(
+        device.uart.line_configs,
+        [LineConfig {
+            baud: 9600,
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+        }]
+    );
+
+    // Repeating the same coding is not forwarded again
+    device.set_line_coding(coding);
+    device.service();
+    assert_eq!(device.uart.line_configs.len(), 1);
+}
+
+#[test]
+fn unsupported_line_coding_keeps_the_uart_setting() {
+    let mut device = Device::enumerated(1);
+    device.set_line_coding(LineCoding::new(57600, 0, 0, 8));
+
+    // Accepted on the bus, but has no UART equivalent
+    assert_eq!(
+        device.set_line_coding(LineCoding::new(57600, 0, 0, 16)),
+        Handshake::Ack(vec![])
+    );
+    device.set_line_coding(LineCoding::new(0, 0, 0, 8));
+    device.service();
+
+    assert_eq!(device.uart.line_configs, [LineConfig::eight_n_one(57600)]);
+    assert_eq!(device.channel.line_config(), LineConfig::eight_n_one(57600));
+}
+
+#[test]
+fn device_line_config_lasts_until_the_host_sets_another() {
+    let mut device = Device::enumerated(1);
+    let config = LineConfig::eight_n_one(19200);
+    device.channel.set_line_config(config, &mut device.uart);
+    device.service();
+    assert_eq!(device.channel.line_config(), config);
+
+    // The line coding the host set before does not undo it
+    device.set_line_coding(LineCoding::default());
+    assert_eq!(device.channel.line_config(), config);
+
+    device.set_line_coding(LineCoding::new(57600, 0, 0, 8));
+    assert_eq!(
+        device.uart.line_configs,
+        [config, LineConfig::eight_n_one(57600)]
+    );
+}
+
+#[test]
+fn configuration_applies_the_default_line_coding() {
+    let mut device = Device::new(1);
+    device.service();
+    assert!(device.uart.line_configs.is_empty());
+
+    // The CDC default (9600 8N1) replaces the power-on setting
+    device.configure();
+    assert_eq!(device.uart.line_configs, [LineConfig::eight_n_one(9600)]);
+}
+
+#[test]
+fn saved_line_coding_replaces_the_cdc_default() {
+    // Functions that start out with the UART's power-on setting (115200 8N1)
+    let power_on = LineConfig::eight_n_one(115_200);
+    let mut device = Device::with_line_coding(1, power_on.to_line_coding());
+    device.configure();
+    assert!(device.uart.line_configs.is_empty());
+
+    let reported = device
+        .control_in(CLASS_IN, GET_LINE_CODING, 0, 0, 7)
+        .unwrap();
+    assert_eq!(reported, power_on.to_line_coding().to_bytes());
+
+    // A bus reset returns to it, not to 9600
+    device.set_line_coding(LineCoding::new(9600, 0, 0, 8));
+    device.uart.line_configs.clear();
+    device.configure();
+    assert_eq!(device.uart.line_configs, [power_on]);
+}
+
+#[test]
+fn line_coding_waits_for_configuration() {
+    let mut device = Device::new(1);
+    device.host.reset();
+    device.service();
+    device.control_out(0x00, 0x05, 7, 0, &[]);
+    device.set_line_coding(LineCoding::new(57600, 0, 0, 8));
+    assert!(device.uart.line_configs.is_empty());
+
+    device.control_out(0x00, 0x09, 1, 0, &[]);
+    assert_eq!(device.uart.line_configs, [LineConfig::eight_n_one(57600)]);
+}
+
+#[test]
+fn control_lines_and_break() {
+    let mut device = Device::enumerated(1);
+    assert_eq!(
+        device.control_out(CLASS_OUT, SET_CONTROL_LINE_STATE, 0x0003, 0, &[]),
+        Handshake::Ack(vec![])
+    );
+    assert!(device.serials[0].dtr());
+    assert!(device.serials[0].rts());
+
+    device.control_out(CLASS_OUT, SET_CONTROL_LINE_STATE, 0x0001, 0, &[]);
+    assert!(device.serials[0].dtr());
+    assert!(!device.serials[0].rts());
+
+    assert_eq!(
+        device.control_out(CLASS_OUT, SEND_BREAK, 250, 0, &[]),
+        Handshake::Ack(vec![])
+    );
+    assert_eq!(device.serials[0].take_break_request(), Some(250));
+    assert_eq!(device.serials[0].take_break_request(), None);
+}
+
+#[test]
+fn unknown_class_request_stalls() {
+    let mut device = Device::enumerated(1);
+    assert_eq!(
+        device.control_out(CLASS_OUT, 0x42, 0, 0, &[]),
+        Handshake::Stall
+    );
+    // The next request works again
+    assert_eq!(
+        device.control_out(CLASS_OUT, SET_CONTROL_LINE_STATE, 0x0001, 0, &[]),
+        Handshake::Ack(vec![])
+    );
+}
+
+#[test]
+fn out_packets_wait_for_queue_room() {
+    let mut device = Device::enumerated(1);
+    let packets: Vec<Vec<u8>> = (0..6u8).map(|n| vec![n; PACKET_SIZE]).collect();
+
+    // Four packets fill the queue; the fifth stays in the endpoint buffer
+    for packet in &packets[..5] {
+        assert_eq!(device.host.out(DATA_OUT_EP, packet), Handshake::Ack(vec![]));
+        device.service();
+    }
+    assert_eq!(device.host.out(DATA_OUT_EP, &packets[5]), Handshake::Nak);
+    device.service();
+    assert_eq!(device.host.out(DATA_OUT_EP, &packets[5]), Handshake::Nak);
+
+    // The UART drains the queue, which lets the held packets through
+    let mut output = device.uart_output();
+    device.service();
+    assert_eq!(
+        device.host.out(DATA_OUT_EP, &packets[5]),
+        Handshake::Ack(vec![])
+    );
+    device.service();
+    output.extend(device.uart_output());
+
+    assert_eq!(output, packets.concat());
+}
+
+#[test]
+fn short_data_waits_for_the_latency_timer() {
+    let mut device = Device::enumerated(1);
+    device.from_uart.push_slice(b"0123456789");
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Nak);
+
+    device.now_us = LATENCY_US / 2;
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Nak);
+    assert_eq!(
+        device.channel.flush_deadline(device.now_us),
+        Some(LATENCY_US)
+    );
+
+    device.now_us = LATENCY_US;
+    device.service();
+    assert_eq!(
+        device.host.take_in(DATA_IN_EP),
+        Handshake::Ack(b"0123456789".to_vec())
+    );
+    assert_eq!(device.channel.flush_deadline(device.now_us), None);
+}
+
+#[test]
+fn busy_in_endpoint_keeps_data_queued() {
+    let mut device = Device::enumerated(1);
+    let data: Vec<u8> = (0..150u8).collect();
+    device.from_uart.push_slice(&data);
+
+    // Full packets go out right away, one at a time as the host collects them
+    device.service();
+    device.service();
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        match device.host.take_in(DATA_IN_EP) {
+            Handshake::Ack(packet) => {
+                assert_eq!(packet.len(), PACKET_SIZE);
+                received.extend(packet);
+            }
+            other => panic!("expected a full packet, got {other:?}"),
+        }
+        device.service();
+    }
+
+    // The short rest waits for the latency timer
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Nak);
+    device.now_us = LATENCY_US;
+    device.service();
+    match device.host.take_in(DATA_IN_EP) {
+        Handshake::Ack(packet) => received.extend(packet),
+        other => panic!("expected the rest, got {other:?}"),
+    }
+
+    assert_eq!(received, data);
+    assert!(device.uart_to_cdc.is_empty());
+    assert_eq!(device.channel.dropped(), 0);
+}
+
+#[test]
+fn full_last_packet_is_followed_by_a_zlp() {
+    let mut device = Device::enumerated(1);
+    let data: Vec<u8> = (0..PACKET_SIZE as u8).collect();
+    device.from_uart.push_slice(&data);
+
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Ack(data));
+    // The queue is empty: a zero-length packet ends the transfer
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Ack(vec![]));
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Nak);
+
+    // A short last packet ends the transfer by itself
+    device.from_uart.push_slice(&[1; PACKET_SIZE + 1]);
+    device.service();
+    assert_eq!(
+        device.host.take_in(DATA_IN_EP),
+        Handshake::Ack(vec![1; PACKET_SIZE])
+    );
+    device.now_us = LATENCY_US;
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Ack(vec![1]));
+    device.service();
+    assert_eq!(device.host.take_in(DATA_IN_EP), Handshake::Nak);
+}
+
+#[test]
+fn line_errors_are_notified() {
+    let mut device = Device::enumerated(1);
+    device.service();
+    assert_eq!(device.host.take_in(NOTIFY_EP), Handshake::Nak);
+
+    device.uart.errors.framing += 1;
+    device.service();
+    // SERIAL_STATE on interface 0: carrier and DSR plus bFraming
+    assert_eq!(
+        device.host.take_in(NOTIFY_EP),
+        Handshake::Ack(vec![0xA1, 0x20, 0, 0, 0, 0, 2, 0, 0x13, 0x00])
+    );
+
+    // The event bits are edges: an idle state follows
+    device.service();
+    assert_eq!(
+        device.host.take_in(NOTIFY_EP),
+        Handshake::Ack(vec![0xA1, 0x20, 0, 0, 0, 0, 2, 0, 0x03, 0x00])
+    );
+    device.service();
+    assert_eq!(device.host.take_in(NOTIFY_EP), Handshake::Nak);
+}
@@ -1,24 +1,368 @@
+//! Board selection and code generation
+//!
+//! The board is described by a TOML file (see `boards/`). It is chosen by
+//! the `PICOTERM_BOARD` environment variable (a name in `boards/` or a path
+//! to a `.toml` file), else by a `board-<name>` feature, else it is the
+//! reference board of the selected chip (`pico` or `pico2`).
+//!
+//! From that file this script generates the `Board` implementation
+//! (`$OUT_DIR/board.rs`, included by `src/board/mod.rs`) and the linker
+//! memory layout (`$OUT_DIR/memory.x`, from the chip's template).
+
+use serde::Deserialize;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// Environment variable selecting the board file
+const BOARD_ENV: &str = "PICOTERM_BOARD";
+/// Placeholder for the flash size in the memory layout templates
+const FLASH_SIZE_PLACEHOLDER: &str = "{FLASH_SIZE}";
+/// GPIOs a board file may use (GPIO0-GPIO29)
+const GPIO_COUNT: u8 = 30;
+/// Flash at the end kept out of the FLASH region for the configuration
+/// store
+const CONFIG_FLASH_KIB: u32 = picoterm_bridge::config_store::STORE_SIZE / 1024;
+/// `[[pio_uart]]` entries a board file needs (`PIO_UART_CHANNELS` in src/main.rs)
+const PIO_UART_CHANNELS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Chip {
+    Rp2040,
+    Rp2350,
+}
+
+impl Chip {
+    fn name(self) -> &'static str {
+        match self {
+            Chip::Rp2040 => "rp2040",
+            Chip::Rp2350 => "rp2350",
+        }
+    }
+}
+
+/// A `boards/<name>.toml` file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BoardFile {
+    chip: Chip,
+    /// e.g. "2MiB" or "512KiB"
+    flash_size: String,
+    xtal_hz: u32,
+    led: Option<Led>,
+    rgb_led: Option<RgbLed>,
+    button: Option<Button>,
+    uart0: Option<UartPins>,
+    uart1: Option<UartPins>,
+    #[serde(default)]
+    pio_uart: Vec<PioUartPins>,
+    control_lines: Option<ControlLines>,
+    #[serde(default)]
+    usb: UsbStrings,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Led {
+    pin: u8,
+    #[serde(default)]
+    active_low: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RgbLed {
+    data: u8,
+    power: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Button {
+    pin: u8,
+    #[serde(default)]
+    active_low: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UartPins {
+    tx: u8,
+    rx: u8,
+    cts: Option<u8>,
+    rts: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PioUartPins {
+    tx: u8,
+    rx: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ControlLines {
+    dtr: u8,
+    rts: u8,
+    #[serde(default)]
+    active_low: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UsbStrings {
+    manufacturer: String,
+    product: String,
+    serial_number: String,
+}
+
+impl Default for UsbStrings {
+    fn default() -> Self {
+        Self {
+            manufacturer: "Example".into(),
+            product: "rp-serial".into(),
+            serial_number: "000".into(),
+        }
+    }
+}
+
+/// True if the Cargo feature `name` is enabled
+fn feature_enabled(name: &str) -> bool {
+    let var = format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"));
+    env::var(var).is_ok()
+}
+
+/// Path of the selected board file (relative to the package root)
+fn select_board_file(rp2350: bool) -> PathBuf {
+    if let Ok(board) = env::var(BOARD_ENV) {
+        return if board.ends_with(".toml") {
+            PathBuf::from(board)
+        } else {
+            Path::new("boards").join(format!("{board}.toml"))
+        };
+    }
+
+    let mut features: Vec<_> = env::vars()
+        .filter_map(|(var, _)| {
+            let name = var.strip_prefix("CARGO_FEATURE_BOARD_")?;
+            Some(name.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    let name = match features.as_slice() {
+        [] if rp2350 => "pico2",
+        [] => "pico",
+        [name] => name,
+        _ => panic!("Select at most one board-* feature (or set {BOARD_ENV})"),
+    };
+    Path::new("boards").join(format!("{name}.toml"))
+}
+
+/// Parse a flash size such as "2MiB" or "512KiB" into KiB
+fn flash_size_kib(size: &str) -> Result<u32, String> {
+    let (number, unit_kib) = if let Some(number) = size.strip_suffix("MiB") {
+        (number, 1024)
+    } else if let Some(number) = size.strip_suffix("KiB") {
+        (number, 1)
+    } else {
+        return Err(format!("flash_size {size:?} must end in KiB or MiB"));
+    };
+    let kib = number
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("flash_size {size:?} is not a number of KiB or MiB"))?
+        * unit_kib;
+    if kib <= CONFIG_FLASH_KIB || kib % 4 != 0 || kib > 16 * 1024 {
+        return Err(format!(
+            "flash_size {size:?} must be a multiple of 4KiB, more than {CONFIG_FLASH_KIB}KiB and at most 16MiB"
+        ));
+    }
+    Ok(kib)
+}
+
+/// Check the parts of a board file the generated code cannot express.
+/// UART functions and GPIOs used twice are checked when the firmware is
+/// compiled (`board::check_board`).
+fn validate(board: &BoardFile) -> Result<(), String> {
+    if board.pio_uart.len() != PIO_UART_CHANNELS {
+        return Err(format!(
+            "needs {PIO_UART_CHANNELS} [[pio_uart]] entries, found {}",
+            board.pio_uart.len()
+        ));
+    }
+
+    let mut pins = Vec::new();
+    if let Some(led) = &board.led {
+        pins.push(("led.pin", led.pin));
+    }
+    if let Some(rgb) = &board.rgb_led {
+        pins.push(("rgb_led.data", rgb.data));
+        pins.extend(rgb.power.map(|power| ("rgb_led.power", power)));
+    }
+    if let Some(button) = &board.button {
+        pins.push(("button.pin", button.pin));
+    }
+    for (name, uart) in [("uart0", &board.uart0), ("uart1", &board.uart1)] {
+        let Some(uart) = uart else { continue };
+        if uart.cts.is_some() != uart.rts.is_some() {
+            return Err(format!("{name} needs both cts and rts, or neither"));
+        }
+        pins.extend([(name, uart.tx), (name, uart.rx)]);
+        pins.extend(uart.cts.map(|cts| (name, cts)));
+        pins.extend(uart.rts.map(|rts| (name, rts)));
+    }
+    for pio_uart in &board.pio_uart {
+        pins.extend([("pio_uart", pio_uart.tx), ("pio_uart", pio_uart.rx)]);
+    }
+    if let Some(lines) = &board.control_lines {
+        pins.extend([("control_lines", lines.dtr), ("control_lines", lines.rts)]);
+    }
+
+    if let Some((name, pin)) = pins.iter().find(|(_, pin)| *pin >= GPIO_COUNT) {
+        return Err(format!("{name} uses GPIO{pin}, only GPIO0-GPIO29 exist"));
+    }
+    Ok(())
+}
+
+/// `Some(..)` / `None` as Rust source
+fn option<T>(value: &Option<T>, source: impl Fn(&T) -> String) -> String {
+    value
+        .as_ref()
+        .map_or_else(|| "None".into(), |value| format!("Some({})", source(value)))
+}
+
+/// The `Board` implementation for `board`
+fn generate_board(board: &BoardFile, flash_kib: u32, path: &Path) -> String {
+    let led = option(&board.led, |led| {
+        format!("Led {{ pin: {}, active_low: {} }}", led.pin, led.active_low)
+    });
+    let rgb_led = option(&board.rgb_led, |rgb| {
+        format!(
+            "RgbLed {{ data: {}, power: {} }}",
+            rgb.data,
+            option(&rgb.power, u8::to_string)
+        )
+    });
+    let button = option(&board.button, |button| {
+        format!(
+            "Button {{ pin: {}, active_low: {} }}",
+            button.pin, button.active_low
+        )
+    });
+    let uart = |uart: &Option<UartPins>| {
+        option(uart, |uart| {
+            let flow = match (uart.cts, uart.rts) {
+                (Some(cts), Some(rts)) => format!("Some(({cts}, {rts}))"),
+                _ => "None".into(),
+            };
+            format!(
+                "UartPins {{ tx: {}, rx: {}, flow: {flow} }}",
+                uart.tx, uart.rx
+            )
+        })
+    };
+    let pio_uart: Vec<_> = board
+        .pio_uart
+        .iter()
+        .map(|pins| format!("({}, {})", pins.tx, pins.rx))
+        .collect();
+    let control_lines = option(&board.control_lines, |lines| {
+        format!(
+            "ControlLinePins {{ dtr: {}, rts: {}, active_low: {} }}",
+            lines.dtr, lines.rts, lines.active_low
+        )
+    });
+
+    format!(
+        "// Generated by build.rs from {path}; edit that file instead.
+
+/// The board described by `{path}`
+pub struct GeneratedBoard;
+
+impl Board for GeneratedBoard {{
+    const XTAL_FREQ_HZ: u32 = {xtal_hz};
+    const FLASH_SIZE: usize = {flash_size};
+    const LED: Option<Led> = {led};
+    const RGB_LED: Option<RgbLed> = {rgb_led};
+    const BUTTON: Option<Button> = {button};
+    const UART_PINS: [Option<UartPins>; HW_UART_CHANNELS] = [{uart0}, {uart1}];
+    const PIO_UART_PINS: [(u8, u8); PIO_UART_CHANNELS] = [{pio_uart}];
+    const CONTROL_LINES: Option<ControlLinePins> = {control_lines};
+    const USB_MANUFACTURER: &'static str = {manufacturer:?};
+    const USB_PRODUCT: &'static str = {product:?};
+    const USB_SERIAL_NUMBER: &'static str = {serial_number:?};
+}}
+",
+        path = path.display(),
+        xtal_hz = board.xtal_hz,
+        flash_size = flash_kib * 1024,
+        uart0 = uart(&board.uart0),
+        uart1 = uart(&board.uart1),
+        pio_uart = pio_uart.join(", "),
+        manufacturer = board.usb.manufacturer,
+        product = board.usb.product,
+        serial_number = board.usb.serial_number,
+    )
+}
 
 fn main() {
-    // Determine which memory layout to use based on features
-    let memory_file = if env::var("CARGO_FEATURE_RP2350").is_ok() {
-        "memory_rp2350.x"
+    let rp2350 = env::var("CARGO_FEATURE_RP2350").is_ok();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    let path = select_board_file(rp2350);
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Failed to read board file {}: {err}", path.display()));
+    let board: BoardFile = toml::from_str(&text)
+        .unwrap_or_else(|err| panic!("Invalid board file {}: {err}", path.display()));
+    let flash_kib = flash_size_kib(&board.flash_size)
+        .and_then(|kib| validate(&board).map(|()| kib))
+        .unwrap_or_else(|err| panic!("Invalid board file {}: {err}", path.display()));
+
+    // The chip feature selects the HAL, so it has to match the board
+    let chip = board.chip.name();
+    if !feature_enabled(chip) {
+        panic!(
+            "{} is an {chip} board; build it with `cargo {chip}-build`",
+            path.display()
+        );
+    }
+    let other_chip = if board.chip == Chip::Rp2040 {
+        "rp2350"
     } else {
-        // Default to RP2040
-        "memory_rp2040.x"
+        "rp2040"
     };
+    if feature_enabled(other_chip) {
+        panic!(
+            "{} is an {chip} board; build it with --no-default-features",
+            path.display()
+        );
+    }
 
-    // Copy the appropriate memory file to memory.x in the project root
-    let src = Path::new(memory_file);
-    let dst = Path::new("memory.x");
+    fs::write(
+        out_dir.join("board.rs"),
+        generate_board(&board, flash_kib, &path),
+    )
+    .expect("Failed to write board.rs");
 
-    fs::copy(&src, &dst).expect("Failed to copy memory file");
+    // Fill the board's flash size, less the configuration store, into the
+    // chip's memory layout
+    let memory_file = format!("memory_{chip}.x");
+    let template = fs::read_to_string(&memory_file).expect("Failed to read memory file");
+    assert!(
+        template.contains(FLASH_SIZE_PLACEHOLDER),
+        "{memory_file} has no {FLASH_SIZE_PLACEHOLDER} placeholder"
+    );
+    let app_flash_kib = flash_kib - CONFIG_FLASH_KIB;
+    let memory = template.replace(FLASH_SIZE_PLACEHOLDER, &format!("{app_flash_kib}K"));
+    fs::write(out_dir.join("memory.x"), memory).expect("Failed to write memory.x");
+    println!("cargo:rustc-link-search={}", out_dir.display());
 
-    // Tell cargo to rerun if the memory files change
-    println!("cargo:rerun-if-changed={}", memory_file);
+    // Tell cargo to rerun if the board selection or its inputs change
+    println!("cargo:rerun-if-env-changed={BOARD_ENV}");
+    println!("cargo:rerun-if-changed={}", path.display());
+    println!("cargo:rerun-if-changed=boards");
     println!("cargo:rerun-if-changed=memory_rp2040.x");
     println!("cargo:rerun-if-changed=memory_rp2350.x");
 }
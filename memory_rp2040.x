@@ -1,6 +1,10 @@
+/*
+ * {FLASH_SIZE} is filled in by build.rs: the board file's flash_size less the
+ * two sectors at the end that hold the saved configuration.
+ */
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = {FLASH_SIZE} - 0x100
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
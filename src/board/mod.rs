@@ -1,4 +1,7 @@
 //! ボード固有コードのエントリポイントです。
+//!
+//! チップ（RP2040 / RP2350）ごとのコードは `rp2040` / `rp2350` に、
+//! ボードごとの配線は `boards/<name>.toml` に書き、build.rs がそこから `Board` の実装を生成します。
 
 // ボードごとのモジュールを条件コンパイルで切り替えます。
 // `--features rp2040` を指定してビルドすることで rp2040 の設定が使われます。
@@ -15,6 +18,184 @@ pub mod rp2350;
 #[cfg(feature = "rp2350")]
 pub use rp2350::*;
 
+mod pins;
+
+pub use pins::{init_pins_and_enable_uarts, reserved_gpios, resolve_uart_pins};
+// GPIO の UART 機能の対応表（ホストでもテストできるよう picoterm-bridge にあります）
+pub use picoterm_bridge::pin_map::{GPIO_COUNT, UartPins, UartSignal, uart_function};
+
+use crate::uart_config::FlowControl;
+use crate::{HW_UART_CHANNELS, PIO_UART_CHANNELS, UART_FLOW_CONTROL};
+
+/// ボードの配線と部品の情報。build.rs がボードファイルから実装を生成します。
+///
+/// ピンはすべて GPIO 番号です。割り当ての妥当性（UART 機能の有無、重複）は
+/// コンパイル時に検査されます。
+pub trait Board {
+    /// 外部クリスタルの周波数（Hz）
+    const XTAL_FREQ_HZ: u32;
+    /// フラッシュの容量（バイト）。末尾のセクタに設定を保存します。
+    const FLASH_SIZE: usize;
+    /// アクティビティ表示用 LED（無いボードは `None`）
+    const LED: Option<Led>;
+    /// アドレス指定型 RGB LED（WS2812 / NeoPixel）。ファームウェアは消灯状態に保ちます。
+    const RGB_LED: Option<RgbLed>;
+    /// ユーザーボタン（BOOTSEL とは別に GPIO で読めるもの）
+    const BUTTON: Option<Button>;
+    /// UART0 / UART1 の標準のピン。`None` のチャンネルはピンに接続されません。
+    /// 保存した設定で起動時に別のピンを選べます（`resolve_uart_pins`）。
+    const UART_PINS: [Option<UartPins>; HW_UART_CHANNELS];
+    /// PIO UART チャンネルの (TX, RX)
+    const PIO_UART_PINS: [(u8, u8); PIO_UART_CHANNELS];
+    /// 最初のポートの DTR/RTS を反映する出力（無いボードは `None`）
+    const CONTROL_LINES: Option<ControlLinePins>;
+    /// USB ディスクリプタの製造者名
+    const USB_MANUFACTURER: &'static str;
+    /// USB ディスクリプタの製品名
+    const USB_PRODUCT: &'static str;
+    /// USB ディスクリプタのシリアル番号
+    const USB_SERIAL_NUMBER: &'static str;
+}
+
+/// 単色 LED
+#[derive(Clone, Copy)]
+pub struct Led {
+    pub pin: u8,
+    /// true の場合、Low で点灯します
+    pub active_low: bool,
+}
+
+/// WS2812 互換の RGB LED
+#[derive(Clone, Copy)]
+pub struct RgbLed {
+    /// データ入力
+    pub data: u8,
+    /// 電源を切り替える GPIO（High で給電、常時給電のボードは `None`）
+    pub power: Option<u8>,
+}
+
+/// 押しボタン
+#[derive(Clone, Copy)]
+pub struct Button {
+    pub pin: u8,
+    /// true の場合、押すと Low になります（内部プルアップを使います）
+    pub active_low: bool,
+}
+
+/// DTR/RTS 出力ピン
+#[derive(Clone, Copy)]
+pub struct ControlLinePins {
+    pub dtr: u8,
+    pub rts: u8,
+    /// true の場合、FTDI/CP210x と同様に信号がアサートされると Low になります
+    pub active_low: bool,
+}
+
+// build.rs が選択したボードファイル（`PICOTERM_BOARD` / `board-*` feature / チップの標準ボード）
+// から生成した実装です。
+mod generated {
+    use super::*;
+
+    include!(concat!(env!("OUT_DIR"), "/board.rs"));
+}
+
+/// ビルド対象のボード
+pub type ActiveBoard = generated::GeneratedBoard;
+
+/// アクティビティ LED の極性（LED の無いボードでは使われません）
+pub const LED_ACTIVE_LOW: bool = match ActiveBoard::LED {
+    Some(led) => led.active_low,
+    None => false,
+};
+
+// 選択したボードの割り当てをコンパイル時に検査します。
+const _: () = check_board::<ActiveBoard>();
+
+/// `gpio` を使用済みとして `used` に記録します（二重の割り当てはコンパイルエラー）
+const fn claim(used: u32, gpio: u8) -> u32 {
+    assert!(
+        (gpio as usize) < GPIO_COUNT,
+        "board file uses a GPIO that does not exist"
+    );
+    assert!(used & (1 << gpio) == 0, "board file assigns a GPIO twice");
+    used | (1 << gpio)
+}
+
+/// `gpio` が `uart` の `signal` に使えることを確かめます
+const fn check_uart_pin(gpio: u8, uart: usize, signal: UartSignal) {
+    let (actual_uart, actual_signal) = uart_function(gpio);
+    assert!(
+        actual_uart == uart && actual_signal as u8 == signal as u8,
+        "board file routes a UART signal to a GPIO without that function"
+    );
+}
+
+/// ボードファイルの割り当てを検査します
+const fn check_board<B: Board>() {
+    let mut used = 0;
+    if let Some(led) = B::LED {
+        used = claim(used, led.pin);
+    }
+    if let Some(rgb) = B::RGB_LED {
+        used = claim(used, rgb.data);
+        if let Some(power) = rgb.power {
+            used = claim(used, power);
+        }
+    }
+    if let Some(button) = B::BUTTON {
+        used = claim(used, button.pin);
+    }
+    let mut uart = 0;
+    while uart < HW_UART_CHANNELS {
+        let flow_control = matches!(UART_FLOW_CONTROL[uart], FlowControl::RtsCts);
+        match B::UART_PINS[uart] {
+            Some(pins) => {
+                check_uart_pin(pins.tx, uart, UartSignal::Tx);
+                check_uart_pin(pins.rx, uart, UartSignal::Rx);
+                used = claim(claim(used, pins.tx), pins.rx);
+                match pins.flow {
+                    Some((cts, rts)) => {
+                        check_uart_pin(cts, uart, UartSignal::Cts);
+                        check_uart_pin(rts, uart, UartSignal::Rts);
+                        used = claim(claim(used, cts), rts);
+                    }
+                    None => assert!(
+                        !flow_control,
+                        "UART_FLOW_CONTROL enables RTS/CTS on a UART without flow control pins"
+                    ),
+                }
+            }
+            None => assert!(
+                !flow_control,
+                "UART_FLOW_CONTROL enables RTS/CTS on a UART without pins"
+            ),
+        }
+        uart += 1;
+    }
+    let mut channel = 0;
+    while channel < PIO_UART_CHANNELS {
+        let (tx, rx) = B::PIO_UART_PINS[channel];
+        used = claim(claim(used, tx), rx);
+        channel += 1;
+    }
+    if let Some(lines) = B::CONTROL_LINES {
+        used = claim(claim(used, lines.dtr), lines.rts);
+    }
+    let _ = used;
+}
+
+/// `init_pins_and_enable_uarts` が返す、Core0 で使用する出力ピン一式
+pub struct BoardPins {
+    /// アクティビティ表示用 LED
+    pub led: Option<crate::DynLedPin>,
+    /// ホストの DTR 信号を反映する GPIO
+    pub dtr: Option<crate::control_lines::ControlLine>,
+    /// ホストの RTS 信号を反映する GPIO
+    pub rts: Option<crate::control_lines::ControlLine>,
+    /// 各 UART で使えるフロー制御（CTS/RTS を UART 機能に切り替えた UART だけ RTS/CTS）
+    pub uart_flow_control: [FlowControl; HW_UART_CHANNELS],
+}
+
 // BSP の再エクスポートモジュールを公開します（src/board/bsp.rs を参照）
 pub mod bsp;
 
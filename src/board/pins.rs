@@ -0,0 +1,236 @@
+//! ボードファイルから生成した `Board` に従ってピンを割り当てます（RP2040 / RP2350 共通）。
+
+use super::hal::gpio::{
+    DynPinId, FunctionNull, FunctionPio0, FunctionUart, Pin, PinState, Pins, PullDown, PullUp,
+};
+use super::hal::pac;
+use super::{Board, BoardPins, GPIO_COUNT, UartPins};
+use crate::HW_UART_CHANNELS;
+use crate::control_lines::ControlLine;
+use crate::uart_config::FlowControl;
+use picoterm_bridge::config::PinSelection;
+
+/// リセット直後の GPIO を番号で扱うための型
+type UnusedPin = Pin<DynPinId, FunctionNull, PullDown>;
+
+/// `Pins` を GPIO 番号で取り出せる配列にします
+fn numbered(pins: Pins) -> [Option<UnusedPin>; GPIO_COUNT] {
+    [
+        Some(pins.gpio0.into_dyn_pin()),
+        Some(pins.gpio1.into_dyn_pin()),
+        Some(pins.gpio2.into_dyn_pin()),
+        Some(pins.gpio3.into_dyn_pin()),
+        Some(pins.gpio4.into_dyn_pin()),
+        Some(pins.gpio5.into_dyn_pin()),
+        Some(pins.gpio6.into_dyn_pin()),
+        Some(pins.gpio7.into_dyn_pin()),
+        Some(pins.gpio8.into_dyn_pin()),
+        Some(pins.gpio9.into_dyn_pin()),
+        Some(pins.gpio10.into_dyn_pin()),
+        Some(pins.gpio11.into_dyn_pin()),
+        Some(pins.gpio12.into_dyn_pin()),
+        Some(pins.gpio13.into_dyn_pin()),
+        Some(pins.gpio14.into_dyn_pin()),
+        Some(pins.gpio15.into_dyn_pin()),
+        Some(pins.gpio16.into_dyn_pin()),
+        Some(pins.gpio17.into_dyn_pin()),
+        Some(pins.gpio18.into_dyn_pin()),
+        Some(pins.gpio19.into_dyn_pin()),
+        Some(pins.gpio20.into_dyn_pin()),
+        Some(pins.gpio21.into_dyn_pin()),
+        Some(pins.gpio22.into_dyn_pin()),
+        Some(pins.gpio23.into_dyn_pin()),
+        Some(pins.gpio24.into_dyn_pin()),
+        Some(pins.gpio25.into_dyn_pin()),
+        Some(pins.gpio26.into_dyn_pin()),
+        Some(pins.gpio27.into_dyn_pin()),
+        Some(pins.gpio28.into_dyn_pin()),
+        Some(pins.gpio29.into_dyn_pin()),
+    ]
+}
+
+/// GPIO `gpio` を取り出します（割り当ての重複はコンパイル時に検査済み）
+fn take(pins: &mut [Option<UnusedPin>; GPIO_COUNT], gpio: u8) -> Result<UnusedPin, &'static str> {
+    pins[usize::from(gpio)].take().ok_or("GPIO assigned twice")
+}
+
+/// GPIO `gpio` を UART 機能に切り替えます
+fn route_uart(pins: &mut [Option<UnusedPin>; GPIO_COUNT], gpio: u8) -> Result<(), &'static str> {
+    take(pins, gpio)?
+        .try_into_function::<FunctionUart>()
+        .map(|_| ())
+        .map_err(|_| "GPIO has no UART function")
+}
+
+/// ボード `B` が UART 以外（PIO UART、LED、ボタン、DTR/RTS）に使う GPIO のビットマスク
+pub fn reserved_gpios<B: Board>() -> u32 {
+    let mut gpios = 0u32;
+    let mut reserve = |gpio: u8| gpios |= 1 << gpio;
+    for &(tx, rx) in B::PIO_UART_PINS.iter() {
+        reserve(tx);
+        reserve(rx);
+    }
+    if let Some(led) = B::LED {
+        reserve(led.pin);
+    }
+    if let Some(rgb) = B::RGB_LED {
+        reserve(rgb.data);
+        if let Some(power) = rgb.power {
+            reserve(power);
+        }
+    }
+    if let Some(button) = B::BUTTON {
+        reserve(button.pin);
+    }
+    if let Some(lines) = B::CONTROL_LINES {
+        reserve(lines.dtr);
+        reserve(lines.rts);
+    }
+    gpios
+}
+
+/// 保存した設定で選んだ UART ピンを検査し、実際に使うピンを返します。
+///
+/// 選んだピンがその UART の機能を持たない、ボードが他の用途に使っている、または
+/// `flow_control` が RTS/CTS なのにフロー制御ピンが無い場合は、その UART だけ
+/// ボードファイルの標準のピンに戻します。
+pub fn resolve_uart_pins<B: Board>(
+    selections: &[PinSelection; HW_UART_CHANNELS],
+    flow_control: &[FlowControl; HW_UART_CHANNELS],
+) -> [Option<UartPins>; HW_UART_CHANNELS] {
+    let reserved = reserved_gpios::<B>();
+    core::array::from_fn(|uart| {
+        let pins = match selections[uart] {
+            PinSelection::BoardDefault => return B::UART_PINS[uart],
+            PinSelection::Unconnected => None,
+            PinSelection::Pins(pins) if pins.validate(uart, reserved).is_ok() => Some(pins),
+            PinSelection::Pins(_) => return B::UART_PINS[uart],
+        };
+        // RTS/CTS を使う UART にはフロー制御ピンが必要
+        let rts_cts = matches!(flow_control[uart], FlowControl::RtsCts);
+        if rts_cts && pins.is_none_or(|pins| pins.flow.is_none()) {
+            B::UART_PINS[uart]
+        } else {
+            pins
+        }
+    })
+}
+
+/// ボード `B` の配線に従ってピンを初期化します。
+///
+/// UART0/UART1、PIO0、DMA のリセットを解除し、各ピンを UART / PIO0 機能に切り替えます。
+/// UART のピンは `uart_pins`（保存した設定、無ければボードファイルの標準）に従い、
+/// `flow_control` が RTS/CTS の UART は CTS/RTS も UART 機能に切り替えます。
+/// UART の通信設定は `uart_core1` が最初の設定時に書き込み、その時点で UART を有効にします。
+/// RGB LED は消灯状態に保ち、ボタンは内部プルを有効にした入力にします。
+pub fn init_pins_and_enable_uarts<B: Board>(
+    pins: Pins,
+    uart_pins: &[PinSelection; HW_UART_CHANNELS],
+    flow_control: &[FlowControl; HW_UART_CHANNELS],
+    resets: &mut pac::RESETS,
+) -> Result<BoardPins, &'static str> {
+    let mut pins = numbered(pins);
+
+    // UART0/UART1 のリセットを解除
+    resets
+        .reset()
+        .modify(|_, w| w.uart0().clear_bit().uart1().clear_bit());
+    while resets.reset_done().read().uart0().bit_is_clear()
+        || resets.reset_done().read().uart1().bit_is_clear()
+    {}
+
+    // UART ピンを設定（ハードウェアフロー制御を使う場合は CTS/RTS も UART 機能に切り替える）
+    let uart_pins = resolve_uart_pins::<B>(uart_pins, flow_control);
+    let mut uart_flow_control = [FlowControl::None; HW_UART_CHANNELS];
+    for ((uart_pins, requested), active) in uart_pins
+        .iter()
+        .zip(flow_control)
+        .zip(&mut uart_flow_control)
+    {
+        let Some(uart_pins) = uart_pins else {
+            continue;
+        };
+        route_uart(&mut pins, uart_pins.tx)?;
+        route_uart(&mut pins, uart_pins.rx)?;
+        if let (FlowControl::RtsCts, Some((cts, rts))) = (requested, uart_pins.flow) {
+            route_uart(&mut pins, cts)?;
+            route_uart(&mut pins, rts)?;
+            *active = FlowControl::RtsCts;
+        }
+    }
+
+    // PIO UART ピンを PIO0 機能に切り替える（RX はアイドル High を保つためプルアップ）
+    for &(tx, rx) in B::PIO_UART_PINS.iter() {
+        take(&mut pins, tx)?
+            .try_into_function::<FunctionPio0>()
+            .map_err(|_| "GPIO has no PIO0 function")?;
+        take(&mut pins, rx)?
+            .into_pull_type::<PullUp>()
+            .try_into_function::<FunctionPio0>()
+            .map_err(|_| "GPIO has no PIO0 function")?;
+    }
+
+    // PIO0 のリセットを解除（プログラムのロードは pio_uart::init で行う）
+    resets.reset().modify(|_, w| w.pio0().clear_bit());
+    while resets.reset_done().read().pio0().bit_is_clear() {}
+
+    // DMA のリセットを解除（チャネルの設定は uart_core1::init_uart_ptrs で行う）
+    resets.reset().modify(|_, w| w.dma().clear_bit());
+    while resets.reset_done().read().dma().bit_is_clear() {}
+
+    // DTR/RTS 出力ピンを非アサート状態で初期化（ターゲットの誤リセットを防ぐ）
+    let (dtr, rts) = match B::CONTROL_LINES {
+        Some(lines) => {
+            let deasserted = if lines.active_low {
+                PinState::High
+            } else {
+                PinState::Low
+            };
+            let dtr = take(&mut pins, lines.dtr)?.into_push_pull_output_in_state(deasserted);
+            let rts = take(&mut pins, lines.rts)?.into_push_pull_output_in_state(deasserted);
+            (
+                Some(ControlLine::new(dtr, lines.active_low)),
+                Some(ControlLine::new(rts, lines.active_low)),
+            )
+        }
+        None => (None, None),
+    };
+
+    // RGB LED は電源とデータ線を Low にして消灯させておく
+    if let Some(rgb) = B::RGB_LED {
+        if let Some(power) = rgb.power {
+            let _power = take(&mut pins, power)?.into_push_pull_output_in_state(PinState::Low);
+        }
+        let _data = take(&mut pins, rgb.data)?.into_push_pull_output_in_state(PinState::Low);
+    }
+
+    // ボタンは押していないときのレベルにプルしておく
+    if let Some(button) = B::BUTTON {
+        let pin = take(&mut pins, button.pin)?;
+        if button.active_low {
+            let _button = pin.into_pull_up_input();
+        } else {
+            let _button = pin.into_pull_down_input();
+        }
+    }
+
+    // LED ピンを消灯状態で取得して返す
+    let led = match B::LED {
+        Some(led) => {
+            let off = if led.active_low {
+                PinState::High
+            } else {
+                PinState::Low
+            };
+            Some(take(&mut pins, led.pin)?.into_push_pull_output_in_state(off))
+        }
+        None => None,
+    };
+
+    Ok(BoardPins {
+        led,
+        dtr,
+        rts,
+        uart_flow_control,
+    })
+}
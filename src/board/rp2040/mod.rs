@@ -1,23 +1,15 @@
 // RP2040 固有のハードウェア設定をまとめるファイルです。
-// 今は外部クリスタルの周波数とピン初期化を提供します。
-
-pub mod usb;
-
-/// 外部クリスタルの周波数（Hz）
-pub const EXTERNAL_XTAL_FREQ_HZ: u32 = 12_000_000;
+// タイマーと USB バスを提供します（ボードごとの配線は `boards/*.toml` にあります）。
 
 use super::hal as bsp_hal;
-use crate::DynLedPin;
-use crate::UART_BAUD_RATE;
-use bsp_hal::gpio::FunctionUart;
-use bsp_hal::gpio::Pins;
 use bsp_hal::pac;
-use bsp_hal::uart::{DataBits, StopBits, UartConfig, UartPeripheral};
-use fugit::RateExtU32;
 
 /// Board-specific Timer alias so `main.rs` can reference the HAL's Timer type.
 pub type BoardTimer = bsp_hal::timer::Timer;
 
+/// これより近い起床時刻はアラームを使わずに割り込みを保留させます（µs）
+const WAKE_MIN_LEAD_US: i32 = 2;
+
 /// Create a board-specific timer instance. `main.rs` calls this to obtain
 /// a concrete `BoardTimer` without depending on HAL generics.
 pub fn make_timer(
@@ -28,28 +20,38 @@ pub fn make_timer(
     bsp_hal::timer::Timer::new(pac_timer, resets, clocks)
 }
 
-/// RP2040 用にピンを初期化し、Core0 側で UART0 を有効化します。
-pub fn init_pins_and_enable_uart(
-    pins: Pins,
-    pac_uart0: pac::UART0,
+/// USB コントローラのバス型（USB デバイス本体は `usb_serial` が共通で持ちます）
+pub type BoardUsbBus = bsp_hal::usb::UsbBus;
+
+/// USB コントローラをデバイスモードで初期化し、`usb_serial::init_usb` に渡すバスを返します。
+pub fn make_usb_bus(
+    usbctrl_regs: pac::USBCTRL_REGS,
+    usbctrl_dpram: pac::USBCTRL_DPRAM,
+    usb_clock: bsp_hal::clocks::UsbClock,
     resets: &mut pac::RESETS,
-    system_freq_hz: u32,
-) -> Result<DynLedPin, &'static str> {
-    // UART ピンを設定
-    let uart_tx = pins.gpio0.into_function::<FunctionUart>();
-    let uart_rx = pins.gpio1.into_function::<FunctionUart>();
-
-    let uart_config = UartConfig::new(UART_BAUD_RATE.Hz(), DataBits::Eight, None, StopBits::One);
-    match UartPeripheral::new(pac_uart0, (uart_tx, uart_rx), resets)
-        .enable(uart_config, system_freq_hz.Hz())
-    {
-        Ok(u) => {
-            let _u = u; // ローカルに束縛して所有権を移動させない
-        }
-        Err(_) => return Err("Failed to enable UART0 via HAL"),
+) -> BoardUsbBus {
+    BoardUsbBus::new(usbctrl_regs, usbctrl_dpram, usb_clock, true, resets)
+}
+
+/// タイマーの現在値（µs）の下位 32 ビットを返します（割り込みからも呼べます）。
+pub fn time_us() -> u32 {
+    unsafe { (*pac::TIMER::ptr()).timerawl().read().bits() }
+}
+
+/// Core0 の起床用アラーム（ALARM0）を `time_us` の時刻 `at` にセットします。
+/// 既に過ぎている時刻なら割り込みを直ちに保留させます。
+pub fn wake_at(at: u32) {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    // ALARM0 は下位 32 ビットの一致で発火するため、過去の時刻は書き込まない
+    if (at.wrapping_sub(time_us()) as i32) < WAKE_MIN_LEAD_US {
+        pac::NVIC::pend(pac::Interrupt::TIMER_IRQ_0);
+        return;
     }
+    timer.inte().modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+    timer.alarm0().write(|w| unsafe { w.bits(at) });
+}
 
-    // LED ピンを取得して返す
-    let led_local = pins.gpio25.into_push_pull_output().into_dyn_pin();
-    Ok(led_local)
+/// ALARM0 の割り込みを解除します（割り込みハンドラから呼びます）。
+pub fn ack_wake() {
+    unsafe { (*pac::TIMER::ptr()).intr().write(|w| w.bits(1)) };
 }
@@ -1,23 +1,15 @@
-// RP2350 board scaffold
-
-pub mod usb;
-
-/// External crystal frequency for RP2350 boards (Hz)
-pub const EXTERNAL_XTAL_FREQ_HZ: u32 = 12_000_000;
+// RP2350 chip support: timer and USB bus (board wiring lives in `boards/*.toml`)
 
 use super::hal as bsp_hal;
-use crate::DynLedPin;
-use crate::UART_BAUD_RATE;
-use bsp_hal::gpio::FunctionUart;
-use bsp_hal::gpio::Pins;
 use bsp_hal::pac;
-use bsp_hal::uart::{DataBits, StopBits, UartConfig, UartPeripheral};
-use fugit::RateExtU32;
 
 // Export a concrete timer type so `main.rs` can refer to `board::BoardTimer`.
 pub type BoardTimer<D> = bsp_hal::timer::Timer<D>;
 use bsp_hal::timer::CopyableTimer0;
 
+/// Wake-up times closer than this pend the interrupt instead of using the alarm (µs)
+const WAKE_MIN_LEAD_US: i32 = 2;
+
 /// Construct the board timer (Timer0 specialized)
 pub fn make_timer(
     pac_timer: pac::TIMER0,
@@ -28,26 +20,38 @@ pub fn make_timer(
     bsp_hal::timer::Timer::new_timer0(pac_timer, resets, clocks)
 }
 
-/// Initialize pins and enable UART0. This is a stub that assumes
-/// rp235x-hal provides similar APIs to rp2040-hal; adjust when testing on hardware.
-pub fn init_pins_and_enable_uart(
-    pins: Pins,
-    pac_uart0: pac::UART0,
+/// Bus type of the USB controller (the USB device itself lives in `usb_serial`)
+pub type BoardUsbBus = bsp_hal::usb::UsbBus;
+
+/// Bring up the USB controller in device mode for `usb_serial::init_usb`
+pub fn make_usb_bus(
+    usb: pac::USB,
+    usb_dpram: pac::USB_DPRAM,
+    usb_clock: bsp_hal::clocks::UsbClock,
     resets: &mut pac::RESETS,
-    system_freq_hz: u32,
-) -> Result<DynLedPin, &'static str> {
-    let uart_tx = pins.gpio0.into_function::<FunctionUart>();
-    let uart_rx = pins.gpio1.into_function::<FunctionUart>();
-
-    let uart_config = UartConfig::new(UART_BAUD_RATE.Hz(), DataBits::Eight, None, StopBits::One);
-    match UartPeripheral::new(pac_uart0, (uart_tx, uart_rx), resets)
-        .enable(uart_config, system_freq_hz.Hz())
-    {
-        Ok(_u) => {}
-        Err(_) => return Err("Failed to enable UART0 via rp235x-hal"),
+) -> BoardUsbBus {
+    BoardUsbBus::new(usb, usb_dpram, usb_clock, true, resets)
+}
+
+/// Low 32 bits of the microsecond timer (usable from interrupts)
+pub fn time_us() -> u32 {
+    unsafe { (*pac::TIMER0::ptr()).timerawl().read().bits() }
+}
+
+/// Arm Core0's wake-up alarm (ALARM0) for `time_us` value `at`. A time that
+/// has already passed pends the interrupt right away.
+pub fn wake_at(at: u32) {
+    let timer = unsafe { &*pac::TIMER0::ptr() };
+    // ALARM0 fires when the low 32 bits match, so never write a past time
+    if (at.wrapping_sub(time_us()) as i32) < WAKE_MIN_LEAD_US {
+        pac::NVIC::pend(pac::Interrupt::TIMER0_IRQ_0);
+        return;
     }
+    timer.inte().modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+    timer.alarm0().write(|w| unsafe { w.bits(at) });
+}
 
-    // Placeholder LED pin — change to actual board LED pin if known
-    let led_local = pins.gpio25.into_push_pull_output().into_dyn_pin();
-    Ok(led_local)
+/// Acknowledge the ALARM0 interrupt (called from its handler)
+pub fn ack_wake() {
+    unsafe { (*pac::TIMER0::ptr()).intr().write(|w| w.bits(1)) };
 }
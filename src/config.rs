@@ -0,0 +1,61 @@
+//! Configuration store in the last two flash sectors, read at boot
+//!
+//! The memory layout leaves `config_store::STORE_SIZE` at the end of the
+//! flash out of FLASH (see `build.rs`), so the firmware never overlaps it.
+//! The store reads it through the XIP window and writes it with `flash`;
+//! without a valid record the default configuration is used, which takes
+//! the board file's pins and strings.
+
+use crate::board::{ActiveBoard, Board};
+use crate::flash;
+use core::sync::atomic::{AtomicBool, Ordering};
+use picoterm_bridge::config::Config;
+use picoterm_bridge::config_store::{ConfigFlash, ConfigStore, RECORD_SIZE, STORE_SIZE};
+
+/// Start of flash in the XIP address space (RP2040 and RP2350)
+const XIP_BASE: usize = 0x1000_0000;
+/// Offset of the store from the start of flash
+const STORE_OFFSET: u32 = ActiveBoard::FLASH_SIZE as u32 - STORE_SIZE;
+
+const _: () = assert!(RECORD_SIZE == flash::PAGE_SIZE);
+
+/// The store's sectors of the board's flash
+pub struct BoardFlash;
+
+impl ConfigFlash for BoardFlash {
+    fn read(&self, offset: u32, buf: &mut [u8]) {
+        let start = XIP_BASE + (STORE_OFFSET + offset) as usize;
+        buf.copy_from_slice(unsafe { core::slice::from_raw_parts(start as *const u8, buf.len()) });
+    }
+
+    fn erase(&mut self, offset: u32) {
+        flash::erase_sector(STORE_OFFSET + offset);
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8; RECORD_SIZE]) {
+        flash::program_page(STORE_OFFSET + offset, data);
+    }
+}
+
+/// The configuration read at boot
+static mut CONFIG: Config = Config::DEFAULT;
+/// Set once `load` has filled in `CONFIG`
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Read the saved configuration, or the default if nothing valid was saved
+///
+/// # Panics
+/// If called more than once
+pub fn load() -> &'static Config {
+    if LOADED.load(Ordering::Relaxed) {
+        panic!("Configuration already loaded");
+    }
+    LOADED.store(true, Ordering::Relaxed);
+
+    let config = ConfigStore::new(BoardFlash).load().unwrap_or_default();
+    unsafe {
+        let config_ptr = core::ptr::addr_of_mut!(CONFIG);
+        *config_ptr = config;
+        &*config_ptr
+    }
+}
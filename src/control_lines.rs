@@ -0,0 +1,89 @@
+//! Host modem control lines (DTR/RTS) mirrored to GPIO outputs
+//!
+//! Lets esptool, avrdude and similar tools toggle a target's reset/boot pins
+//! through picoterm exactly as through an FTDI/CP210x adapter. The pins and
+//! their polarity come from the board file (`boards/*.toml`). The escape
+//! menu's target reset holds RTS asserted for a moment on top of that.
+
+use crate::DynOutputPin;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::Mutex;
+use embedded_hal::digital::v2::OutputPin;
+
+/// One GPIO following a host control line
+pub struct ControlLine {
+    pin: DynOutputPin,
+    active_low: bool,
+    asserted: bool,
+}
+
+impl ControlLine {
+    /// Wrap a pin that the board has already driven to the deasserted level
+    pub fn new(pin: DynOutputPin, active_low: bool) -> Self {
+        Self {
+            pin,
+            active_low,
+            asserted: false,
+        }
+    }
+
+    /// Drive the pin to reflect the given line state
+    pub fn set(&mut self, asserted: bool) {
+        if asserted == self.asserted {
+            return;
+        }
+        self.asserted = asserted;
+
+        if asserted != self.active_low {
+            let _ = self.pin.set_high();
+        } else {
+            let _ = self.pin.set_low();
+        }
+    }
+}
+
+static DTR_LINE: Mutex<RefCell<Option<ControlLine>>> = Mutex::new(RefCell::new(None));
+static RTS_LINE: Mutex<RefCell<Option<ControlLine>>> = Mutex::new(RefCell::new(None));
+/// RTS as the host last set it
+static HOST_RTS: AtomicBool = AtomicBool::new(false);
+/// Set while a target reset holds RTS asserted
+static RESET_HELD: AtomicBool = AtomicBool::new(false);
+
+/// Install the DTR and RTS output lines (called once from Core0; `None` on
+/// boards without them)
+pub fn init(dtr: Option<ControlLine>, rts: Option<ControlLine>) {
+    cortex_m::interrupt::free(|cs| {
+        *DTR_LINE.borrow(cs).borrow_mut() = dtr;
+        *RTS_LINE.borrow(cs).borrow_mut() = rts;
+    });
+}
+
+/// Mirror the host's DTR/RTS state onto the GPIO outputs
+pub fn update(dtr: bool, rts: bool) {
+    HOST_RTS.store(rts, Ordering::Relaxed);
+    cortex_m::interrupt::free(|cs| {
+        if let Some(ref mut line) = DTR_LINE.borrow(cs).borrow_mut().as_mut() {
+            line.set(dtr);
+        }
+        if let Some(ref mut line) = RTS_LINE.borrow(cs).borrow_mut().as_mut() {
+            line.set(rts || RESET_HELD.load(Ordering::Relaxed));
+        }
+    });
+}
+
+/// True if the board has an RTS output to reset the target with
+pub fn has_reset_line() -> bool {
+    cortex_m::interrupt::free(|cs| RTS_LINE.borrow(cs).borrow().is_some())
+}
+
+/// Hold RTS asserted, which resets an ESP32-style target through EN, or let
+/// it follow the host again
+pub fn hold_reset(asserted: bool) {
+    RESET_HELD.store(asserted, Ordering::Relaxed);
+    cortex_m::interrupt::free(|cs| {
+        if let Some(ref mut line) = RTS_LINE.borrow(cs).borrow_mut().as_mut() {
+            line.set(asserted || HOST_RTS.load(Ordering::Relaxed));
+        }
+    });
+}
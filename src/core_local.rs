@@ -0,0 +1,80 @@
+//! Per-core ownership of the inter-core FIFO halves
+//!
+//! Each `ByteRing` between the cores is split once at boot. The halves a
+//! core uses are handed to that core and only ever touched there:
+//! Core0 keeps the CDC→UART producers and UART→CDC consumers, Core1 (which
+//! receives its halves through the spawn closure) the other two.
+//!
+//! `CoreLocal` is the home of such halves. It records its owning core and
+//! refuses access from the other one, so masking interrupts on the calling
+//! core is all the exclusion it needs: that only guards against the owning
+//! core's own interrupt handlers, which is exactly who else can reach it.
+//! Nothing here is shared between the cores, so no hardware spinlock is
+//! taken.
+//!
+//! # Memory ordering
+//!
+//! The only state both cores touch is inside the rings: the buffer, the
+//! head index (written only by the consumer) and the tail index (written
+//! only by the producer). The producer writes the bytes and then stores the
+//! tail with `Release`; the consumer loads the tail with `Acquire` before
+//! reading the bytes, and the mirror image holds for the head. These are
+//! plain loads and stores, which is all the Cortex-M0+ provides.
+//!
+//! - RP2040 (Cortex-M0+): both cores see SRAM through the same bus fabric
+//!   without caches and each core issues its memory accesses in program
+//!   order, so the orderings only need to stop the compiler from reordering
+//!   (which `Acquire`/`Release` do; the compiler also emits `dmb`).
+//! - RP2350 (Cortex-M33): the core may buffer stores, so the `dmb` emitted
+//!   for `Acquire`/`Release` is what makes the data visible before the
+//!   index. SRAM is not cached, so nothing more is required.
+
+use crate::board::hal::sio::{CoreId, Sio};
+use core::cell::RefCell;
+
+/// A value owned by one core and only accessed from that core
+pub struct CoreLocal<T> {
+    owner: CoreId,
+    value: RefCell<Option<T>>,
+}
+
+// SAFETY: `with` and `install` only touch the value on the owning core with
+// its interrupts masked, so accesses never overlap.
+unsafe impl<T: Send> Sync for CoreLocal<T> {}
+
+impl<T> CoreLocal<T> {
+    /// An empty value owned by `owner`
+    pub const fn new(owner: CoreId) -> Self {
+        Self {
+            owner,
+            value: RefCell::new(None),
+        }
+    }
+
+    /// Hand the value to the owning core (must be called on that core)
+    pub fn install(&self, value: T) {
+        self.check_core();
+        cortex_m::interrupt::free(|_| {
+            *self.value.borrow_mut() = Some(value);
+        });
+    }
+
+    /// Run `f` on the value with this core's interrupts masked. Panics if
+    /// called from the other core or before `install`.
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.check_core();
+        cortex_m::interrupt::free(|_| {
+            let mut value = self.value.borrow_mut();
+            f(value.as_mut().expect("core-local value not installed"))
+        })
+    }
+
+    #[inline]
+    fn check_core(&self) {
+        assert!(
+            Sio::core() == self.owner,
+            "core-local value used on the wrong core"
+        );
+    }
+}
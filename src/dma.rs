@@ -0,0 +1,173 @@
+//! Minimal DMA channel access for the Core1 UART paths
+//!
+//! Like `uart_core1`, this works on the raw registers so Core1 does not need
+//! HAL objects. Only what the UART paths use is provided: byte-wide, DREQ
+//! paced transfers, optionally into a ring on the write side or chained to a
+//! second channel, and the completion interrupt on DMA_IRQ_0.
+
+use crate::pac;
+
+/// CTRL_TRIG register bit layout (differs between RP2040 and RP2350)
+#[cfg(feature = "rp2040")]
+mod ctrl {
+    pub const INCR_READ: u32 = 1 << 4;
+    pub const INCR_WRITE: u32 = 1 << 5;
+    pub const RING_SIZE_SHIFT: u32 = 6;
+    pub const RING_SEL_WRITE: u32 = 1 << 10;
+    pub const CHAIN_TO_SHIFT: u32 = 11;
+    pub const TREQ_SEL_SHIFT: u32 = 15;
+    pub const BUSY: u32 = 1 << 24;
+}
+
+/// CTRL_TRIG register bit layout (differs between RP2040 and RP2350)
+#[cfg(feature = "rp2350")]
+mod ctrl {
+    pub const INCR_READ: u32 = 1 << 4;
+    pub const INCR_WRITE: u32 = 1 << 6;
+    pub const RING_SIZE_SHIFT: u32 = 8;
+    pub const RING_SEL_WRITE: u32 = 1 << 12;
+    pub const CHAIN_TO_SHIFT: u32 = 13;
+    pub const TREQ_SEL_SHIFT: u32 = 17;
+    pub const BUSY: u32 = 1 << 26;
+}
+
+/// CTRL_TRIG channel enable bit
+const CTRL_EN_BIT: u32 = 1 << 0;
+
+/// Transfer count for a channel that should run until it is stopped. On the
+/// RP2040 the largest count lasts over an hour at 1 Mbaud and the channel is
+/// restarted when it runs out. The RP2350 re-triggers the channel itself
+/// (TRIGGER_SELF) rather than using its endless mode, which would stop the
+/// count the UART RX path reads.
+#[cfg(feature = "rp2040")]
+pub const ENDLESS: u32 = u32::MAX;
+#[cfg(feature = "rp2350")]
+pub const ENDLESS: u32 = (0x1 << 28) | COUNT_MASK;
+
+/// Transfer count bits of TRANS_COUNT (the RP2350 keeps the mode above them)
+#[cfg(feature = "rp2040")]
+pub const COUNT_MASK: u32 = u32::MAX;
+#[cfg(feature = "rp2350")]
+pub const COUNT_MASK: u32 = 0x0FFF_FFFF;
+
+/// DREQ numbers of the UART TX/RX FIFOs, indexed by UART
+#[cfg(feature = "rp2040")]
+pub const DREQ_UART_TX: [u32; 2] = [20, 22];
+#[cfg(feature = "rp2040")]
+pub const DREQ_UART_RX: [u32; 2] = [21, 23];
+#[cfg(feature = "rp2350")]
+pub const DREQ_UART_TX: [u32; 2] = [28, 30];
+#[cfg(feature = "rp2350")]
+pub const DREQ_UART_RX: [u32; 2] = [29, 31];
+
+/// How the addresses of a transfer advance
+#[derive(Clone, Copy)]
+pub enum Mode {
+    /// Memory to peripheral: the read address increments
+    ToPeripheral,
+    /// Peripheral to memory, wrapping the write address inside an aligned
+    /// ring of `1 << ring_bits` bytes
+    FromPeripheralRing { ring_bits: u32 },
+}
+
+/// CTRL value of an enabled byte-wide transfer
+fn control(mode: Mode, dreq: u32, chain_to: Channel) -> u32 {
+    let ctrl = CTRL_EN_BIT
+        | ((chain_to.0 as u32) << ctrl::CHAIN_TO_SHIFT)
+        | (dreq << ctrl::TREQ_SEL_SHIFT);
+    ctrl | match mode {
+        Mode::ToPeripheral => ctrl::INCR_READ,
+        Mode::FromPeripheralRing { ring_bits } => {
+            ctrl::INCR_WRITE | ctrl::RING_SEL_WRITE | (ring_bits << ctrl::RING_SIZE_SHIFT)
+        }
+    }
+}
+
+/// One DMA channel, identified by its number
+#[derive(Clone, Copy)]
+pub struct Channel(pub usize);
+
+/// DMA register block
+#[inline]
+fn dma() -> &'static pac::dma::RegisterBlock {
+    unsafe { &*pac::DMA::ptr() }
+}
+
+impl Channel {
+    /// True while the channel has a transfer in progress
+    #[inline]
+    pub fn is_busy(self) -> bool {
+        (dma().ch(self.0).ch_ctrl_trig().read().bits() & ctrl::BUSY) != 0
+    }
+
+    /// Current write address (the position reached in a ring)
+    #[inline]
+    pub fn write_addr(self) -> u32 {
+        dma().ch(self.0).ch_write_addr().read().bits()
+    }
+
+    /// Current read address (how far a transfer from memory has got)
+    #[inline]
+    pub fn read_addr(self) -> u32 {
+        dma().ch(self.0).ch_read_addr().read().bits()
+    }
+
+    /// Transfers left until the count runs out; counts down as data moves
+    #[inline]
+    pub fn remaining(self) -> u32 {
+        dma().ch(self.0).ch_trans_count().read().bits() & COUNT_MASK
+    }
+
+    /// Start a byte-wide transfer of `count` bytes paced by `dreq`
+    pub fn start(self, read: u32, write: u32, count: u32, mode: Mode, dreq: u32) {
+        self.start_then(read, write, count, mode, dreq, self);
+    }
+
+    /// Like `start`, and trigger `next` when the transfer completes
+    /// (`next` being this channel disables chaining)
+    pub fn start_then(
+        self,
+        read: u32,
+        write: u32,
+        count: u32,
+        mode: Mode,
+        dreq: u32,
+        next: Channel,
+    ) {
+        let ch = dma().ch(self.0);
+        self.load_addresses(read, write, count);
+        ch.ch_ctrl_trig()
+            .write(|w| unsafe { w.bits(control(mode, dreq, next)) });
+    }
+
+    /// Set up a transfer like `start` without starting it; it starts when
+    /// another channel chains to this one
+    pub fn load(self, read: u32, write: u32, count: u32, mode: Mode, dreq: u32) {
+        let ch = dma().ch(self.0);
+        self.load_addresses(read, write, count);
+        ch.ch_al1_ctrl()
+            .write(|w| unsafe { w.bits(control(mode, dreq, self)) });
+    }
+
+    /// Write the registers of a transfer that do not trigger it
+    fn load_addresses(self, read: u32, write: u32, count: u32) {
+        let ch = dma().ch(self.0);
+        ch.ch_read_addr().write(|w| unsafe { w.bits(read) });
+        ch.ch_write_addr().write(|w| unsafe { w.bits(write) });
+        ch.ch_trans_count().write(|w| unsafe { w.bits(count) });
+    }
+
+    /// Route this channel's completion interrupt to DMA_IRQ_0
+    pub fn enable_irq0(self) {
+        let inte = dma().inte0().read().bits();
+        dma()
+            .inte0()
+            .write(|w| unsafe { w.bits(inte | (1 << self.0)) });
+    }
+
+    /// Acknowledge a pending completion interrupt on DMA_IRQ_0
+    #[inline]
+    pub fn ack_irq0(self) {
+        dma().ints0().write(|w| unsafe { w.bits(1 << self.0) });
+    }
+}
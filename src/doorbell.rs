@@ -0,0 +1,42 @@
+//! Core1 → Core0 wake-up through the SIO inter-core FIFO
+//!
+//! Core0 sleeps in WFI between USB interrupts, so SEV cannot wake it. When
+//! Core1 has moved data or seen line errors, it pushes a word into the FIFO
+//! towards Core0, which raises Core0's SIO FIFO interrupt. The word itself
+//! carries no meaning. The FIFO is only used this way after Core1 has been
+//! spawned (the launch handshake also runs through it).
+
+use crate::pac;
+
+/// FIFO_ST register: the RX FIFO holds data
+const FIFO_ST_VLD_BIT: u32 = 1 << 0;
+/// FIFO_ST register: the TX FIFO has room
+const FIFO_ST_RDY_BIT: u32 = 1 << 1;
+/// FIFO_ST register: write overflow / read underflow flags (write 1 to clear)
+const FIFO_ST_ERROR_BITS: u32 = (1 << 2) | (1 << 3);
+
+#[inline]
+fn sio() -> &'static pac::sio::RegisterBlock {
+    unsafe { &*pac::SIO::ptr() }
+}
+
+/// Wake Core0 (called from Core1). If the FIFO is full, Core0 already has
+/// a wake-up pending.
+#[inline]
+pub fn ring() {
+    let sio = sio();
+    if sio.fifo_st().read().bits() & FIFO_ST_RDY_BIT != 0 {
+        sio.fifo_wr().write(|w| unsafe { w.bits(0) });
+    }
+}
+
+/// Acknowledge all wake-ups (called from Core0's SIO FIFO interrupt)
+pub fn clear() {
+    let sio = sio();
+    while sio.fifo_st().read().bits() & FIFO_ST_VLD_BIT != 0 {
+        let _ = sio.fifo_rd().read().bits();
+    }
+    // The error flags would keep the interrupt asserted
+    sio.fifo_st()
+        .write(|w| unsafe { w.bits(FIFO_ST_ERROR_BITS) });
+}
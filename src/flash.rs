@@ -0,0 +1,221 @@
+//! Erasing and programming the flash the firmware runs from
+//!
+//! While the flash is busy it cannot be read through XIP, so for the length
+//! of an operation neither core may run code or read data from flash:
+//!
+//! - Core1 is parked in a RAM loop with its interrupts disabled. Core0 asks
+//!   with `PAUSE_REQUESTED` (and SEV), Core1's main loop sees
+//!   `pause_requested` and calls `park_core1`, which reports back through
+//!   `CORE1_PARKED` once it runs from RAM.
+//! - Core0 disables its interrupts and runs the whole operation in
+//!   `run_in_ram`: the boot ROM routines that take the flash out of XIP,
+//!   erase or program it, flush the XIP cache and bring XIP back. Their
+//!   addresses are looked up before XIP is switched off.
+//!
+//! XIP comes back from the ROM in its slow generic read mode; the fast
+//! mode set up at boot is restored afterwards, by calling a RAM copy of
+//! boot2 on the RP2040 and by rewriting the saved QMI window registers on
+//! the RP2350.
+//!
+//! The DMA keeps filling the UART RX rings meanwhile, but nothing empties
+//! them: a sector erase takes tens of milliseconds, so at high baud rates
+//! incoming data can be lost while the configuration is saved.
+
+use crate::board::hal::rom_data;
+use core::sync::atomic::{AtomicBool, Ordering};
+use picoterm_bridge::config_store::SECTOR_SIZE;
+
+/// Flash page, the unit `program_page` writes
+pub const PAGE_SIZE: usize = 256;
+/// Erase in 64KiB blocks where possible, with the 64KiB block erase command
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+
+/// Set by Core0 while it needs Core1 out of flash
+static PAUSE_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by Core1 while it waits in `park_core1`
+static CORE1_PARKED: AtomicBool = AtomicBool::new(false);
+
+/// True if Core0 is waiting for Core1 to call `park_core1`
+#[inline]
+pub fn pause_requested() -> bool {
+    PAUSE_REQUESTED.load(Ordering::Acquire)
+}
+
+/// Wait in RAM until Core0 has finished with the flash (Core1, with
+/// interrupts disabled, when `pause_requested`)
+///
+/// Written in assembly so that nothing, not even a compiler helper, is
+/// fetched from flash between reporting parked and being released.
+#[unsafe(link_section = ".data.ram_func")]
+#[inline(never)]
+pub fn park_core1() {
+    unsafe {
+        core::arch::asm!(
+            "movs {tmp}, #1",
+            "dmb",
+            "strb {tmp}, [{parked}]",
+            "2:",
+            "ldrb {tmp}, [{requested}]",
+            "cmp {tmp}, #0",
+            "bne 2b",
+            "dmb",
+            "strb {tmp}, [{parked}]",
+            parked = in(reg) CORE1_PARKED.as_ptr(),
+            requested = in(reg) PAUSE_REQUESTED.as_ptr(),
+            tmp = out(reg) _,
+        );
+    }
+}
+
+/// The ROM routines and arguments of one flash operation, gathered while
+/// XIP still works. `run_in_ram` relies on the field offsets.
+#[repr(C)]
+struct FlashOp {
+    /// 0: `connect_internal_flash`
+    connect_internal_flash: usize,
+    /// 4: `flash_exit_xip`
+    exit_xip: usize,
+    /// 8: `flash_range_erase` or `flash_range_program`
+    operation: usize,
+    /// 12: its arguments, passed in r0-r3
+    args: [usize; 4],
+    /// 28: `flash_flush_cache`
+    flush_cache: usize,
+    /// 32: `flash_enter_cmd_xip`
+    enter_cmd_xip: usize,
+    /// 36: Thumb address of the RAM copy of boot2 (RP2040), or 0
+    boot2: usize,
+    /// 40: address of QMI M0_TIMING, followed by M0_RFMT and M0_RCMD
+    /// (RP2350), or 0
+    qmi_m0: usize,
+    /// 44: the values of those three registers before the operation
+    qmi_m0_values: [u32; 3],
+}
+
+/// Carry out `op` with XIP switched off (Core0, interrupts disabled, Core1
+/// parked)
+#[unsafe(link_section = ".data.ram_func")]
+#[inline(never)]
+unsafe fn run_in_ram(op: &FlashOp) {
+    unsafe {
+        core::arch::asm!(
+            "ldr r0, [r4, #0]",
+            "blx r0",
+            "ldr r0, [r4, #4]",
+            "blx r0",
+            "ldr r0, [r4, #12]",
+            "ldr r1, [r4, #16]",
+            "ldr r2, [r4, #20]",
+            "ldr r3, [r4, #24]",
+            "ldr r5, [r4, #8]",
+            "blx r5",
+            "ldr r0, [r4, #28]",
+            "blx r0",
+            "ldr r0, [r4, #32]",
+            "blx r0",
+            // RP2040: boot2 sets the fast read mode up again
+            "ldr r0, [r4, #36]",
+            "cmp r0, #0",
+            "beq 2f",
+            "blx r0",
+            "2:",
+            // RP2350: put the QMI window's timing and read format back
+            "ldr r0, [r4, #40]",
+            "cmp r0, #0",
+            "beq 3f",
+            "ldr r1, [r4, #44]",
+            "str r1, [r0, #0]",
+            "ldr r1, [r4, #48]",
+            "str r1, [r0, #4]",
+            "ldr r1, [r4, #52]",
+            "str r1, [r0, #8]",
+            "3:",
+            in("r4") op,
+            out("r5") _,
+            clobber_abi("C"),
+        );
+    }
+}
+
+/// Boot2 copied to RAM, called after an operation to restore fast XIP
+#[cfg(feature = "rp2040")]
+static mut BOOT2_COPY: [u32; 64] = [0; 64];
+
+impl FlashOp {
+    /// `operation` with `args`, plus the chip's way back to fast XIP
+    fn new(operation: usize, args: [usize; 4]) -> Self {
+        let mut op = Self {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr() as usize,
+            exit_xip: rom_data::flash_exit_xip::ptr() as usize,
+            operation,
+            args,
+            flush_cache: rom_data::flash_flush_cache::ptr() as usize,
+            enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr() as usize,
+            boot2: 0,
+            qmi_m0: 0,
+            qmi_m0_values: [0; 3],
+        };
+
+        // Boot2 is the first 256 bytes of flash
+        #[cfg(feature = "rp2040")]
+        unsafe {
+            let copy = &mut *core::ptr::addr_of_mut!(BOOT2_COPY);
+            for (index, word) in copy.iter_mut().enumerate() {
+                *word = core::ptr::read_volatile((0x1000_0000 as *const u32).add(index));
+            }
+            op.boot2 = copy.as_ptr() as usize | 1;
+        }
+
+        #[cfg(feature = "rp2350")]
+        unsafe {
+            let qmi = &*crate::board::hal::pac::QMI::ptr();
+            op.qmi_m0 = qmi.m0_timing().as_ptr() as usize;
+            op.qmi_m0_values = [
+                qmi.m0_timing().read().bits(),
+                qmi.m0_rfmt().read().bits(),
+                qmi.m0_rcmd().read().bits(),
+            ];
+        }
+
+        op
+    }
+}
+
+/// Run `op` with Core1 parked and Core0's interrupts disabled (Core0, with
+/// Core1 running its main loop)
+fn execute(op: &FlashOp) {
+    PAUSE_REQUESTED.store(true, Ordering::Release);
+    cortex_m::asm::sev();
+    while !CORE1_PARKED.load(Ordering::Acquire) {}
+
+    cortex_m::interrupt::free(|_| unsafe { run_in_ram(op) });
+
+    PAUSE_REQUESTED.store(false, Ordering::Release);
+    while CORE1_PARKED.load(Ordering::Acquire) {}
+}
+
+/// Erase the 4KiB sector at `offset` from the start of flash
+pub fn erase_sector(offset: u32) {
+    let op = FlashOp::new(
+        rom_data::flash_range_erase::ptr() as usize,
+        [
+            offset as usize,
+            SECTOR_SIZE as usize,
+            BLOCK_SIZE as usize,
+            usize::from(BLOCK_ERASE_CMD),
+        ],
+    );
+    execute(&op);
+}
+
+/// Program the erased page at `offset` from the start of flash
+pub fn program_page(offset: u32, data: &[u8; PAGE_SIZE]) {
+    // The ROM reads the data while XIP is off, so it must not be in flash
+    let page = *data;
+    let op = FlashOp::new(
+        rom_data::flash_range_program::ptr() as usize,
+        [offset as usize, page.as_ptr() as usize, PAGE_SIZE, 0],
+    );
+    execute(&op);
+}
@@ -2,20 +2,63 @@
 #![no_main]
 
 //! Dual-core firmware: Core0 handles USB, Core1 handles UART.
-//! Communication between cores uses lock-free SPSC FIFOs.
+//! Communication between cores uses lock-free SPSC FIFOs; each core owns its
+//! halves of them (see `core_local`).
+//! UART0, UART1 and the PIO UARTs are bridged to one CDC-ACM interface
+//! each of a composite USB device; each bridge channel has its own pair of
+//! FIFOs. A fifth CDC-ACM interface runs the configuration shell.
 
 mod board;
+mod config;
+mod control_lines;
+mod core_local;
+mod dma;
+mod doorbell;
+mod flash;
+mod pio_uart;
+mod settings;
 mod uart_core1;
 mod usb_serial;
 
-use board::EXTERNAL_XTAL_FREQ_HZ;
 use board::hal as bsp_hal;
+use board::{ActiveBoard, Board};
 use embedded_hal::digital::v2::OutputPin;
+use picoterm_bridge::config::{Config, LedMode};
+use picoterm_bridge::escape::EscapeKey;
+use picoterm_bridge::{byte_ring, cdc_acm, uart_config};
+use uart_config::{FlowControl, LineConfig};
+/// Power-on baud rate of channels without one in the saved configuration
 const UART_BAUD_RATE: u32 = 115_200;
+/// Number of hardware UART channels (UART0 and UART1)
+const HW_UART_CHANNELS: usize = 2;
+/// Number of PIO UART channels, following the hardware UARTs
+const PIO_UART_CHANNELS: usize = 2;
+/// Number of bridge channels (one CDC-ACM interface each)
+const UART_CHANNELS: usize = HW_UART_CHANNELS + PIO_UART_CHANNELS;
+/// Flow control of hardware UART channels without one in the saved configuration
+const UART_FLOW_CONTROL: [FlowControl; HW_UART_CHANNELS] = [FlowControl::None, FlowControl::None];
+/// UART→USB data is held back until a packet fills or RX has been idle this long (µs)
+const UART_LATENCY_TIMER_US: u32 = 1000;
+/// Received character that sends the UART→USB data right away (e.g. `Some(b'\n')`)
+const UART_EVENT_CHAR: Option<u8> = None;
+/// Typed sequence that opens the on-device menu of a data port: `~` `.` at
+/// the start of a line, or e.g. `Some(EscapeKey::Control(0x1D))` for Ctrl-]
+/// (`None`: everything goes to the UART)
+const UART_ESCAPE: Option<EscapeKey> = Some(EscapeKey::Tilde);
+/// Length of a break sent from the escape menu (ms)
+const ESCAPE_BREAK_MS: u16 = 250;
+/// How long the escape menu's target reset holds RTS asserted (ms)
+const TARGET_RESET_MS: u64 = 100;
+/// Size of each inter-core FIFO (must be a power of two)
 const FIFO_BUFFER_SIZE: usize = 16384;
 const CORE1_STACK_SIZE: usize = 1024;
+/// Time the shell's reply to `reboot` gets to reach the host (µs)
+const REBOOT_DELAY_US: u64 = 100_000;
+/// How long the LED stays on after activity in `LedMode::Activity` (µs)
+const LED_ACTIVITY_US: u64 = 10_000;
 
 use board::entry;
+use cortex_m_rt::exception;
 use defmt_rtt as _;
 use panic_probe as _;
 
@@ -23,31 +66,45 @@ use bsp_hal::{
     clocks::{Clock, init_clocks_and_plls},
     gpio::{DynPinId, FunctionSio, Pin, Pins, PullDown, SioOutput},
     multicore::Multicore,
-    pac,
-    sio::Sio,
+    pac::{self, interrupt},
+    sio::{CoreId, Sio},
     watchdog::Watchdog,
 };
 
-type DynLedPin = Pin<DynPinId, FunctionSio<SioOutput>, PullDown>;
+type DynOutputPin = Pin<DynPinId, FunctionSio<SioOutput>, PullDown>;
+type DynLedPin = DynOutputPin;
 
-use core::cell::RefCell;
+use byte_ring::{ByteRing, Consumer, Producer};
+use core::cell::{Cell, RefCell};
 use core::sync::atomic::{AtomicBool, Ordering};
+use core_local::CoreLocal;
 use cortex_m::interrupt::Mutex;
-use heapless::spsc::{Consumer, Producer, Queue};
+use uart_core1::{BridgePort, PortIo};
 
-type AppResult<T> = Result<T, &'static str>;
+type FifoProducer = Producer<'static, FIFO_BUFFER_SIZE>;
+type FifoConsumer = Consumer<'static, FIFO_BUFFER_SIZE>;
 
-static mut CDC_TO_UART_QUEUE: Queue<u8, FIFO_BUFFER_SIZE> = Queue::new();
-static CDC_TO_UART_PRODUCER: Mutex<RefCell<Option<Producer<u8, FIFO_BUFFER_SIZE>>>> =
-    Mutex::new(RefCell::new(None));
-static CDC_TO_UART_CONSUMER: Mutex<RefCell<Option<Consumer<'static, u8, FIFO_BUFFER_SIZE>>>> =
-    Mutex::new(RefCell::new(None));
+static mut CDC_TO_UART_QUEUES: [ByteRing<FIFO_BUFFER_SIZE>; UART_CHANNELS] =
+    [const { ByteRing::new() }; UART_CHANNELS];
+static mut UART_TO_CDC_QUEUES: [ByteRing<FIFO_BUFFER_SIZE>; UART_CHANNELS] =
+    [const { ByteRing::new() }; UART_CHANNELS];
 
-static mut UART_TO_CDC_QUEUE: Queue<u8, FIFO_BUFFER_SIZE> = Queue::new();
-static UART_TO_CDC_PRODUCER: Mutex<RefCell<Option<Producer<u8, FIFO_BUFFER_SIZE>>>> =
-    Mutex::new(RefCell::new(None));
-static UART_TO_CDC_CONSUMER: Mutex<RefCell<Option<Consumer<'static, u8, FIFO_BUFFER_SIZE>>>> =
-    Mutex::new(RefCell::new(None));
+/// Core0's halves of one channel's FIFOs
+struct Core0Fifos {
+    cdc_to_uart: FifoProducer,
+    uart_to_cdc: FifoConsumer,
+}
+
+/// Core1's halves of one channel's FIFOs
+struct Core1Fifos {
+    cdc_to_uart: FifoConsumer,
+    uart_to_cdc: FifoProducer,
+}
+
+/// FIFO halves used by Core0 (USB), per channel
+static CORE0_FIFOS: CoreLocal<[Core0Fifos; UART_CHANNELS]> = CoreLocal::new(CoreId::Core0);
+/// FIFO halves used by Core1 (UART), per channel
+static CORE1_FIFOS: CoreLocal<[Core1Fifos; UART_CHANNELS]> = CoreLocal::new(CoreId::Core1);
 
 static mut CORE1_STACK: bsp_hal::multicore::Stack<CORE1_STACK_SIZE> =
     bsp_hal::multicore::Stack::new();
@@ -55,98 +112,269 @@ static mut CORE1_STACK: bsp_hal::multicore::Stack<CORE1_STACK_SIZE> =
 static LED_PIN: Mutex<RefCell<Option<DynLedPin>>> = Mutex::new(RefCell::new(None));
 static LED_STATE: AtomicBool = AtomicBool::new(false);
 static mut LED_LAST_ACTIVITY_US: u64 = 0;
-pub static USB_EVENT: AtomicBool = AtomicBool::new(false);
-
-struct Core1Data;
-static CORE1_DATA: Mutex<RefCell<Option<Core1Data>>> = Mutex::new(RefCell::new(None));
+/// Set by the USB interrupts when data moved or the host sent a request;
+/// the main loop takes it to drive the activity LED
+static USB_EVENT: AtomicBool = AtomicBool::new(false);
+/// When (`board::time_us`) the main loop's next deadline is due, if it has one
+static LOOP_DEADLINE: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
 
+/// Switch the activity LED (boards without one only track the state)
 fn set_led_state(state: bool) {
     cortex_m::interrupt::free(|cs| {
         if let Some(ref mut led) = LED_PIN.borrow(cs).borrow_mut().as_mut() {
-            if state {
+            if state != board::LED_ACTIVE_LOW {
                 let _ = led.set_high().is_ok();
             } else {
                 let _ = led.set_low().is_ok();
             }
-            LED_STATE.store(state, Ordering::Relaxed);
-        } else {
-            core::panic!("LED_PIN initialization failed");
         }
+        LED_STATE.store(state, Ordering::Relaxed);
     });
 }
 
-fn process_received_byte(byte: u8) {
-    cortex_m::interrupt::free(|cs| {
-        if let Some(ref mut producer) = UART_TO_CDC_PRODUCER.borrow(cs).borrow_mut().as_mut() {
-            match producer.enqueue(byte) {
-                Ok(()) => {}
-                Err(_) => {
-                    LED_STATE.store(true, Ordering::Relaxed);
-                }
-            }
-        } else {
-            core::panic!("USB producer not initialized");
-        }
+/// Line configuration of `channel` at power-on: 8N1 at the saved baud rate
+fn power_on_line_config(config: &Config, channel: usize) -> LineConfig {
+    LineConfig::eight_n_one(config.baud[channel].unwrap_or(UART_BAUD_RATE))
+}
+
+/// Flow control of hardware UART `uart` at power-on: the saved mode, or
+/// `UART_FLOW_CONTROL`
+fn power_on_flow_control(config: &Config, uart: usize) -> FlowControl {
+    config.flow_control[uart].unwrap_or(UART_FLOW_CONTROL[uart])
+}
+
+/// Bridge port of `channel`: a hardware UART or a PIO UART
+fn bridge_port(channel: usize) -> &'static dyn BridgePort {
+    if channel < HW_UART_CHANNELS {
+        &uart_core1::PORTS[channel]
+    } else {
+        &pio_uart::PIO_PORTS[channel - HW_UART_CHANNELS]
+    }
+}
+
+/// Move data between one UART and its pair of FIFOs, then re-arm the
+/// port for whatever is left to do.
+///
+/// Runs on Core1, either from the port's interrupt handler or from
+/// `core1_task` with interrupts disabled. Data is copied directly between
+/// the FIFOs' contiguous regions and the port (see `picoterm_bridge::pump`).
+fn service_port(channel: usize, port: &impl BridgePort) {
+    let errors = port.line_errors();
+    let (rx, tx) = CORE1_FIFOS.with(|fifos| {
+        let fifos = &mut fifos[channel];
+        let rx = picoterm_bridge::pump::uart_to_host(&mut PortIo(port), &mut fifos.uart_to_cdc);
+        let tx = port.transmit(&mut fifos.cdc_to_uart);
+        (rx, tx)
     });
+
+    // Core0 has new data to send, room for the host's data or line errors
+    // to report
+    if rx.moved || tx.moved || port.line_errors() != errors {
+        doorbell::ring();
+    }
+
+    port.arm(!rx.stalled, tx.stalled);
+}
+
+#[interrupt]
+fn UART0_IRQ() {
+    service_port(0, &uart_core1::PORTS[0]);
+}
+
+#[interrupt]
+fn UART1_IRQ() {
+    service_port(1, &uart_core1::PORTS[1]);
+}
+
+/// Run the USB stack and move data between the CDC functions and the FIFOs,
+/// then re-arm the timer alarm (Core0, from the USB, doorbell and timer
+/// interrupts)
+fn service_usb() {
+    if usb_serial::handle_usb_serial() {
+        USB_EVENT.store(true, Ordering::Relaxed);
+        // Data may have been queued for, or taken from, Core1
+        cortex_m::asm::sev();
+    }
+    cortex_m::interrupt::free(arm_wake);
 }
 
-fn core1_task() {
+/// Arm Core0's timer alarm for whatever comes first: the latency timer of
+/// held-back data or the main loop's deadline. A loop deadline that has
+/// passed is dropped; the alarm interrupt has already ended the WFI.
+fn arm_wake(cs: &cortex_m::interrupt::CriticalSection) {
+    let now = board::time_us();
+    let ahead = |deadline: u32| deadline.wrapping_sub(now) as i32 > 0;
+    let loop_deadline = LOOP_DEADLINE
+        .borrow(cs)
+        .get()
+        .filter(|&deadline| ahead(deadline));
+    LOOP_DEADLINE.borrow(cs).set(loop_deadline);
+    if let Some(deadline) = [usb_serial::flush_deadline(), loop_deadline]
+        .into_iter()
+        .flatten()
+        .min_by_key(|deadline| deadline.wrapping_sub(now))
+    {
+        board::wake_at(deadline);
+    }
+}
+
+#[interrupt]
+fn USBCTRL_IRQ() {
+    service_usb();
+}
+
+/// Doorbell from Core1 (see `doorbell`)
+#[cfg(feature = "rp2040")]
+#[interrupt]
+fn SIO_IRQ_PROC0() {
+    doorbell::clear();
+    service_usb();
+}
+
+/// Doorbell from Core1 (see `doorbell`)
+#[cfg(feature = "rp2350")]
+#[interrupt]
+fn SIO_IRQ_FIFO() {
+    doorbell::clear();
+    service_usb();
+}
+
+/// Timer alarm: the latency timer (see `usb_serial::send_to_host`) or a
+/// main loop deadline (see `arm_wake`)
+#[cfg(feature = "rp2040")]
+#[interrupt]
+fn TIMER_IRQ_0() {
+    board::ack_wake();
+    service_usb();
+}
+
+/// Timer alarm: the latency timer (see `usb_serial::send_to_host`) or a
+/// main loop deadline (see `arm_wake`)
+#[cfg(feature = "rp2350")]
+#[interrupt]
+fn TIMER0_IRQ_0() {
+    board::ack_wake();
+    service_usb();
+}
+
+/// Completion of a hardware UART TX DMA transfer
+#[interrupt]
+fn DMA_IRQ_0() {
+    for (channel, port) in uart_core1::PORTS.iter().enumerate() {
+        port.ack_tx_dma();
+        service_port(channel, port);
+    }
+}
+
+/// 1 ms tick on Core1: picks up what the RX DMA has stored. The UART's RX
+/// timeout interrupt never fires because the DMA keeps its FIFO empty.
+#[exception]
+fn SysTick() {
+    for (channel, port) in uart_core1::PORTS.iter().enumerate() {
+        service_port(channel, port);
+    }
+}
+
+#[interrupt]
+fn PIO0_IRQ_0() {
+    for (index, port) in pio_uart::PIO_PORTS.iter().enumerate() {
+        service_port(HW_UART_CHANNELS + index, port);
+    }
+}
+
+/// SysTick CSR: counter enable, exception enable, processor clock
+const SYST_CSR_ENABLE: u32 = 1 << 0;
+const SYST_CSR_TICKINT: u32 = 1 << 1;
+const SYST_CSR_CLKSOURCE: u32 = 1 << 2;
+
+/// Core1 main loop: the DMA, UART and PIO interrupts and the SysTick move
+/// the data, this loop applies configuration changes from Core0 and starts
+/// transmissions.
+///
+/// Core0 signals new CDC→UART data, freed UART→CDC room, configuration
+/// requests and flash operations (see `flash`) with SEV, so Core1 sleeps in
+/// WFE (which also wakes on its own interrupts) rather than WFI.
+fn core1_task(system_freq: u32, fifos: [Core1Fifos; UART_CHANNELS]) {
+    // Take ownership of Core1's FIFO halves before any handler can run
+    CORE1_FIFOS.install(fifos);
+
+    // The bridge interrupts are only unmasked in Core1's NVIC, so their
+    // handlers always run on Core1
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::UART0_IRQ);
+        pac::NVIC::unmask(pac::Interrupt::UART1_IRQ);
+        pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0);
+        pac::NVIC::unmask(pac::Interrupt::PIO0_IRQ_0);
+    }
+
+    // Each core has its own SysTick; Core0 does not use its own
+    unsafe {
+        let syst = &*cortex_m::peripheral::SYST::PTR;
+        syst.rvr.write(system_freq / 1000 - 1);
+        syst.cvr.write(0);
+        syst.csr
+            .write(SYST_CSR_ENABLE | SYST_CSR_TICKINT | SYST_CSR_CLKSOURCE);
+    }
+
     loop {
-        if uart_core1::is_readable() {
-            let data = uart_core1::read_byte();
-            process_received_byte(data);
+        // Stay out of flash while Core0 writes it
+        if flash::pause_requested() {
+            cortex_m::interrupt::free(|_| flash::park_core1());
         }
 
-        cortex_m::interrupt::free(|cs| {
-            if let Some(ref mut consumer) = CDC_TO_UART_CONSUMER.borrow(cs).borrow_mut().as_mut() {
-                while let Some(b) = consumer.dequeue() {
-                    while uart_core1::is_tx_full() {}
-                    uart_core1::write_byte(b);
-                }
+        cortex_m::interrupt::free(|_| {
+            for (channel, port) in uart_core1::PORTS.iter().enumerate() {
+                port.apply_pending();
+                service_port(channel, port);
+            }
+            for (index, port) in pio_uart::PIO_PORTS.iter().enumerate() {
+                port.apply_pending();
+                service_port(HW_UART_CHANNELS + index, port);
             }
         });
+        cortex_m::asm::wfe();
     }
 }
 
-fn initialize_fifo_buffers() -> AppResult<()> {
-    let (cdc_to_uart_producer, cdc_to_uart_consumer): (
-        Producer<u8, FIFO_BUFFER_SIZE>,
-        Consumer<'static, u8, FIFO_BUFFER_SIZE>,
-    ) = unsafe {
-        let queue_ptr = core::ptr::addr_of_mut!(CDC_TO_UART_QUEUE);
-        (*queue_ptr).split()
-    };
-
-    cortex_m::interrupt::free(|cs| {
-        *CDC_TO_UART_PRODUCER.borrow(cs).borrow_mut() = Some(cdc_to_uart_producer);
-        *CDC_TO_UART_CONSUMER.borrow(cs).borrow_mut() = Some(cdc_to_uart_consumer);
-    });
-
-    let (uart_to_cdc_producer, uart_to_cdc_consumer): (
-        Producer<u8, FIFO_BUFFER_SIZE>,
-        Consumer<'static, u8, FIFO_BUFFER_SIZE>,
-    ) = unsafe {
-        let queue_ptr = core::ptr::addr_of_mut!(UART_TO_CDC_QUEUE);
-        (*queue_ptr).split()
-    };
+/// Split every inter-core FIFO into the halves used by Core0 and Core1.
+///
+/// Must only be called once: each queue may only be split once.
+fn split_fifos() -> ([Core0Fifos; UART_CHANNELS], [Core1Fifos; UART_CHANNELS]) {
+    let mut core1_fifos: [Option<Core1Fifos>; UART_CHANNELS] = [const { None }; UART_CHANNELS];
+    let core0_fifos = core::array::from_fn(|channel| {
+        let (cdc_to_uart_producer, cdc_to_uart_consumer) = unsafe {
+            let queue_ptr = core::ptr::addr_of_mut!(CDC_TO_UART_QUEUES[channel]);
+            (*queue_ptr).split()
+        };
+        let (uart_to_cdc_producer, uart_to_cdc_consumer) = unsafe {
+            let queue_ptr = core::ptr::addr_of_mut!(UART_TO_CDC_QUEUES[channel]);
+            (*queue_ptr).split()
+        };
 
-    cortex_m::interrupt::free(|cs| {
-        *UART_TO_CDC_PRODUCER.borrow(cs).borrow_mut() = Some(uart_to_cdc_producer);
-        *UART_TO_CDC_CONSUMER.borrow(cs).borrow_mut() = Some(uart_to_cdc_consumer);
+        core1_fifos[channel] = Some(Core1Fifos {
+            cdc_to_uart: cdc_to_uart_consumer,
+            uart_to_cdc: uart_to_cdc_producer,
+        });
+        Core0Fifos {
+            cdc_to_uart: cdc_to_uart_producer,
+            uart_to_cdc: uart_to_cdc_consumer,
+        }
     });
 
-    Ok(())
+    (
+        core0_fifos,
+        core1_fifos.map(|fifos| fifos.expect("FIFO not split")),
+    )
 }
 
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().expect("Failed to take PAC peripherals");
-    let core = cortex_m::Peripherals::take().expect("Failed to take Core peripherals");
     let mut sio = Sio::new(pac.SIO);
 
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let clocks = init_clocks_and_plls(
-        EXTERNAL_XTAL_FREQ_HZ,
+        ActiveBoard::XTAL_FREQ_HZ,
         pac.XOSC,
         pac.CLOCKS,
         pac.PLL_SYS,
@@ -157,6 +385,7 @@ fn main() -> ! {
     .expect("Failed to initialize clocks");
 
     let system_freq = clocks.system_clock.freq().to_Hz();
+    let peripheral_freq = clocks.peripheral_clock.freq().to_Hz();
 
     let pins = Pins::new(
         pac.IO_BANK0,
@@ -165,30 +394,47 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    match board::init_pins_and_enable_uart(pins, pac.UART0, &mut pac.RESETS, system_freq) {
-        Ok(led_local) => {
+    // Pins, flow control, baud rates, USB strings and LED mode from the
+    // saved configuration (the board file's pins and strings otherwise)
+    let config = config::load();
+    let flow_control = core::array::from_fn(|uart| power_on_flow_control(config, uart));
+    let uart_flow_control = match board::init_pins_and_enable_uarts::<ActiveBoard>(
+        pins,
+        &config.uart_pins,
+        &flow_control,
+        &mut pac.RESETS,
+    ) {
+        Ok(board_pins) => {
             cortex_m::interrupt::free(|cs| {
-                *LED_PIN.borrow(cs).borrow_mut() = Some(led_local);
+                *LED_PIN.borrow(cs).borrow_mut() = board_pins.led;
             });
+            control_lines::init(board_pins.dtr, board_pins.rts);
+            board_pins.uart_flow_control
         }
         Err(_e) => {
             cortex_m::asm::bkpt();
+            [FlowControl::None; HW_UART_CHANNELS]
         }
-    }
+    };
 
-    initialize_fifo_buffers().expect("FIFO buffer initialization failed");
+    // Core0 keeps its FIFO halves, Core1's are moved into its task at spawn
+    let (core0_fifos, core1_fifos) = split_fifos();
+    CORE0_FIFOS.install(core0_fifos);
 
-    // Initialize UART pointer for Core1 before spawning
-    uart_core1::init_uart_ptr();
+    // Initialize the UARTs and their DMA for Core1 before spawning
+    uart_core1::init_uart_ptrs(peripheral_freq);
+    for (port, flow_control) in uart_core1::PORTS.iter().zip(uart_flow_control) {
+        port.request_flow_control(flow_control);
+    }
+    pio_uart::init(&ActiveBoard::PIO_UART_PINS, system_freq);
+    for channel in 0..UART_CHANNELS {
+        bridge_port(channel).request_line_config(power_on_line_config(config, channel));
+    }
 
     let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
     let cores = mc.cores();
     let core1 = &mut cores[1];
 
-    cortex_m::interrupt::free(|cs| {
-        *CORE1_DATA.borrow(cs).borrow_mut() = Some(Core1Data);
-    });
-
     #[cfg(feature = "rp2350")]
     {
         let stack_alloc = unsafe {
@@ -199,7 +445,7 @@ fn main() -> ! {
                 .expect("Failed to take CORE1_STACK")
         };
         core1
-            .spawn(stack_alloc, core1_task)
+            .spawn(stack_alloc, move || core1_task(system_freq, core1_fifos))
             .expect("Failed to spawn Core1 task");
     }
 
@@ -211,7 +457,7 @@ fn main() -> ! {
         };
 
         core1
-            .spawn(stack_slice, core1_task)
+            .spawn(stack_slice, move || core1_task(system_freq, core1_fifos))
             .expect("Failed to spawn Core1 task");
     }
 
@@ -228,7 +474,7 @@ fn main() -> ! {
     set_led_state(false);
 
     #[cfg(feature = "rp2040")]
-    usb_serial::init_usb(
+    let usb_bus = board::make_usb_bus(
         pac.USBCTRL_REGS,
         pac.USBCTRL_DPRAM,
         clocks.usb_clock,
@@ -236,50 +482,130 @@ fn main() -> ! {
     );
 
     #[cfg(feature = "rp2350")]
-    usb_serial::init_usb(pac.USB, pac.USB_DPRAM, clocks.usb_clock, &mut pac.RESETS);
+    let usb_bus = board::make_usb_bus(pac.USB, pac.USB_DPRAM, clocks.usb_clock, &mut pac.RESETS);
 
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, system_freq);
-    let mut last_usb_activity = false;
+    usb_serial::init_usb(usb_bus, config);
 
-    loop {
-        let mut usb_activity = usb_serial::handle_usb_serial();
+    // USB is serviced from its interrupt, Core1's doorbell and the latency
+    // timer alarm, all only unmasked on Core0. The launch handshake with Core1 is over, so
+    // the FIFO is free for the doorbell
+    doorbell::clear();
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
+        #[cfg(feature = "rp2040")]
+        pac::NVIC::unmask(pac::Interrupt::SIO_IRQ_PROC0);
+        #[cfg(feature = "rp2040")]
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+        #[cfg(feature = "rp2350")]
+        pac::NVIC::unmask(pac::Interrupt::SIO_IRQ_FIFO);
+        #[cfg(feature = "rp2350")]
+        pac::NVIC::unmask(pac::Interrupt::TIMER0_IRQ_0);
+    }
 
-        if crate::USB_EVENT.load(core::sync::atomic::Ordering::Relaxed) {
-            crate::USB_EVENT.store(false, core::sync::atomic::Ordering::Relaxed);
-            usb_activity = true;
-            let now = timer.get_counter().ticks();
-            unsafe {
-                LED_LAST_ACTIVITY_US = now;
-            }
-            set_led_state(true);
-        } else if usb_activity {
-            let now = timer.get_counter().ticks();
+    let mut break_deadlines: [Option<u64>; UART_CHANNELS] = [None; UART_CHANNELS];
+    let mut reset_deadline: Option<u64> = None;
+    let mut reboot_deadline: Option<u64> = None;
+
+    // The main loop only times breaks, target resets, the LED and the
+    // shell's reboot, and writes the shell's save; in between it sleeps in WFI until the timer alarm
+    loop {
+        let usb_activity = cortex_m::interrupt::free(|_| {
+            let event = USB_EVENT.load(Ordering::Relaxed);
+            USB_EVENT.store(false, Ordering::Relaxed);
+            event
+        });
+        if usb_activity {
             unsafe {
-                LED_LAST_ACTIVITY_US = now;
+                LED_LAST_ACTIVITY_US = timer.get_counter().ticks();
             }
         }
 
-        if usb_activity != last_usb_activity {
-            if usb_activity {
-                set_led_state(true);
-            } else {
-                set_led_state(false);
+        // CDC SEND_BREAK: Core1 drives the break, Core0 times it
+        for (channel, break_deadline) in break_deadlines.iter_mut().enumerate() {
+            let port = bridge_port(channel);
+            match usb_serial::take_break_request(channel) {
+                Some(0) => {
+                    port.set_break(false);
+                    *break_deadline = None;
+                }
+                Some(cdc_acm::BREAK_INDEFINITE) => {
+                    port.set_break(true);
+                    *break_deadline = None;
+                }
+                Some(duration_ms) => {
+                    port.set_break(true);
+                    let now = timer.get_counter().ticks();
+                    *break_deadline = Some(now + u64::from(duration_ms) * 1000);
+                }
+                None => {}
+            }
+            if let Some(deadline) = *break_deadline
+                && timer.get_counter().ticks() >= deadline
+            {
+                port.set_break(false);
+                *break_deadline = None;
             }
-            last_usb_activity = usb_activity;
         }
 
+        // Escape menu target reset: Core0 holds RTS asserted for a moment
+        if usb_serial::take_reset_request() {
+            control_lines::hold_reset(true);
+            reset_deadline = Some(timer.get_counter().ticks() + TARGET_RESET_MS * 1000);
+        }
+        if let Some(deadline) = reset_deadline
+            && timer.get_counter().ticks() >= deadline
         {
-            let now = timer.get_counter().ticks();
-            let last = unsafe { LED_LAST_ACTIVITY_US };
-            let elapsed = now.wrapping_sub(last);
-            let duration = 10_000u64; // 10 ms
-            if elapsed <= duration {
-                set_led_state(true);
-            } else {
-                set_led_state(false);
-            }
+            control_lines::hold_reset(false);
+            reset_deadline = None;
+        }
+
+        // Shell `save`: write the flash here rather than in the USB
+        // interrupt, then have the USB side report the outcome
+        if settings::write_requested_save() {
+            pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+        }
+
+        // Shell `reboot`: reset once the reply has had time to go out
+        if reboot_deadline.is_none() && settings::reboot_requested() {
+            reboot_deadline = Some(timer.get_counter().ticks() + REBOOT_DELAY_US);
+        }
+        if let Some(deadline) = reboot_deadline
+            && timer.get_counter().ticks() >= deadline
+        {
+            cortex_m::peripheral::SCB::sys_reset();
         }
 
-        delay.delay_us(1000u32);
+        // In activity mode, keep the LED on for a moment after the last activity
+        let last = unsafe { LED_LAST_ACTIVITY_US };
+        let activity = config.led == LedMode::Activity
+            && timer.get_counter().ticks().wrapping_sub(last) <= LED_ACTIVITY_US;
+        set_led_state(activity || config.led == LedMode::On);
+        let led_deadline = activity.then_some(last + LED_ACTIVITY_US + 1);
+
+        // Sleep until the next interrupt, with the timer alarm armed for the
+        // earliest deadline (the alarm compares the counter's low 32 bits,
+        // which is `board::time_us`). With interrupts masked, an event
+        // arriving after the checks still ends the WFI, and its handler runs
+        // once they are unmasked
+        let deadline = break_deadlines
+            .iter()
+            .chain([&reset_deadline, &reboot_deadline, &led_deadline])
+            .flatten()
+            .min()
+            .copied();
+        cortex_m::interrupt::free(|cs| {
+            LOOP_DEADLINE
+                .borrow(cs)
+                .set(deadline.map(|deadline| deadline as u32));
+            arm_wake(cs);
+            if !USB_EVENT.load(Ordering::Relaxed)
+                && !usb_serial::break_requested()
+                && !usb_serial::reset_requested()
+                && !settings::save_requested()
+                && (reboot_deadline.is_some() || !settings::reboot_requested())
+            {
+                cortex_m::asm::wfi();
+            }
+        });
     }
 }
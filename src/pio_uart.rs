@@ -0,0 +1,488 @@
+//! PIO-based UART channels for Core1
+//!
+//! The RP2040/RP2350 only have two hardware UARTs; further bridge channels
+//! run on PIO0. Each channel uses two state machines (TX on `2 * index`, RX
+//! on `2 * index + 1`) running the `uart_tx`/`uart_rx` programs from
+//! pico-examples, and is serviced by Core1 through `BridgePort` exactly like
+//! a hardware UART.
+//!
+//! The programs are small enough to be kept hand-assembled here, so no PIO
+//! assembler is needed at build time. They implement 8N1 only: the baud rate
+//! follows the host's line coding, the character format does not.
+
+use crate::byte_ring::Consumer;
+use crate::pac;
+use crate::uart_config::LineConfig;
+use crate::uart_core1::{BridgePort, increment};
+use crate::{FIFO_BUFFER_SIZE, PIO_UART_CHANNELS};
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use picoterm_bridge::pump::{self, Pumped};
+use picoterm_bridge::stats::LineErrors;
+use picoterm_bridge::{ByteSink, SinkError};
+
+/// PIO clock cycles per UART bit in both programs
+const CYCLES_PER_BIT: u32 = 8;
+
+/// `uart_tx` (side-set pin = TX):
+///
+/// ```text
+/// .side_set 1 opt
+///     pull       side 1 [7]  ; stop bit, then idle high until data arrives
+///     set x, 7   side 0 [7]  ; start bit, preload the bit counter
+/// bitloop:
+///     out pins, 1            ; data bits, LSB first
+///     jmp x-- bitloop   [6]
+/// ```
+const TX_PROGRAM: [u16; 4] = [0x9FA0, 0xF727, 0x6001, 0x0642];
+
+/// `uart_rx` (IN base and JMP pin = RX):
+///
+/// ```text
+/// start:
+///     wait 0 pin 0        ; start bit
+///     set x, 7    [10]    ; move to the middle of the first data bit
+/// bitloop:
+///     in pins, 1
+///     jmp x-- bitloop [6]
+///     jmp pin good_stop
+///     irq 0 rel           ; framing error or break: flag it,
+///     wait 1 pin 0        ; wait for the line to go idle
+///     jmp start           ; and drop the character
+/// good_stop:
+///     push
+/// ```
+const RX_PROGRAM: [u16; 9] = [
+    0x2020, 0xEA27, 0x4001, 0x0642, 0x00C8, 0xC010, 0x20A0, 0x0000, 0x8020,
+];
+
+/// Instruction memory offsets of the two programs (shared by all channels)
+const TX_OFFSET: u32 = 0;
+const RX_OFFSET: u32 = TX_PROGRAM.len() as u32;
+
+/// `set pins, 0` / `set pins, 1` / `set pindirs, 1`
+const SET_PINS_LOW: u32 = 0xE000;
+const SET_PINS_HIGH: u32 = 0xE001;
+const SET_PINDIRS_OUT: u32 = 0xE081;
+
+/// PIO CTRL register state machine enable field offset
+const CTRL_SM_ENABLE_SHIFT: u32 = 0;
+/// PIO CTRL register clock divider restart field offset
+const CTRL_CLKDIV_RESTART_SHIFT: u32 = 8;
+/// PIO FSTAT register RX FIFO empty field offset
+const FSTAT_RXEMPTY_SHIFT: u32 = 8;
+/// PIO FSTAT register TX FIFO empty field offset
+const FSTAT_TXEMPTY_SHIFT: u32 = 24;
+/// PIO FLEVEL register: TX level of SMn at bit `FLEVEL_SM_SHIFT * n`
+const FLEVEL_SM_SHIFT: u32 = 8;
+const FLEVEL_MASK: u32 = 0xF;
+/// Depth of the TX FIFO (joined with the unused RX FIFO)
+const TX_FIFO_DEPTH: usize = 8;
+/// PIO FDEBUG register RX stall (push on a full FIFO) field offset
+const FDEBUG_RXSTALL_SHIFT: u32 = 0;
+/// PIO FDEBUG register TX stall (pull on an empty FIFO) field offset
+const FDEBUG_TXSTALL_SHIFT: u32 = 24;
+/// PIO IRQ flag raised by `irq 0 rel` is `RX_ERROR_IRQ + sm`
+const RX_ERROR_IRQ: u32 = 0;
+
+/// IRQ0_INTE RX FIFO not empty field offset
+const INTE_RXNEMPTY_SHIFT: u32 = 0;
+/// IRQ0_INTE TX FIFO not full field offset
+const INTE_TXNFULL_SHIFT: u32 = 4;
+/// IRQ0_INTE state machine IRQ flag field offset
+const INTE_SM_SHIFT: u32 = 8;
+
+/// SMx_EXECCTRL side-set enable bit (side-set is optional per instruction)
+const EXECCTRL_SIDE_EN_BIT: u32 = 1 << 30;
+/// SMx_EXECCTRL JMP pin field offset
+const EXECCTRL_JMP_PIN_SHIFT: u32 = 24;
+/// SMx_EXECCTRL wrap top field offset
+const EXECCTRL_WRAP_TOP_SHIFT: u32 = 12;
+/// SMx_EXECCTRL wrap bottom field offset
+const EXECCTRL_WRAP_BOTTOM_SHIFT: u32 = 7;
+
+/// SMx_SHIFTCTRL join RX FIFO bit (8-entry RX FIFO)
+const SHIFTCTRL_FJOIN_RX_BIT: u32 = 1 << 31;
+/// SMx_SHIFTCTRL join TX FIFO bit (8-entry TX FIFO)
+const SHIFTCTRL_FJOIN_TX_BIT: u32 = 1 << 30;
+/// SMx_SHIFTCTRL OUT shifts right (LSB first)
+const SHIFTCTRL_OUT_SHIFTDIR_BIT: u32 = 1 << 19;
+/// SMx_SHIFTCTRL IN shifts right (data ends up in bits 31:24)
+const SHIFTCTRL_IN_SHIFTDIR_BIT: u32 = 1 << 18;
+
+/// SMx_PINCTRL field offsets
+const PINCTRL_SIDESET_COUNT_SHIFT: u32 = 29;
+const PINCTRL_SET_COUNT_SHIFT: u32 = 26;
+const PINCTRL_OUT_COUNT_SHIFT: u32 = 20;
+const PINCTRL_IN_BASE_SHIFT: u32 = 15;
+const PINCTRL_SIDESET_BASE_SHIFT: u32 = 10;
+const PINCTRL_SET_BASE_SHIFT: u32 = 5;
+const PINCTRL_OUT_BASE_SHIFT: u32 = 0;
+
+/// Core1-side state of one PIO UART channel.
+///
+/// Follows the same single-writer atomic scheme as `uart_core1::UartPort`.
+pub struct PioUartPort {
+    /// State machine running `uart_tx`
+    tx_sm: u32,
+    /// State machine running `uart_rx`
+    rx_sm: u32,
+    /// PIO clock (clk_sys) in Hz
+    clock_hz: AtomicU32,
+    /// System clock cycles per UART bit at the current baud rate
+    bit_cycles: AtomicU32,
+
+    /// Baud rate mailbox: Core0 writes the rate and bumps `line_config_seq`
+    line_config_baud: AtomicU32,
+    line_config_seq: AtomicU32,
+    line_config_applied: AtomicU32,
+
+    /// Break state requested by the host / currently driven on TX
+    break_requested: AtomicBool,
+    break_active: AtomicBool,
+
+    /// Line error counters for RX
+    framing_errors: AtomicU32,
+    overruns: AtomicU32,
+}
+
+/// PIO UART channels, indexed by bridge channel minus `HW_UART_CHANNELS`
+pub static PIO_PORTS: [PioUartPort; PIO_UART_CHANNELS] = [PioUartPort::new(0), PioUartPort::new(1)];
+
+/// Load the programs into PIO0 and start every PIO UART channel at
+/// `UART_BAUD_RATE` (called once before spawning Core1).
+///
+/// `pins` holds the (TX, RX) GPIO of each channel; the board must already
+/// have switched them to the PIO0 function.
+pub fn init(pins: &[(u8, u8); PIO_UART_CHANNELS], sys_clock_hz: u32) {
+    let pio = pio();
+
+    for (i, &instr) in TX_PROGRAM.iter().enumerate() {
+        pio.instr_mem(TX_OFFSET as usize + i)
+            .write(|w| unsafe { w.bits(relocate(instr, TX_OFFSET)) });
+    }
+    for (i, &instr) in RX_PROGRAM.iter().enumerate() {
+        pio.instr_mem(RX_OFFSET as usize + i)
+            .write(|w| unsafe { w.bits(relocate(instr, RX_OFFSET)) });
+    }
+
+    let mut enable = 0;
+    for (port, &(tx_pin, rx_pin)) in PIO_PORTS.iter().zip(pins) {
+        port.init(u32::from(tx_pin), u32::from(rx_pin), sys_clock_hz);
+        enable |= (1 << port.tx_sm) | (1 << port.rx_sm);
+    }
+
+    let ctrl = pio.ctrl().read().bits();
+    pio.ctrl()
+        .write(|w| unsafe { w.bits(ctrl | (enable << CTRL_SM_ENABLE_SHIFT)) });
+}
+
+/// PIO0 register block
+#[inline]
+fn pio() -> &'static pac::pio0::RegisterBlock {
+    unsafe { &*pac::PIO0::ptr() }
+}
+
+/// Add the load offset to the target of a JMP instruction
+fn relocate(instr: u16, offset: u32) -> u32 {
+    let instr = u32::from(instr);
+    if instr & 0xE000 == 0 {
+        instr + offset
+    } else {
+        instr
+    }
+}
+
+/// Compute the 16.8 fixed point clock divider for `CYCLES_PER_BIT` cycles per bit
+fn clock_divider(clock_hz: u32, baud: u32) -> (u32, u32) {
+    let div = (u64::from(clock_hz) * 256) / (u64::from(CYCLES_PER_BIT) * u64::from(baud));
+    match div >> 8 {
+        0 => (1, 0),
+        int if int > 0xFFFF => (0xFFFF, 0),
+        int => (int as u32, (div & 0xFF) as u32),
+    }
+}
+
+impl PioUartPort {
+    const fn new(index: u32) -> Self {
+        Self {
+            tx_sm: 2 * index,
+            rx_sm: 2 * index + 1,
+            clock_hz: AtomicU32::new(0),
+            bit_cycles: AtomicU32::new(0),
+            line_config_baud: AtomicU32::new(0),
+            line_config_seq: AtomicU32::new(0),
+            line_config_applied: AtomicU32::new(0),
+            break_requested: AtomicBool::new(false),
+            break_active: AtomicBool::new(false),
+            framing_errors: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+        }
+    }
+
+    /// Configure both state machines (left disabled; `init` enables them)
+    fn init(&self, tx_pin: u32, rx_pin: u32, sys_clock_hz: u32) {
+        let pio = pio();
+        self.clock_hz.store(sys_clock_hz, Ordering::Relaxed);
+        self.set_baud(crate::UART_BAUD_RATE);
+
+        let tx = pio.sm(self.tx_sm as usize);
+        tx.sm_execctrl().write(|w| unsafe {
+            w.bits(
+                EXECCTRL_SIDE_EN_BIT
+                    | ((TX_OFFSET + TX_PROGRAM.len() as u32 - 1) << EXECCTRL_WRAP_TOP_SHIFT)
+                    | (TX_OFFSET << EXECCTRL_WRAP_BOTTOM_SHIFT),
+            )
+        });
+        tx.sm_shiftctrl()
+            .write(|w| unsafe { w.bits(SHIFTCTRL_FJOIN_TX_BIT | SHIFTCTRL_OUT_SHIFTDIR_BIT) });
+        // Side-set count includes the "optional" enable bit
+        tx.sm_pinctrl().write(|w| unsafe {
+            w.bits(
+                (2 << PINCTRL_SIDESET_COUNT_SHIFT)
+                    | (1 << PINCTRL_SET_COUNT_SHIFT)
+                    | (1 << PINCTRL_OUT_COUNT_SHIFT)
+                    | (tx_pin << PINCTRL_SIDESET_BASE_SHIFT)
+                    | (tx_pin << PINCTRL_SET_BASE_SHIFT)
+                    | (tx_pin << PINCTRL_OUT_BASE_SHIFT),
+            )
+        });
+        // Drive TX idle high before turning it into an output
+        self.exec(self.tx_sm, SET_PINS_HIGH);
+        self.exec(self.tx_sm, SET_PINDIRS_OUT);
+        self.exec(self.tx_sm, TX_OFFSET);
+
+        let rx = pio.sm(self.rx_sm as usize);
+        rx.sm_execctrl().write(|w| unsafe {
+            w.bits(
+                (rx_pin << EXECCTRL_JMP_PIN_SHIFT)
+                    | ((RX_OFFSET + RX_PROGRAM.len() as u32 - 1) << EXECCTRL_WRAP_TOP_SHIFT)
+                    | (RX_OFFSET << EXECCTRL_WRAP_BOTTOM_SHIFT),
+            )
+        });
+        rx.sm_shiftctrl()
+            .write(|w| unsafe { w.bits(SHIFTCTRL_FJOIN_RX_BIT | SHIFTCTRL_IN_SHIFTDIR_BIT) });
+        rx.sm_pinctrl()
+            .write(|w| unsafe { w.bits(rx_pin << PINCTRL_IN_BASE_SHIFT) });
+        self.exec(self.rx_sm, RX_OFFSET);
+    }
+
+    /// Execute one instruction on a state machine (also works while disabled)
+    #[inline]
+    fn exec(&self, sm: u32, instr: u32) {
+        pio()
+            .sm(sm as usize)
+            .sm_instr()
+            .write(|w| unsafe { w.bits(instr) });
+    }
+
+    /// Program both clock dividers for `baud`
+    fn set_baud(&self, baud: u32) {
+        let pio = pio();
+        let clock_hz = self.clock_hz.load(Ordering::Relaxed);
+        let (int, frac) = clock_divider(clock_hz, baud);
+        for sm in [self.tx_sm, self.rx_sm] {
+            pio.sm(sm as usize)
+                .sm_clkdiv()
+                .write(|w| unsafe { w.bits((int << 16) | (frac << 8)) });
+        }
+        self.bit_cycles
+            .store(clock_hz / baud.max(1), Ordering::Relaxed);
+
+        let restart = ((1 << self.tx_sm) | (1 << self.rx_sm)) << CTRL_CLKDIV_RESTART_SHIFT;
+        let ctrl = pio.ctrl().read().bits();
+        pio.ctrl().write(|w| unsafe { w.bits(ctrl | restart) });
+    }
+
+    /// True while Core0 has requested a baud rate Core1 has not applied yet
+    #[inline]
+    fn line_config_pending(&self) -> bool {
+        self.line_config_seq.load(Ordering::Acquire)
+            != self.line_config_applied.load(Ordering::Relaxed)
+    }
+
+    /// Apply a pending baud rate, if any (called from Core1).
+    ///
+    /// Like `UartPort`, the queued characters are sent at the old rate
+    /// first: the rate stays pending until TX is idle and is retried on the
+    /// next pass, while `tx_space` holds back new data.
+    fn apply_pending_line_config(&self) {
+        let seq = self.line_config_seq.load(Ordering::Acquire);
+        if seq == self.line_config_applied.load(Ordering::Relaxed) || !self.tx_idle() {
+            return;
+        }
+        self.line_config_applied.store(seq, Ordering::Relaxed);
+        self.set_baud(self.line_config_baud.load(Ordering::Relaxed));
+    }
+
+    /// True once every queued character, stop bit included, has been sent
+    fn tx_idle(&self) -> bool {
+        let pio = pio();
+        if pio.fstat().read().bits() & (1 << (FSTAT_TXEMPTY_SHIFT + self.tx_sm)) == 0 {
+            return false;
+        }
+        // TXSTALL is sticky: clear it and see whether the program is still
+        // waiting on `pull`, i.e. has started the stop bit of the last character
+        let stall = 1 << (FDEBUG_TXSTALL_SHIFT + self.tx_sm);
+        pio.fdebug().write(|w| unsafe { w.bits(stall) });
+        if pio.fdebug().read().bits() & stall == 0 {
+            return false;
+        }
+        cortex_m::asm::delay(self.bit_cycles.load(Ordering::Relaxed));
+        true
+    }
+
+    /// Hold TX low while a break is requested (called from Core1).
+    ///
+    /// The TX state machine is stopped once it has finished the last queued
+    /// character and TX is then driven directly; no new data is accepted
+    /// until the break ends.
+    fn apply_pending_break(&self) {
+        let requested = self.break_requested.load(Ordering::Acquire);
+        if requested == self.break_active.load(Ordering::Relaxed) {
+            return;
+        }
+        if requested && !self.tx_idle() {
+            // Retried on the next pass; `tx_space` holds back new data
+            return;
+        }
+        self.break_active.store(requested, Ordering::Relaxed);
+
+        let pio = pio();
+        let enable = 1 << (CTRL_SM_ENABLE_SHIFT + self.tx_sm);
+        let ctrl = pio.ctrl().read().bits();
+        if requested {
+            pio.ctrl().write(|w| unsafe { w.bits(ctrl & !enable) });
+            self.exec(self.tx_sm, SET_PINS_LOW);
+        } else {
+            self.exec(self.tx_sm, SET_PINS_HIGH);
+            self.exec(self.tx_sm, TX_OFFSET);
+            pio.ctrl().write(|w| unsafe { w.bits(ctrl | enable) });
+        }
+    }
+
+    /// Number of bytes `write` accepts right now
+    #[inline]
+    fn tx_space(&self) -> usize {
+        if self.break_requested.load(Ordering::Relaxed)
+            || self.break_active.load(Ordering::Relaxed)
+            || self.line_config_pending()
+        {
+            return 0;
+        }
+        let level = (pio().flevel().read().bits() >> (FLEVEL_SM_SHIFT * self.tx_sm)) & FLEVEL_MASK;
+        TX_FIFO_DEPTH - level as usize
+    }
+
+    /// Queue `data` (at most `tx_space()` bytes) in the TX FIFO
+    fn write(&self, data: &[u8]) {
+        let pio = pio();
+        for &b in data {
+            pio.txf(self.tx_sm as usize)
+                .write(|w| unsafe { w.bits(u32::from(b)) });
+        }
+    }
+
+    /// Count framing errors and RX FIFO overruns flagged by the RX program
+    fn record_errors(&self) {
+        let pio = pio();
+
+        let irq = 1 << (RX_ERROR_IRQ + self.rx_sm);
+        if pio.irq().read().bits() & irq != 0 {
+            pio.irq().write(|w| unsafe { w.bits(irq) });
+            increment(&self.framing_errors);
+        }
+
+        let stall = 1 << (FDEBUG_RXSTALL_SHIFT + self.rx_sm);
+        if pio.fdebug().read().bits() & stall != 0 {
+            pio.fdebug().write(|w| unsafe { w.bits(stall) });
+            increment(&self.overruns);
+        }
+    }
+}
+
+impl BridgePort for PioUartPort {
+    /// Only the baud rate is applied; the programs are fixed at 8N1
+    fn request_line_config(&self, config: LineConfig) {
+        self.line_config_baud.store(config.baud, Ordering::Relaxed);
+        let seq = self.line_config_seq.load(Ordering::Relaxed).wrapping_add(1);
+        self.line_config_seq.store(seq, Ordering::Release);
+        cortex_m::asm::sev();
+    }
+
+    fn set_break(&self, asserted: bool) {
+        self.break_requested.store(asserted, Ordering::Release);
+        cortex_m::asm::sev();
+    }
+
+    /// The RX program cannot tell a break from a framing error, and has no
+    /// parity; both are reported as framing errors.
+    fn line_errors(&self) -> LineErrors {
+        LineErrors {
+            framing: self.framing_errors.load(Ordering::Acquire),
+            parity: 0,
+            breaks: 0,
+            overrun: self.overruns.load(Ordering::Acquire),
+        }
+    }
+
+    /// From the largest clock divider (65535 + 255/256) up to a divider of
+    /// 1.0, `CYCLES_PER_BIT` system clocks per bit
+    fn baud_range(&self) -> RangeInclusive<u32> {
+        let clock_hz = self.clock_hz.load(Ordering::Relaxed);
+        clock_hz.div_ceil(CYCLES_PER_BIT << 16)..=clock_hz / CYCLES_PER_BIT
+    }
+
+    fn apply_pending(&self) {
+        self.apply_pending_line_config();
+        self.apply_pending_break();
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.record_errors();
+
+        let pio = pio();
+        let mut count = 0;
+        while count < buf.len()
+            && pio.fstat().read().bits() & (1 << (FSTAT_RXEMPTY_SHIFT + self.rx_sm)) == 0
+        {
+            buf[count] = (pio.rxf(self.rx_sm as usize).read().bits() >> 24) as u8;
+            count += 1;
+        }
+        count
+    }
+
+    fn transmit(&self, queue: &mut Consumer<'static, FIFO_BUFFER_SIZE>) -> Pumped {
+        pump::host_to_uart(queue, &mut TxFifo(self))
+    }
+
+    /// Routes RX FIFO not empty, the RX error flag and TX FIFO not full to
+    /// PIO0_IRQ_0. These are level interrupts, so TX must be disabled once
+    /// there is nothing left to send.
+    fn arm(&self, rx: bool, tx: bool) {
+        let pio = pio();
+        let rx_bits = (1 << (INTE_RXNEMPTY_SHIFT + self.rx_sm))
+            | (1 << (INTE_SM_SHIFT + RX_ERROR_IRQ + self.rx_sm));
+        let tx_bits = 1 << (INTE_TXNFULL_SHIFT + self.tx_sm);
+
+        let mut inte = pio.sm_irq(0).irq_inte().read().bits() & !(rx_bits | tx_bits);
+        if rx {
+            inte |= rx_bits;
+        }
+        if tx {
+            inte |= tx_bits;
+        }
+        pio.sm_irq(0).irq_inte().write(|w| unsafe { w.bits(inte) });
+    }
+}
+
+/// The TX FIFO of a PIO UART as the sink of `pump::host_to_uart`
+struct TxFifo<'a>(&'a PioUartPort);
+
+impl ByteSink for TxFifo<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, SinkError> {
+        let count = data.len().min(self.0.tx_space());
+        self.0.write(&data[..count]);
+        Ok(count)
+    }
+}
@@ -0,0 +1,127 @@
+//! The running bridge as the control port's shell sees it
+//!
+//! `Settings` holds the configuration the shell edits, starting out as the
+//! one loaded at boot. The rest of what the shell shows comes from the USB
+//! side's channel state and the bridge ports. The shell runs in the USB
+//! interrupt, so `save` and `reboot` only post their request: the main loop
+//! writes the edited configuration to the flash store, which keeps the USB
+//! interrupt masked only while the flash itself is busy, and resets the
+//! chip once the reply is out.
+
+use crate::board::{self, ActiveBoard};
+use crate::config::BoardFlash;
+use crate::uart_config::LineConfig;
+use crate::{
+    HW_UART_CHANNELS, bridge_port, power_on_flow_control, power_on_line_config, usb_serial,
+};
+use core::cell::{Cell, RefCell};
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::Mutex;
+use picoterm_bridge::config::Config;
+use picoterm_bridge::config_store::{ConfigStore, SaveError};
+use picoterm_bridge::pin_map::UartPins;
+use picoterm_bridge::shell::ShellTarget;
+use picoterm_bridge::stats::LineErrors;
+
+/// Set by the shell's `reboot`, taken by the main loop
+static REBOOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Configuration the shell's `save` posted, taken by the main loop
+static SAVE_REQUEST: Mutex<RefCell<Option<Config>>> = Mutex::new(RefCell::new(None));
+/// Outcome of the last save, taken by the shell
+static SAVE_RESULT: Mutex<Cell<Option<Result<(), SaveError>>>> = Mutex::new(Cell::new(None));
+
+/// True once the shell has asked for a reboot
+pub fn reboot_requested() -> bool {
+    REBOOT_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// True while a configuration posted by `save` waits for the main loop
+pub fn save_requested() -> bool {
+    cortex_m::interrupt::free(|cs| SAVE_REQUEST.borrow(cs).borrow().is_some())
+}
+
+/// Write the configuration posted by `save`, if any, to the flash store
+/// (Core0's main loop). Returns true if there was one; the shell picks up
+/// the outcome on the next USB poll.
+pub fn write_requested_save() -> bool {
+    let Some(config) = cortex_m::interrupt::free(|cs| SAVE_REQUEST.borrow(cs).take()) else {
+        return false;
+    };
+    let result = ConfigStore::new(BoardFlash).save(&config);
+    cortex_m::interrupt::free(|cs| SAVE_RESULT.borrow(cs).set(Some(result)));
+    true
+}
+
+/// The configuration being edited and the pins the UARTs run on
+pub struct Settings {
+    config: Config,
+    uart_pins: [Option<UartPins>; HW_UART_CHANNELS],
+}
+
+impl Settings {
+    /// Start editing from `boot`, the configuration the bridge runs with
+    pub fn new(boot: &Config) -> Self {
+        Self {
+            config: *boot,
+            uart_pins: board::resolve_uart_pins::<ActiveBoard>(
+                &boot.uart_pins,
+                &core::array::from_fn(|uart| power_on_flow_control(boot, uart)),
+            ),
+        }
+    }
+}
+
+impl ShellTarget for Settings {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    fn line_config(&self, channel: usize) -> LineConfig {
+        usb_serial::line_config(channel)
+    }
+
+    fn apply_baud(&mut self, channel: usize) {
+        usb_serial::set_baud(channel, power_on_line_config(&self.config, channel).baud);
+    }
+
+    fn baud_range(&self, channel: usize) -> RangeInclusive<u32> {
+        bridge_port(channel).baud_range()
+    }
+
+    fn uart_pins(&self, uart: usize) -> Option<UartPins> {
+        self.uart_pins[uart]
+    }
+
+    fn reserved_gpios(&self) -> u32 {
+        board::reserved_gpios::<ActiveBoard>()
+    }
+
+    fn line_errors(&self, channel: usize) -> LineErrors {
+        bridge_port(channel).line_errors()
+    }
+
+    fn dropped(&self, channel: usize) -> u32 {
+        usb_serial::dropped(channel)
+    }
+
+    fn save(&mut self) {
+        cortex_m::interrupt::free(|cs| {
+            SAVE_RESULT.borrow(cs).set(None);
+            SAVE_REQUEST.borrow(cs).replace(Some(self.config));
+        });
+    }
+
+    fn save_result(&mut self) -> Option<Result<(), SaveError>> {
+        cortex_m::interrupt::free(|cs| SAVE_RESULT.borrow(cs).take())
+    }
+
+    fn reboot(&mut self) {
+        REBOOT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+}
@@ -1,54 +1,708 @@
 //! Minimal UART helpers for Core1
 //!
-//! Provides low-level UART0 access for Core1 without HAL generics.
-//! Core1 reads and writes directly to UART0 registers, avoiding pin
-//! reconfiguration.
+//! Provides low-level UART0/UART1 access for Core1 without HAL generics.
+//! Core1 programs the UART registers directly, avoiding pin reconfiguration.
+//! Each hardware UART has one `UartPort` in `PORTS`.
+//!
+//! Data moves by DMA: RX runs continuously into a ring buffer that Core1
+//! copies out in chunks, and TX reads everything queued for the UART straight
+//! from the inter-core queue, so Core1 does no per-byte register access.
+//!
+//! `BridgePort` is the interface Core0 and Core1 use for every bridge
+//! channel, so the PIO UARTs in `pio_uart` are serviced the same way.
+//! `PortIo` hands a port to the data path in `picoterm_bridge`.
+
+use crate::FIFO_BUFFER_SIZE;
+use crate::byte_ring::Consumer;
 
+use crate::dma::{self, Channel};
 use crate::pac;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use crate::uart_config::{DataBits, FlowControl, LineConfig, Parity, StopBits};
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, Ordering};
+use picoterm_bridge::pump::Pumped;
+use picoterm_bridge::stats::LineErrors;
+use picoterm_bridge::{ByteSource, UartControl};
+
+/// Size of each UART's RX DMA ring as a power of two (the ring is aligned to
+/// its size). 4 KiB lasts about 10 ms at 4 Mbaud between Core1 passes.
+const RX_RING_BITS: u32 = 12;
+const RX_RING_SIZE: usize = 1 << RX_RING_BITS;
+/// Room left for data the RX DMA stores while Core1 copies out a ring it has
+/// lapped: that much of the oldest intact data is given up as well
+const RX_LAP_MARGIN: usize = 256;
+
+/// RX DMA ring of one UART
+#[repr(C, align(4096))]
+struct RxRing([u8; RX_RING_SIZE]);
+const _: () = assert!(core::mem::align_of::<RxRing>() == RX_RING_SIZE);
 
+/// RX DMA rings, indexed like `PORTS` (only touched by Core1 and the DMA)
+static mut RX_RINGS: [RxRing; crate::HW_UART_CHANNELS] =
+    [const { RxRing([0; RX_RING_SIZE]) }; crate::HW_UART_CHANNELS];
+
+/// Marks that no received 0x00 has to be dropped for a break
+const NO_BREAK_NUL: u32 = u32::MAX;
+
+/// UART RSR register framing error bit
+const RSR_FE_BIT: u32 = 1 << 0;
+/// UART RSR register parity error bit
+const RSR_PE_BIT: u32 = 1 << 1;
+/// UART RSR register break error bit
+const RSR_BE_BIT: u32 = 1 << 2;
+/// UART RSR register overrun error bit
+const RSR_OE_BIT: u32 = 1 << 3;
+/// All UART RSR register error bits
+const RSR_ERROR_BITS: u32 = RSR_FE_BIT | RSR_PE_BIT | RSR_BE_BIT | RSR_OE_BIT;
+
+/// UART FR register busy bit (set while a character is being shifted out)
+const BUSY_BIT: u32 = 1 << 3;
 /// UART FR register RX FIFO empty bit
 const RXFE_BIT: u32 = 1 << 4;
-/// UART FR register TX FIFO full bit
-const TXFF_BIT: u32 = 1 << 5;
 
-/// Static pointer to UART0 peripheral, initialized once
-static UART0_PTR: AtomicPtr<pac::uart0::RegisterBlock> = AtomicPtr::new(core::ptr::null_mut());
+/// UART IMSC/ICR framing, parity, break and overrun error interrupt bits
+const ERROR_INTERRUPT_BITS: u32 = 0xF << 7;
+
+/// UART DMACR register RX DMA enable bit
+const RXDMAE_BIT: u32 = 1 << 0;
+/// UART DMACR register TX DMA enable bit
+const TXDMAE_BIT: u32 = 1 << 1;
+
+/// UART CR register enable bit
+const UARTEN_BIT: u32 = 1 << 0;
+/// UART CR register RTS hardware flow control enable bit
+const RTSEN_BIT: u32 = 1 << 14;
+/// UART CR register CTS hardware flow control enable bit
+const CTSEN_BIT: u32 = 1 << 15;
 
-/// Initialize UART0 pointer for Core1 (called once before spawning Core1)
-pub fn init_uart_ptr() {
-    let uart0_addr = pac::UART0::ptr() as *mut pac::uart0::RegisterBlock;
-    UART0_PTR.store(uart0_addr, Ordering::Release);
+/// UART LCR_H register send break bit
+const BRK_BIT: u32 = 1 << 0;
+/// UART LCR_H register parity enable bit
+const PEN_BIT: u32 = 1 << 1;
+/// UART LCR_H register even parity select bit
+const EPS_BIT: u32 = 1 << 2;
+/// UART LCR_H register two stop bits select bit
+const STP2_BIT: u32 = 1 << 3;
+/// UART LCR_H register FIFO enable bit
+const FEN_BIT: u32 = 1 << 4;
+/// UART LCR_H register word length field offset
+const WLEN_SHIFT: u32 = 5;
+/// UART LCR_H register stick parity select bit
+const SPS_BIT: u32 = 1 << 7;
+
+/// One bridge channel as seen by the two cores, implemented by the hardware
+/// UARTs (`UartPort`) and the PIO UARTs (`pio_uart::PioUartPort`).
+///
+/// `request_line_config`, `set_break` and `line_errors` are called from
+/// Core0; everything else only from Core1. The Core0 requests end with SEV
+/// so a sleeping Core1 picks them up.
+pub trait BridgePort: Sync {
+    /// Request a new line configuration (called from Core0)
+    fn request_line_config(&self, config: LineConfig);
+
+    /// Start or stop driving a break on TX (called from Core0)
+    fn set_break(&self, asserted: bool);
+
+    /// Current RX line error counters
+    fn line_errors(&self) -> LineErrors;
+
+    /// Baud rates the port's clock divider can produce
+    fn baud_range(&self) -> RangeInclusive<u32>;
+
+    /// Apply the line configuration, break and flow control requested by
+    /// Core0, if they changed (called from Core1)
+    fn apply_pending(&self);
+
+    /// Copy received data into `buf` and return the number of bytes, counting
+    /// line errors on the way. Breaks are counted, not returned as data.
+    fn read(&self, buf: &mut [u8]) -> usize;
+
+    /// Start transmitting data from `queue`, releasing what has been sent.
+    /// `stalled` means queued data is waiting for TX room.
+    fn transmit(&self, queue: &mut Consumer<'static, FIFO_BUFFER_SIZE>) -> Pumped;
+
+    /// Set up the port for the time until the next service pass: `rx` false
+    /// stops taking data off the line (so RTS flow control or an overrun
+    /// kicks in), `tx` asks for an interrupt once more data can be written.
+    fn arm(&self, rx: bool, tx: bool);
 }
 
-/// Get UART0 reference (safe after init_uart_ptr)
-#[inline]
-fn uart0() -> &'static pac::uart0::RegisterBlock {
-    let ptr = UART0_PTR.load(Ordering::Acquire);
-    debug_assert!(!ptr.is_null(), "UART0 not initialized");
-    unsafe { &*ptr }
+/// A `BridgePort` as seen by the data path: the UART side of
+/// `pump::uart_to_host` on Core1 and the `UartControl` of
+/// `host::HostChannel` on Core0
+pub struct PortIo<'a, P: ?Sized>(pub &'a P);
+
+impl<P: BridgePort + ?Sized> ByteSource for PortIo<'_, P> {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.0.read(buf)
+    }
 }
 
-/// Returns true if UART0 has readable data
-#[inline]
-pub fn is_readable() -> bool {
-    (uart0().uartfr().read().bits() & RXFE_BIT) == 0
+impl<P: BridgePort + ?Sized> UartControl for PortIo<'_, P> {
+    fn set_line_config(&mut self, config: LineConfig) {
+        self.0.request_line_config(config);
+    }
+
+    fn set_break(&mut self, asserted: bool) {
+        self.0.set_break(asserted);
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        self.0.line_errors()
+    }
 }
 
-/// Reads one byte from UART0 (ensure is_readable() is true first)
-#[inline]
-pub fn read_byte() -> u8 {
-    (uart0().uartdr().read().bits() & 0xFF) as u8
+/// Core1-side state of one hardware UART.
+///
+/// Every field is an atomic with a single writer: the `request_*`/`set_*`
+/// methods are called from Core0 and the `apply_*`/RX/TX methods from Core1.
+/// Only plain loads/stores are used so this also works on the Cortex-M0+
+/// which has no atomic read-modify-write instructions.
+pub struct UartPort {
+    /// Index into `PORTS` and the DMA buffers
+    index: usize,
+    /// DMA channel filling the RX ring
+    rx_dma: Channel,
+    /// DMA channel feeding the TX FIFO from the queue
+    tx_dma: Channel,
+    /// DMA channel `tx_dma` chains to for queued data that wrapped around
+    tx_wrap_dma: Channel,
+    /// Register block pointer, initialized once
+    regs: AtomicPtr<pac::uart0::RegisterBlock>,
+    /// UART reference clock (clk_peri) in Hz, used for baud divisor calculation
+    clock_hz: AtomicU32,
+
+    /// Line configuration mailbox: Core0 writes the fields and then bumps
+    /// `line_config_seq`; Core1 applies the configuration whenever the
+    /// sequence differs from the last one it applied.
+    line_config_baud: AtomicU32,
+    line_config_format: AtomicU8,
+    line_config_seq: AtomicU32,
+    line_config_applied: AtomicU32,
+
+    /// Hardware flow control requested / currently enabled in UARTCR
+    flow_control_requested: AtomicBool,
+    flow_control_active: AtomicBool,
+
+    /// Break state requested by the host / currently driven on TX
+    break_requested: AtomicBool,
+    break_active: AtomicBool,
+
+    /// Queued bytes handed to the TX DMA, released once it has sent them
+    tx_sending: AtomicU32,
+    /// The current transmission goes on to `tx_wrap_dma`
+    tx_wrapped: AtomicBool,
+    /// Read address the last TX channel stops at
+    tx_end: AtomicU32,
+
+    /// Ring position Core1 has copied out up to
+    rx_read: AtomicU32,
+    /// Bytes the RX DMA has stored that Core1 has not copied out yet; more
+    /// than the ring means the DMA has overwritten some of them
+    rx_unread: AtomicU32,
+    /// RX DMA transfer count when `rx_unread` was last brought up to date
+    rx_dma_remaining: AtomicU32,
+    /// Ring position of the 0x00 a break left in the data, or `NO_BREAK_NUL`
+    break_nul: AtomicU32,
+
+    /// Line error counters for RX
+    framing_errors: AtomicU32,
+    parity_errors: AtomicU32,
+    breaks: AtomicU32,
+    overruns: AtomicU32,
 }
 
-/// Returns true if UART0 TX FIFO is full
-#[inline]
-pub fn is_tx_full() -> bool {
-    (uart0().uartfr().read().bits() & TXFF_BIT) != 0
+/// UART0 and UART1, indexed by bridge channel
+pub static PORTS: [UartPort; crate::HW_UART_CHANNELS] = [UartPort::new(0), UartPort::new(1)];
+
+/// Initialize the UARTs and their DMA channels for Core1 (called once before
+/// spawning Core1). DMA channels 0-5 are used.
+pub fn init_uart_ptrs(uart_clock_hz: u32) {
+    PORTS[0].init(pac::UART0::ptr(), uart_clock_hz);
+    PORTS[1].init(pac::UART1::ptr(), uart_clock_hz);
 }
 
-/// Writes one byte to UART0 (TX FIFO must not be full)
+/// Single-writer increment (Core1 is the only writer, so load/store suffices)
 #[inline]
-pub fn write_byte(b: u8) {
-    uart0().uartdr().write(|w| unsafe { w.bits(u32::from(b)) });
+pub(crate) fn increment(counter: &AtomicU32) {
+    let count = counter.load(Ordering::Relaxed).wrapping_add(1);
+    counter.store(count, Ordering::Release);
+}
+
+/// Compute the PL011 integer and fractional baud divisors
+fn baud_divisors(clock_hz: u32, baud: u32) -> (u32, u32) {
+    let baud_div = (8 * u64::from(clock_hz)) / u64::from(baud);
+    match baud_div >> 7 {
+        0 => (1, 0),
+        ibrd if ibrd >= 0xFFFF => (0xFFFF, 0),
+        ibrd => (ibrd as u32, (baud_div & 0x7F).div_ceil(2) as u32),
+    }
+}
+
+impl UartPort {
+    const fn new(index: usize) -> Self {
+        Self {
+            index,
+            rx_dma: Channel(2 * index),
+            tx_dma: Channel(2 * index + 1),
+            tx_wrap_dma: Channel(2 * crate::HW_UART_CHANNELS + index),
+            regs: AtomicPtr::new(core::ptr::null_mut()),
+            clock_hz: AtomicU32::new(0),
+            line_config_baud: AtomicU32::new(0),
+            line_config_format: AtomicU8::new(0),
+            line_config_seq: AtomicU32::new(0),
+            line_config_applied: AtomicU32::new(0),
+            flow_control_requested: AtomicBool::new(false),
+            flow_control_active: AtomicBool::new(false),
+            break_requested: AtomicBool::new(false),
+            break_active: AtomicBool::new(false),
+            tx_sending: AtomicU32::new(0),
+            tx_wrapped: AtomicBool::new(false),
+            tx_end: AtomicU32::new(0),
+            rx_read: AtomicU32::new(0),
+            rx_unread: AtomicU32::new(0),
+            rx_dma_remaining: AtomicU32::new(0),
+            break_nul: AtomicU32::new(NO_BREAK_NUL),
+            framing_errors: AtomicU32::new(0),
+            parity_errors: AtomicU32::new(0),
+            breaks: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+        }
+    }
+
+    fn init(&self, regs: *const pac::uart0::RegisterBlock, uart_clock_hz: u32) {
+        self.clock_hz.store(uart_clock_hz, Ordering::Relaxed);
+        self.regs
+            .store(regs as *mut pac::uart0::RegisterBlock, Ordering::Release);
+
+        self.start_rx_dma();
+        self.tx_dma.enable_irq0();
+        self.tx_wrap_dma.enable_irq0();
+        let uart = self.uart();
+        uart.uartdmacr()
+            .write(|w| unsafe { w.bits(RXDMAE_BIT | TXDMAE_BIT) });
+        uart.uartimsc()
+            .write(|w| unsafe { w.bits(ERROR_INTERRUPT_BITS) });
+    }
+
+    /// Acknowledge the TX DMA completion interrupts (called from DMA_IRQ_0)
+    #[inline]
+    pub fn ack_tx_dma(&self) {
+        self.tx_dma.ack_irq0();
+        self.tx_wrap_dma.ack_irq0();
+    }
+
+    /// Start (or restart) the RX DMA channel at the current ring position
+    fn start_rx_dma(&self) {
+        let ring = self.rx_ring() as u32;
+        let position = self.rx_dma_position() as u32;
+        self.count_rx_dma();
+        self.rx_dma_remaining
+            .store(dma::ENDLESS & dma::COUNT_MASK, Ordering::Relaxed);
+        self.rx_dma.start(
+            self.uart().uartdr().as_ptr() as u32,
+            ring + position,
+            dma::ENDLESS,
+            dma::Mode::FromPeripheralRing {
+                ring_bits: RX_RING_BITS,
+            },
+            dma::DREQ_UART_RX[self.index],
+        );
+    }
+
+    /// Start of this UART's RX ring
+    #[inline]
+    fn rx_ring(&self) -> *const u8 {
+        unsafe { core::ptr::addr_of!(RX_RINGS[self.index]) as *const u8 }
+    }
+
+    /// Ring position the RX DMA writes next
+    #[inline]
+    fn rx_dma_position(&self) -> usize {
+        self.rx_dma.write_addr() as usize & (RX_RING_SIZE - 1)
+    }
+
+    /// Add what the RX DMA has stored since the last call to `rx_unread`
+    fn count_rx_dma(&self) {
+        let remaining = self.rx_dma.remaining();
+        let stored = self
+            .rx_dma_remaining
+            .load(Ordering::Relaxed)
+            .wrapping_sub(remaining)
+            & dma::COUNT_MASK;
+        self.rx_dma_remaining.store(remaining, Ordering::Relaxed);
+        let unread = self
+            .rx_unread
+            .load(Ordering::Relaxed)
+            .saturating_add(stored);
+        self.rx_unread.store(unread, Ordering::Relaxed);
+    }
+
+    /// Get the register block (safe after init_uart_ptrs)
+    #[inline]
+    fn uart(&self) -> &'static pac::uart0::RegisterBlock {
+        let ptr = self.regs.load(Ordering::Acquire);
+        debug_assert!(!ptr.is_null(), "UART not initialized");
+        unsafe { &*ptr }
+    }
+
+    /// Count the line errors flagged since the last call (called from Core1).
+    ///
+    /// The DMA only moves data bytes and loses the error flags that come
+    /// with each of them, so errors come from the sticky RSR flags and the
+    /// counts are approximate: each increment means at least one error of
+    /// that kind since the last pass, and a burst of them between two passes
+    /// counts once. A break also sets the framing error bit; it is only
+    /// counted as a break, and the 0x00 it put into the data is marked to be
+    /// dropped.
+    fn record_errors(&self) {
+        let uart = self.uart();
+        let rsr = uart.uartrsr().read().bits() & RSR_ERROR_BITS;
+        if rsr == 0 {
+            return;
+        }
+        uart.uartrsr().write(|w| unsafe { w.bits(0) });
+        uart.uarticr()
+            .write(|w| unsafe { w.bits(ERROR_INTERRUPT_BITS) });
+
+        if (rsr & RSR_BE_BIT) != 0 {
+            increment(&self.breaks);
+            self.mark_break_nul();
+        } else {
+            if (rsr & RSR_FE_BIT) != 0 {
+                increment(&self.framing_errors);
+            }
+            if (rsr & RSR_PE_BIT) != 0 {
+                increment(&self.parity_errors);
+            }
+        }
+        if (rsr & RSR_OE_BIT) != 0 {
+            increment(&self.overruns);
+        }
+    }
+
+    /// Mark the ring position of the 0x00 a break has just produced. The
+    /// error interrupt wakes Core1 right away and the line stays idle after
+    /// a break, so the 0x00 is the last byte the DMA stored if the RX FIFO
+    /// is empty, or else the next one it stores. `read` only drops the byte
+    /// at exactly that position, and only if it is 0x00; no other 0x00 is
+    /// taken for it.
+    fn mark_break_nul(&self) {
+        let read = self.rx_read.load(Ordering::Relaxed) as usize;
+        let position = self.rx_dma_position();
+        let position = if self.uart().uartfr().read().bits() & RXFE_BIT == 0 {
+            position
+        } else if position != read {
+            (position + RX_RING_SIZE - 1) & (RX_RING_SIZE - 1)
+        } else {
+            // Copied out already
+            return;
+        };
+        self.break_nul.store(position as u32, Ordering::Relaxed);
+    }
+
+    /// True while Core0 has requested a line configuration Core1 has not
+    /// applied yet
+    #[inline]
+    fn line_config_pending(&self) -> bool {
+        self.line_config_seq.load(Ordering::Acquire)
+            != self.line_config_applied.load(Ordering::Relaxed)
+    }
+
+    /// True once the TX DMA has moved the last byte of the current
+    /// transmission. The wrap channel is checked by its read address: it is
+    /// not busy either until `tx_dma` chains to it.
+    fn tx_dma_done(&self) -> bool {
+        let last = if self.tx_wrapped.load(Ordering::Relaxed) {
+            self.tx_wrap_dma
+        } else {
+            self.tx_dma
+        };
+        !self.tx_dma.is_busy()
+            && !last.is_busy()
+            && last.read_addr() == self.tx_end.load(Ordering::Relaxed)
+    }
+
+    /// True once the TX DMA and the transmitter have finished
+    fn tx_idle(&self) -> bool {
+        self.tx_dma_done() && (self.uart().uartfr().read().bits() & BUSY_BIT) == 0
+    }
+
+    /// Apply a pending line configuration, if any (called from Core1).
+    ///
+    /// The data in flight is sent with the old configuration first. Until
+    /// TX is idle the configuration stays pending and is retried on the
+    /// next pass, while `transmit` holds back new data; with CTS flow
+    /// control the target may keep TX busy for as long as it likes.
+    fn apply_pending_line_config(&self) {
+        let seq = self.line_config_seq.load(Ordering::Acquire);
+        if seq == self.line_config_applied.load(Ordering::Relaxed) || !self.tx_idle() {
+            return;
+        }
+        self.line_config_applied.store(seq, Ordering::Relaxed);
+
+        let config = LineConfig::from_parts(
+            self.line_config_baud.load(Ordering::Relaxed),
+            self.line_config_format.load(Ordering::Relaxed),
+        );
+        self.configure(&config);
+    }
+
+    /// Select the hardware flow control mode (called from Core0).
+    ///
+    /// RTS/CTS only has an effect if the board has muxed the UART's CTS/RTS
+    /// pins, which it does at boot when the saved configuration or
+    /// `UART_FLOW_CONTROL` selects `RtsCts`.
+    pub fn request_flow_control(&self, mode: FlowControl) {
+        self.flow_control_requested
+            .store(mode == FlowControl::RtsCts, Ordering::Release);
+        cortex_m::asm::sev();
+    }
+
+    /// Enable or disable PL011 CTSEn/RTSEn to match the request (called from Core1)
+    fn apply_pending_flow_control(&self) {
+        let requested = self.flow_control_requested.load(Ordering::Acquire);
+        if requested == self.flow_control_active.load(Ordering::Relaxed) {
+            return;
+        }
+        self.flow_control_active.store(requested, Ordering::Relaxed);
+
+        let uart = self.uart();
+        let cr = uart.uartcr().read().bits();
+        let cr = if requested {
+            cr | CTSEN_BIT | RTSEN_BIT
+        } else {
+            cr & !(CTSEN_BIT | RTSEN_BIT)
+        };
+        uart.uartcr().write(|w| unsafe { w.bits(cr) });
+    }
+
+    /// Drive TX to match the requested break state (called from Core1).
+    ///
+    /// The PL011 starts the break after the character currently being shifted
+    /// out, so no transmitted data is cut short.
+    fn apply_pending_break(&self) {
+        let requested = self.break_requested.load(Ordering::Acquire);
+        if requested == self.break_active.load(Ordering::Relaxed) {
+            return;
+        }
+        self.break_active.store(requested, Ordering::Relaxed);
+
+        let uart = self.uart();
+        let lcr_h = uart.uartlcr_h().read().bits();
+        let lcr_h = if requested {
+            lcr_h | BRK_BIT
+        } else {
+            lcr_h & !BRK_BIT
+        };
+        uart.uartlcr_h().write(|w| unsafe { w.bits(lcr_h) });
+    }
+
+    /// Reprogram baud rate and character format (TX must be idle).
+    ///
+    /// Disables the UART while the divisors and LCR_H are updated (LCR_H
+    /// must be written last to latch the divisors). The board leaves the
+    /// UART disabled, so the first configuration is what enables it.
+    fn configure(&self, config: &LineConfig) {
+        let uart = self.uart();
+
+        let cr = uart.uartcr().read().bits();
+        uart.uartcr().write(|w| unsafe { w.bits(cr & !UARTEN_BIT) });
+
+        let (ibrd, fbrd) = baud_divisors(self.clock_hz.load(Ordering::Relaxed), config.baud);
+        uart.uartibrd().write(|w| unsafe { w.bits(ibrd) });
+        uart.uartfbrd().write(|w| unsafe { w.bits(fbrd) });
+
+        let wlen = match config.data_bits {
+            DataBits::Five => 0,
+            DataBits::Six => 1,
+            DataBits::Seven => 2,
+            DataBits::Eight => 3,
+        };
+        let mut lcr_h = FEN_BIT | (wlen << WLEN_SHIFT);
+        lcr_h |= match config.parity {
+            Parity::None => 0,
+            Parity::Odd => PEN_BIT,
+            Parity::Even => PEN_BIT | EPS_BIT,
+            Parity::Mark => PEN_BIT | SPS_BIT,
+            Parity::Space => PEN_BIT | EPS_BIT | SPS_BIT,
+        };
+        if config.stop_bits == StopBits::Two {
+            lcr_h |= STP2_BIT;
+        }
+        if self.break_active.load(Ordering::Relaxed) {
+            lcr_h |= BRK_BIT;
+        }
+        uart.uartlcr_h().write(|w| unsafe { w.bits(lcr_h) });
+
+        uart.uartcr().write(|w| unsafe { w.bits(cr | UARTEN_BIT) });
+    }
+}
+
+impl BridgePort for UartPort {
+    fn request_line_config(&self, config: LineConfig) {
+        self.line_config_baud.store(config.baud, Ordering::Relaxed);
+        self.line_config_format
+            .store(config.format_bits(), Ordering::Relaxed);
+        let seq = self.line_config_seq.load(Ordering::Relaxed).wrapping_add(1);
+        self.line_config_seq.store(seq, Ordering::Release);
+        cortex_m::asm::sev();
+    }
+
+    fn set_break(&self, asserted: bool) {
+        self.break_requested.store(asserted, Ordering::Release);
+        cortex_m::asm::sev();
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        LineErrors {
+            framing: self.framing_errors.load(Ordering::Acquire),
+            parity: self.parity_errors.load(Ordering::Acquire),
+            breaks: self.breaks.load(Ordering::Acquire),
+            overrun: self.overruns.load(Ordering::Acquire),
+        }
+    }
+
+    /// From the largest divisor (65535 + 63/64) up to clk_peri / 16, the
+    /// smallest (1.0)
+    fn baud_range(&self) -> RangeInclusive<u32> {
+        let clock_hz = self.clock_hz.load(Ordering::Relaxed);
+        clock_hz.div_ceil(16 << 16)..=clock_hz / 16
+    }
+
+    fn apply_pending(&self) {
+        self.apply_pending_line_config();
+        self.apply_pending_break();
+        self.apply_pending_flow_control();
+
+        // Only the RP2040 ever runs out of transfer count
+        if !self.rx_dma.is_busy() {
+            self.start_rx_dma();
+        }
+    }
+
+    /// If the DMA has come round the ring onto data not copied out yet (the
+    /// queue to the host stayed full, or Core1 was parked for a flash
+    /// write), the overwritten bytes are skipped and counted as overruns.
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.record_errors();
+        self.count_rx_dma();
+
+        let ring = self.rx_ring();
+        let mut read = self.rx_read.load(Ordering::Relaxed) as usize;
+        let mut unread = self.rx_unread.load(Ordering::Relaxed) as usize;
+        if unread > RX_RING_SIZE - RX_LAP_MARGIN {
+            let lost = unread - (RX_RING_SIZE - RX_LAP_MARGIN);
+            let break_nul = self.break_nul.load(Ordering::Relaxed) as usize;
+            if break_nul.wrapping_sub(read) & (RX_RING_SIZE - 1) < lost.min(RX_RING_SIZE) {
+                self.break_nul.store(NO_BREAK_NUL, Ordering::Relaxed);
+            }
+            read = (read + lost) & (RX_RING_SIZE - 1);
+            unread -= lost;
+            let overruns = self.overruns.load(Ordering::Relaxed);
+            self.overruns
+                .store(overruns.wrapping_add(lost as u32), Ordering::Release);
+        }
+
+        let mut count = 0;
+        while count < buf.len() && unread > 0 {
+            let byte = unsafe { core::ptr::read_volatile(ring.add(read)) };
+            let break_nul = read as u32 == self.break_nul.load(Ordering::Relaxed);
+            if break_nul {
+                self.break_nul.store(NO_BREAK_NUL, Ordering::Relaxed);
+            }
+            if !break_nul || byte != 0 {
+                buf[count] = byte;
+                count += 1;
+            }
+            read = (read + 1) & (RX_RING_SIZE - 1);
+            unread -= 1;
+        }
+        self.rx_read.store(read as u32, Ordering::Relaxed);
+        self.rx_unread.store(unread as u32, Ordering::Relaxed);
+        count
+    }
+
+    /// The TX DMA reads straight from the queue: `tx_dma` sends what is
+    /// queued up to the end of the buffer and chains to `tx_wrap_dma` for
+    /// the part that wrapped to its start, so Core1 only comes back once all
+    /// of it is out. The bytes stay queued until then and are released on
+    /// that pass. A pending line configuration holds back new transfers.
+    fn transmit(&self, queue: &mut Consumer<'static, FIFO_BUFFER_SIZE>) -> Pumped {
+        let sending = self.tx_sending.load(Ordering::Relaxed) as usize;
+        if !self.tx_dma_done() {
+            return Pumped {
+                moved: false,
+                stalled: queue.len() > sending,
+            };
+        }
+        let mut pumped = Pumped::default();
+        if sending > 0 {
+            queue.release(sending);
+            self.tx_sending.store(0, Ordering::Relaxed);
+            pumped.moved = true;
+        }
+        if queue.is_empty() {
+            return pumped;
+        }
+        if self.line_config_pending() {
+            pumped.stalled = true;
+            return pumped;
+        }
+
+        let (data, wrapped) = queue.read_grants();
+        let uartdr = self.uart().uartdr().as_ptr() as u32;
+        let dreq = dma::DREQ_UART_TX[self.index];
+        let (last, last_data) = if wrapped.is_empty() {
+            (self.tx_dma, data)
+        } else {
+            self.tx_wrap_dma.load(
+                wrapped.as_ptr() as u32,
+                uartdr,
+                wrapped.len() as u32,
+                dma::Mode::ToPeripheral,
+                dreq,
+            );
+            (self.tx_wrap_dma, wrapped)
+        };
+        self.tx_sending
+            .store((data.len() + wrapped.len()) as u32, Ordering::Relaxed);
+        self.tx_wrapped
+            .store(!wrapped.is_empty(), Ordering::Relaxed);
+        self.tx_end.store(
+            last_data.as_ptr() as u32 + last_data.len() as u32,
+            Ordering::Relaxed,
+        );
+
+        // Core0's writes to the queue must be visible to the DMA before it
+        // starts reading
+        cortex_m::asm::dmb();
+        self.tx_dma.start_then(
+            data.as_ptr() as u32,
+            uartdr,
+            data.len() as u32,
+            dma::Mode::ToPeripheral,
+            dreq,
+            last,
+        );
+        pumped
+    }
+
+    /// `rx` switches the RX DMA request: while it is off the data backs up in
+    /// the PL011 RX FIFO, which deasserts RTS with flow control enabled. The
+    /// error interrupts are masked meanwhile, as an overrun would stay
+    /// pending until the next read. TX needs no arming; every finished DMA
+    /// transfer raises DMA_IRQ_0.
+    fn arm(&self, rx: bool, _tx: bool) {
+        let uart = self.uart();
+        if rx {
+            uart.uartdmacr()
+                .write(|w| unsafe { w.bits(RXDMAE_BIT | TXDMAE_BIT) });
+            uart.uartimsc()
+                .write(|w| unsafe { w.bits(ERROR_INTERRUPT_BITS) });
+        } else {
+            uart.uartdmacr().write(|w| unsafe { w.bits(TXDMAE_BIT) });
+            uart.uartimsc().write(|w| unsafe { w.bits(0) });
+        }
+    }
 }
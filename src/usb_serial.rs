@@ -1,8 +1,403 @@
-// Thin wrapper that selects the correct board-specific USB implementation
-#[cfg(feature = "rp2040")]
-pub use crate::board::rp2040::usb::*;
+//! The composite USB device: one CDC-ACM function per bridge channel, plus
+//! a control function running the configuration shell
+//!
+//! Each channel's host→UART data passes through its `Escape`, which opens
+//! the in-band escape menu when the host types the escape sequence.
+//!
+//! Everything here is generic over `UsbBus`; the board module only builds
+//! the bus (`board::make_usb_bus`), so both chips enumerate identically.
+//!
+//! Five CDC-ACM functions no longer fit usb-device's 256-byte control
+//! buffer, so the configuration descriptor is written once into
+//! `CONFIG_DESCRIPTOR` and served by a `ConfigDescriptor` class.
 
-#[cfg(feature = "rp2350")]
-pub use crate::board::rp2350::usb::*;
+use crate::board::{ActiveBoard, Board, BoardUsbBus};
+use crate::cdc_acm::CdcAcmClass;
+use crate::settings::Settings;
+use crate::uart_config::LineConfig;
+use crate::uart_core1::PortIo;
+use crate::{
+    ESCAPE_BREAK_MS, UART_BAUD_RATE, UART_CHANNELS, UART_ESCAPE, UART_EVENT_CHAR,
+    UART_LATENCY_TIMER_US, bridge_port, power_on_line_config,
+};
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::Mutex;
+use picoterm_bridge::ByteSource;
+use picoterm_bridge::config::{Config, ShortString};
+use picoterm_bridge::descriptor::{ConfigDescriptor, ConfigDescriptorWriter};
+use picoterm_bridge::escape::{Escape, MenuTarget};
+use picoterm_bridge::host::HostChannel;
+use picoterm_bridge::shell::Shell;
+use picoterm_bridge::stats::LineErrors;
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::prelude::*;
 
-// If neither is selected, compile-time error will be raised by board/bsp.rs
+/// USB vendor/product ID (Raspberry Pi's VID, the Pico SDK's CDC PID)
+const USB_VID_PID: UsbVidPid = UsbVidPid(0x2E8A, 0x000A);
+/// Max packet size of endpoint 0 and of the CDC bulk endpoints
+const USB_MAX_PACKET_SIZE: u16 = 64;
+/// CDC-ACM functions: one per bridge channel, then the control function
+const CDC_FUNCTIONS: usize = UART_CHANNELS + 1;
+/// Configuration descriptor: 9-byte header plus 61 bytes per CDC-ACM function
+const CONFIG_DESCRIPTOR_LEN: usize = 9 + 61 * CDC_FUNCTIONS;
+
+/// The configuration descriptor, written once by `UsbSerial::new`
+static mut CONFIG_DESCRIPTOR: [u8; CONFIG_DESCRIPTOR_LEN] = [0; CONFIG_DESCRIPTOR_LEN];
+
+/// The USB device, its CDC-ACM functions and the configuration shell
+pub struct UsbSerial<'a, B: UsbBus> {
+    device: UsbDevice<'a, B>,
+    descriptor: ConfigDescriptor,
+    serials: [CdcAcmClass<'a, B>; UART_CHANNELS],
+    escapes: [Escape; UART_CHANNELS],
+    /// The control function, after the bridge channels
+    control: CdcAcmClass<'a, B>,
+    shell: Shell,
+    settings: Settings,
+}
+
+impl<'a, B: UsbBus> UsbSerial<'a, B> {
+    /// Allocate one CDC-ACM function per channel and the control function
+    /// on `bus` and build the composite device around them. Each channel's
+    /// function starts out with its power-on line coding; the USB strings
+    /// are the saved ones, or the board file's.
+    ///
+    /// # Panics
+    /// If called more than once (the configuration descriptor is static)
+    pub fn new(bus: &'a UsbBusAllocator<B>, config: &'a Config) -> Self {
+        let serials: [CdcAcmClass<'a, B>; UART_CHANNELS] = core::array::from_fn(|channel| {
+            let line_coding = power_on_line_config(config, channel).to_line_coding();
+            CdcAcmClass::with_line_coding(bus, USB_MAX_PACKET_SIZE, line_coding)
+        });
+        let control = CdcAcmClass::new(bus, USB_MAX_PACKET_SIZE);
+
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(CONFIG_DESCRIPTOR) };
+        let mut writer = ConfigDescriptorWriter::new(buf).unwrap();
+        for serial in serials.iter().chain([&control]) {
+            serial.write_descriptors(&mut writer).unwrap();
+        }
+        let descriptor = ConfigDescriptor::new(writer.finish());
+
+        let usb = &config.usb;
+        let device = UsbDeviceBuilder::new(bus, USB_VID_PID)
+            .strings(&[StringDescriptors::default()
+                .manufacturer(usb_string(&usb.manufacturer, ActiveBoard::USB_MANUFACTURER))
+                .product(usb_string(&usb.product, ActiveBoard::USB_PRODUCT))
+                .serial_number(usb_string(
+                    &usb.serial_number,
+                    ActiveBoard::USB_SERIAL_NUMBER,
+                ))])
+            .unwrap()
+            .max_packet_size_0(USB_MAX_PACKET_SIZE as u8)
+            .unwrap()
+            .composite_with_iads()
+            .build();
+        Self {
+            device,
+            descriptor,
+            serials,
+            escapes: [const { Escape::new(UART_ESCAPE) }; UART_CHANNELS],
+            control,
+            shell: Shell::new(),
+            settings: Settings::new(config),
+        }
+    }
+
+    /// Poll the device and move data between each CDC function and its
+    /// channel's FIFOs. Returns true if there was USB activity or data moved.
+    pub fn poll(&mut self) -> bool {
+        let mut classes = core::iter::once(&mut self.descriptor as &mut dyn UsbClass<B>)
+            .chain(
+                self.serials
+                    .iter_mut()
+                    .map(|serial| serial as &mut dyn UsbClass<B>),
+            )
+            .chain(core::iter::once(&mut self.control as &mut dyn UsbClass<B>));
+        let mut classes: [&mut dyn UsbClass<B>; CDC_FUNCTIONS + 1] =
+            core::array::from_fn(|_| classes.next().unwrap());
+        let has_usb_event = self.device.poll(&mut classes);
+        let configured = self.device.state() == UsbDeviceState::Configured;
+
+        let mut data_received = false;
+        let mut data_sent = false;
+        let channels = self.serials.iter_mut().zip(&mut self.escapes);
+        for (channel, (serial, escape)) in channels.enumerate() {
+            sync_control_state(serial, channel, configured);
+
+            // Read from USB CDC (PC -> device -> UART), leaving the packet
+            // in the endpoint while the FIFO to the UART is full
+            if receive_from_host(serial, channel, escape) {
+                data_received = true;
+            }
+
+            // Forward UART data to USB CDC (always check, not just when
+            // has_usb_event). While the escape menu is open its text goes
+            // to the host instead, and the UART data waits in the FIFO.
+            let sent = if escape.is_active() {
+                escape.send(serial).unwrap_or(false)
+            } else {
+                send_to_host(serial, channel)
+            };
+            if sent {
+                data_sent = true;
+            }
+        }
+
+        let shell_active = configured && self.run_shell();
+
+        has_usb_event || data_received || data_sent || shell_active
+    }
+
+    /// Feed what the host typed on the control function to the shell and
+    /// send back its reply. Returns true if anything moved.
+    fn run_shell(&mut self) -> bool {
+        let mut packet = [0u8; USB_MAX_PACKET_SIZE as usize];
+        let count = self.control.read(&mut packet);
+        if count > 0 {
+            self.shell.receive(&packet[..count], &mut self.settings);
+        }
+        self.shell.poll(&mut self.settings);
+        // A reply the host cannot take is dropped
+        let sent = self.shell.send(&mut self.control).unwrap_or(false);
+        count > 0 || sent
+    }
+}
+
+/// The saved USB string, or the board file's
+fn usb_string<'a>(saved: &'a Option<ShortString>, board: &'static str) -> &'a str {
+    saved.as_ref().map_or(board, ShortString::as_str)
+}
+
+/// Static USB bus allocator storage (initialized once during init_usb)
+static mut USB_BUS: MaybeUninit<UsbBusAllocator<BoardUsbBus>> = MaybeUninit::uninit();
+/// Static USB device storage (initialized once during init_usb)
+static mut USB_SERIAL: MaybeUninit<UsbSerial<'static, BoardUsbBus>> = MaybeUninit::uninit();
+
+/// Initialization flag to ensure single initialization
+static USB_INITIALIZED: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
+
+/// Initialize the composite USB device on the board's USB controller, with
+/// the strings and power-on line codings of `config`
+///
+/// # Safety
+/// Must be called exactly once before handle_usb_serial()
+pub fn init_usb(bus: BoardUsbBus, config: &'static Config) {
+    cortex_m::interrupt::free(|cs| {
+        if *USB_INITIALIZED.borrow(cs).borrow() {
+            panic!("USB already initialized");
+        }
+        *USB_INITIALIZED.borrow(cs).borrow_mut() = true;
+    });
+
+    unsafe {
+        let bus_ptr = core::ptr::addr_of_mut!(USB_BUS);
+        (*bus_ptr).as_mut_ptr().write(UsbBusAllocator::new(bus));
+        let usb_bus: &'static UsbBusAllocator<BoardUsbBus> = &*(*bus_ptr).as_ptr();
+
+        let usb_ptr = core::ptr::addr_of_mut!(USB_SERIAL);
+        (*usb_ptr)
+            .as_mut_ptr()
+            .write(UsbSerial::new(usb_bus, config));
+    }
+
+    // The UARTs start out with the same line configuration
+    for channel in 0..UART_CHANNELS {
+        *host_channel(channel) = HostChannel::new(
+            power_on_line_config(config, channel),
+            UART_LATENCY_TIMER_US,
+            UART_EVENT_CHAR,
+        );
+    }
+}
+
+/// Handle USB serial communication
+///
+/// Polls USB device and transfers data between USB CDC and UART FIFOs.
+/// Called from the USB and doorbell interrupts on Core0.
+/// Returns true if there was USB activity or data was transmitted.
+pub fn handle_usb_serial() -> bool {
+    unsafe {
+        let usb_ptr = core::ptr::addr_of_mut!(USB_SERIAL);
+        (*(*usb_ptr).as_mut_ptr()).poll()
+    }
+}
+
+/// Data path state of each channel's CDC function (only touched from Core0)
+static mut HOST_CHANNELS: [HostChannel; UART_CHANNELS] = [const {
+    HostChannel::new(
+        LineConfig::eight_n_one(UART_BAUD_RATE),
+        UART_LATENCY_TIMER_US,
+        UART_EVENT_CHAR,
+    )
+}; UART_CHANNELS];
+/// SEND_BREAK requests waiting for the main loop, per channel (only touched from Core0)
+static mut BREAK_REQUESTS: [Option<u16>; UART_CHANNELS] = [None; UART_CHANNELS];
+/// Target reset from the escape menu, waiting for the main loop
+static RESET_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Data path state of `channel` (Core0 only, never held across calls)
+#[inline]
+fn host_channel(channel: usize) -> &'static mut HostChannel {
+    unsafe { &mut (*core::ptr::addr_of_mut!(HOST_CHANNELS))[channel] }
+}
+
+/// Line configuration `channel` runs with (Core0)
+pub fn line_config(channel: usize) -> LineConfig {
+    host_channel(channel).line_config()
+}
+
+/// Switch `channel`'s UART to `baud`, keeping the character format (Core0)
+pub fn set_baud(channel: usize, baud: u32) {
+    let host = host_channel(channel);
+    let config = LineConfig {
+        baud,
+        ..host.line_config()
+    };
+    host.set_line_config(config, &mut PortIo(bridge_port(channel)));
+}
+
+/// UART→USB bytes of `channel` lost because the IN endpoint failed (Core0)
+pub fn dropped(channel: usize) -> u32 {
+    host_channel(channel).dropped()
+}
+
+/// Exchange control state between a channel's CDC function and its UART:
+/// line coding, DTR/RTS, SEND_BREAK requests and SERIAL_STATE notifications
+/// (see `HostChannel::sync_cdc`).
+///
+/// Only channel 0 has DTR/RTS output pins.
+fn sync_control_state<B: UsbBus>(
+    serial: &mut CdcAcmClass<'_, B>,
+    channel: usize,
+    configured: bool,
+) {
+    if channel == 0 {
+        crate::control_lines::update(serial.dtr(), serial.rts());
+    }
+
+    if let Some(duration_ms) = serial.take_break_request() {
+        unsafe {
+            (*core::ptr::addr_of_mut!(BREAK_REQUESTS))[channel] = Some(duration_ms);
+        }
+    }
+
+    host_channel(channel).sync_cdc(serial, configured, &mut PortIo(bridge_port(channel)));
+}
+
+/// Move OUT packets of a channel's CDC function into its USB→UART FIFO,
+/// taking escape sequences out (see `Escape::receive_packets`). Returns
+/// true if data was received.
+fn receive_from_host<B: UsbBus>(
+    serial: &mut CdcAcmClass<'_, B>,
+    channel: usize,
+    escape: &mut Escape,
+) -> bool {
+    let packet_size = usize::from(serial.max_packet_size());
+    crate::CORE0_FIFOS.with(|fifos| {
+        escape.receive_packets(
+            serial,
+            packet_size,
+            &mut fifos[channel].cdc_to_uart,
+            &mut ChannelMenu(channel),
+        )
+    })
+}
+
+/// A bridge channel as its escape menu sees it (Core0)
+struct ChannelMenu(usize);
+
+impl MenuTarget for ChannelMenu {
+    fn line_config(&self) -> LineConfig {
+        line_config(self.0)
+    }
+
+    fn set_baud(&mut self, baud: u32) {
+        set_baud(self.0, baud);
+    }
+
+    fn send_break(&mut self) {
+        unsafe {
+            (*core::ptr::addr_of_mut!(BREAK_REQUESTS))[self.0] = Some(ESCAPE_BREAK_MS);
+        }
+    }
+
+    /// Only channel 0 has the DTR/RTS pins
+    fn reset_target(&mut self) -> bool {
+        if self.0 != 0 || !crate::control_lines::has_reset_line() {
+            return false;
+        }
+        RESET_REQUESTED.store(true, Ordering::Relaxed);
+        true
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        bridge_port(self.0).line_errors()
+    }
+
+    fn dropped(&self) -> u32 {
+        dropped(self.0)
+    }
+}
+
+/// Send a channel's UART→USB data to its CDC function, as many packets as
+/// the IN endpoint takes, under the latency timer set by
+/// `UART_LATENCY_TIMER_US` and `UART_EVENT_CHAR` (see
+/// `HostChannel::send_packets`). Returns true if a packet was sent.
+fn send_to_host<B: UsbBus>(serial: &mut CdcAcmClass<'_, B>, channel: usize) -> bool {
+    let packet_size = usize::from(serial.max_packet_size());
+    let host = host_channel(channel);
+    crate::CORE0_FIFOS.with(|fifos| {
+        host.send_packets(
+            &mut fifos[channel].uart_to_cdc,
+            serial,
+            packet_size,
+            crate::board::time_us(),
+        )
+    })
+}
+
+/// Earliest `board::time_us` at which a channel's held-back UART→USB data
+/// is due under the latency timer, if that is still ahead. Data already due
+/// is only waiting for the IN endpoint, whose interrupt will come anyway.
+pub fn flush_deadline() -> Option<u32> {
+    let hosts = unsafe { &*core::ptr::addr_of!(HOST_CHANNELS) };
+    let now = crate::board::time_us();
+    hosts
+        .iter()
+        .filter_map(|host| host.flush_deadline(now))
+        .min_by_key(|deadline| deadline.wrapping_sub(now))
+}
+
+/// Take the last SEND_BREAK request for `channel` (duration in ms)
+pub fn take_break_request(channel: usize) -> Option<u16> {
+    // Set from the USB interrupt
+    cortex_m::interrupt::free(|_| unsafe {
+        (*core::ptr::addr_of_mut!(BREAK_REQUESTS))[channel].take()
+    })
+}
+
+/// Take a target reset request from the escape menu
+pub fn take_reset_request() -> bool {
+    cortex_m::interrupt::free(|_| {
+        let requested = RESET_REQUESTED.load(Ordering::Relaxed);
+        RESET_REQUESTED.store(false, Ordering::Relaxed);
+        requested
+    })
+}
+
+/// True if a target reset is waiting for `take_reset_request`
+pub fn reset_requested() -> bool {
+    RESET_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// True if a SEND_BREAK request is waiting for `take_break_request`
+pub fn break_requested() -> bool {
+    cortex_m::interrupt::free(|_| unsafe {
+        (*core::ptr::addr_of!(BREAK_REQUESTS))
+            .iter()
+            .any(Option::is_some)
+    })
+}